@@ -1,34 +1,92 @@
-use std::{sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 use anyhow::Result;
 use rand::{distr::{Alphanumeric, SampleString}, rng};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, signal, sync::{RwLock, mpsc::{UnboundedSender, unbounded_channel}}, task::JoinSet};
+use tokio::{net::TcpListener, signal, sync::{Mutex, Notify, RwLock, mpsc::{UnboundedSender, unbounded_channel}}, task::JoinSet};
 
-use crate::modules::{client_handler::ClientHandler, db::{DB, Registry}, values::RedisValue};
+use crate::modules::{aof::{self, Aof}, client_handler::ClientHandler, config::Config, db::{DB, Registry}, expire, rdb, replication::{self, Backlog}, values::RedisValue};
 mod modules;
 
+/// The Redis version this server stands in for, as HELLO reports it and RDB files record it.
+pub const REDIS_VERSION: &str = "7.4.0";
+
 fn generate_random_alphanumeric(length: usize) -> String {
     Alphanumeric.sample_string(&mut rng(), length)
 }
 
 struct ReplicaDb {
-    senders: Vec<UnboundedSender<Vec<RedisValue>>>
+    senders: HashMap<u32, UnboundedSender<Vec<RedisValue>>>,
+    /// Replication offset each replica last acknowledged, by client id.
+    ack_offsets: HashMap<u32, u64>,
+    /// Woken up whenever a replica acknowledges an offset.
+    acks: Arc<Notify>,
+    backlog: Backlog,
 }
 
 impl ReplicaDb {
-    fn new() -> Self {
-        Self { senders: vec![] }
+    fn new(backlog_size: usize) -> Self {
+        Self { senders: HashMap::new(), ack_offsets: HashMap::new(), acks: Arc::new(Notify::new()), backlog: Backlog::new(backlog_size, 0) }
+    }
+
+    /// Disconnects every replica and starts the stream over at `offset`.
+    pub fn reset(&mut self, offset: u64) {
+        self.senders.clear();
+        self.ack_offsets.clear();
+        self.backlog.reset(offset);
+    }
+
+    /// Registers a replica that has every write up to `offset`.
+    pub fn add(&mut self, id: u32, sender: UnboundedSender<Vec<RedisValue>>, offset: u64) {
+        self.senders.insert(id, sender);
+        self.ack_offsets.insert(id, offset);
+    }
+
+    /// Sends a command to every replica, dropping the ones that went away.
+    /// Returns how many bytes it adds to the replication stream.
+    pub fn propagate(&mut self, command: &[RedisValue]) -> u64 {
+        let ack_offsets = &mut self.ack_offsets;
+        self.senders.retain(|id, sender| {
+            let alive = sender.send(command.to_vec()).is_ok();
+            if !alive {
+                ack_offsets.remove(id);
+            }
+            alive
+        });
+        let encoded = RedisValue::Array(command.to_vec()).encode();
+        self.backlog.feed(&encoded);
+        encoded.len() as u64
+    }
+
+    pub fn ack(&mut self, id: u32, offset: u64) {
+        if let Some(ack_offset) = self.ack_offsets.get_mut(&id) {
+            *ack_offset = offset.max(*ack_offset);
+            self.acks.notify_waiters();
+        }
+    }
+
+    /// Number of replicas that acknowledged every write up to `offset`.
+    pub fn count_acked(&self, offset: u64) -> usize {
+        self.ack_offsets.values().filter(|ack_offset| **ack_offset >= offset).count()
     }
 }
 
 struct ReplicaInfo {
     role: String,
     master_replid: String,
+    /// Id of the stream this one continues, valid up to `second_replid_offset`.
+    master_replid2: String,
+    second_replid_offset: Option<u64>,
     master_address: String,
+    master_repl_offset: u64,
+    /// Port this server listens on, which replicas announce to their master.
+    port: String,
+    /// Stops the task following the master, while this is a replica.
+    link_shutdown: Option<Arc<Notify>>,
 }
 
 impl ReplicaInfo {
-    fn new(role: &str, master_replid: &str, master_address: &str) -> Self {
-        Self { role: role.to_string(), master_replid: master_replid.to_string(), master_address: master_address.to_string() }
+    fn new(role: &str, master_replid: &str, master_address: &str, port: &str) -> Self {
+        Self { role: role.to_string(), master_replid: master_replid.to_string(), master_replid2: "0".repeat(40), second_replid_offset: None,
+            master_address: master_address.to_string(), master_repl_offset: 0, port: port.to_string(), link_shutdown: None }
     }
 
     pub fn get_role(&self) -> String {
@@ -39,39 +97,103 @@ impl ReplicaInfo {
         self.master_replid.clone()
     }
 
+    pub fn get_replid2(&self) -> String {
+        self.master_replid2.clone()
+    }
+
+    pub fn get_second_replid_offset(&self) -> Option<u64> {
+        self.second_replid_offset
+    }
+
+    /// Starts following a new stream, with no history.
+    pub fn set_replid(&mut self, replid: &str) {
+        self.master_replid = replid.to_string();
+        self.master_replid2 = "0".repeat(40);
+        self.second_replid_offset = None;
+    }
+
+    /// Switches to a new id for a stream that continues the current one, so replicas
+    /// that know the old id can still resume it.
+    pub fn shift_replid(&mut self, replid: &str) {
+        self.master_replid2 = std::mem::replace(&mut self.master_replid, replid.to_string());
+        self.second_replid_offset = Some(self.master_repl_offset + 1);
+    }
+
     pub fn get_address(&self) -> String {
         self.master_address.clone()
     }
+
+    pub fn get_port(&self) -> String {
+        self.port.clone()
+    }
+
+    /// Becomes a replica of the server at `address`.
+    pub fn set_master(&mut self, address: &str) {
+        self.role = "slave".to_string();
+        self.master_address = address.to_string();
+    }
+
+    /// Becomes a master, continuing the current stream under the new id `replid`.
+    pub fn promote(&mut self, replid: &str) {
+        self.role = "master".to_string();
+        self.master_address = "".to_string();
+        self.shift_replid(replid);
+    }
+
+    pub fn replace_link_shutdown(&mut self, shutdown: Option<Arc<Notify>>) -> Option<Arc<Notify>> {
+        std::mem::replace(&mut self.link_shutdown, shutdown)
+    }
+
+    pub fn get_offset(&self) -> u64 {
+        self.master_repl_offset
+    }
+
+    pub fn set_offset(&mut self, offset: u64) {
+        self.master_repl_offset = offset;
+    }
 }
 
-async fn slave_handshake(rep: &ReplicaInfo, port: &str) -> Result<()> {
-    let mut stream = TcpStream::connect(rep.get_address()).await?;
-    let mut buffer = [0; 1024];
-    // PING
-    stream.write_all(&RedisValue::Array(vec![RedisValue::String("PING".to_string())]).encode()).await?;
-    stream.read(&mut buffer).await?;
-    // REPLCONF listening-port <port>
-    stream.write_all(&RedisValue::array_from_string_vec(vec!["REPLCONF", "listening-port", port]).encode()).await?;
-    stream.read(&mut buffer).await?;
-    // REPLCONF capa psync2
-    stream.write_all(&RedisValue::array_from_string_vec(vec!["REPLCONF", "capa", "psync2"]).encode()).await?;
-    stream.read(&mut buffer).await?;
-    // PSYNC ? -1
-    stream.write_all(&RedisValue::array_from_string_vec(vec!["PSYNC", "?", "-1"]).encode()).await?;
-    stream.read(&mut buffer).await?;
-    Ok(())
+/// Replays the append only file into the database, or creates it from the database
+/// contents if it doesn't exist yet, and opens it for logging.
+async fn start_aof(aof: &Arc<Mutex<Aof>>, db: &Arc<RwLock<DB>>, config: &Arc<RwLock<Config>>, ps_registry: &Arc<RwLock<Registry>>,
+                   repl_info: &Arc<RwLock<ReplicaInfo>>, replicadb: &Arc<RwLock<ReplicaDb>>) -> Result<()> {
+    let (path, load_truncated) = {
+        let config = config.read().await;
+        (config.get_aof_path(), config.is_aof_load_truncated())
+    };
+    if path.exists() {
+        let (_, receiver) = unbounded_channel::<RedisValue>();
+        let mut loader = ClientHandler::new(u32::MAX, Arc::clone(db), Arc::clone(ps_registry), receiver, Arc::clone(repl_info),
+            Arc::clone(replicadb), Arc::clone(config), Arc::clone(aof));
+        loader.disable_propagation();
+        let commands = aof::load(&path, &mut loader, load_truncated).await?;
+        println!("DB loaded from append only file: {} commands", commands);
+    } else {
+        let contents = aof::rewrite(&*db.read().await)?;
+        tokio::fs::write(&path, contents).await?;
+    }
+    aof.lock().await.open(&path).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = std::env::args().collect::<Vec<_>>();
-    let port = match args.iter().skip_while(|a| a != &"--port").skip(1).next() {
+    let config = Config::from_args(&args)?;
+    // the append only file has the most recent data, so the snapshot is only used without it
+    let db = if config.is_appendonly() && config.get_aof_path().exists() {
+        DB::new()
+    } else {
+        let db = rdb::load(&config.get_rdb_path())?;
+        println!("DB loaded from disk: {} keys", db.len());
+        db
+    };
+    let port = match args.iter().skip_while(|a| a != &"--port").nth(1) {
         None => "6379",
         Some(port) => port,
     };
     let role;
     let master_address;
-    match args.iter().skip_while(|a| a != &"--replicaof").skip(1).next() {
+    match args.iter().skip_while(|a| a != &"--replicaof").nth(1) {
         None => {
             role = "master";
             master_address = "".to_string();
@@ -83,18 +205,30 @@ async fn main() -> Result<()> {
         },
     };
     let master_id = generate_random_alphanumeric(40);
-    let replica = ReplicaInfo::new(role, &master_id, &master_address);
-    if role == "slave" {
-        slave_handshake(&replica, port).await?;
-    }
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
-    println!("Listening on 127.0.0.1:{}", port);
+    let replica = ReplicaInfo::new(role, &master_id, &master_address, port);
 
     let mut handles = JoinSet::new();
-    let db = Arc::new(RwLock::new(DB::new()));
+    let db = Arc::new(RwLock::new(db));
+    let config = Arc::new(RwLock::new(config));
     let ps_registry = Arc::new(RwLock::new(Registry::new()));
-    let replicadb = Arc::new(RwLock::new(ReplicaDb::new()));
+    let replicadb = Arc::new(RwLock::new(ReplicaDb::new(config.read().await.get_repl_backlog_size())));
     let repl_info = Arc::new(RwLock::new(replica));
+    let aof = Arc::new(Mutex::new(Aof::new(config.read().await.get_appendfsync())));
+    if config.read().await.is_appendonly() {
+        start_aof(&aof, &db, &config, &ps_registry, &repl_info, &replicadb).await?;
+    }
+    if role == "slave" {
+        let (_, receiver) = unbounded_channel::<RedisValue>();
+        let mut master_handler = ClientHandler::new(u32::MAX, Arc::clone(&db), Arc::clone(&ps_registry), receiver, Arc::clone(&repl_info),
+            Arc::clone(&replicadb), Arc::clone(&config), Arc::clone(&aof));
+        master_handler.set_master_link();
+        replication::start_replica_link(master_handler, Arc::clone(&repl_info), Arc::clone(&replicadb), Arc::clone(&db), false).await;
+    }
+    let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
+    println!("Listening on 127.0.0.1:{}", port);
+    tokio::spawn(rdb::run_save_points(Arc::clone(&db), Arc::clone(&config)));
+    tokio::spawn(aof::run_everysec_fsync(Arc::clone(&aof)));
+    tokio::spawn(expire::run_active_expire(Arc::clone(&db), Arc::clone(&config)));
     let ctrl_c_signal = signal::ctrl_c();
     tokio::pin!(ctrl_c_signal);
     
@@ -105,6 +239,19 @@ async fn main() -> Result<()> {
             biased;
             _ = &mut ctrl_c_signal => {
                 println!("\nCtrl+C received! Stopping listener and waiting for clients to finish...");
+                let (save_points, path) = {
+                    let config = config.read().await;
+                    (config.get_save_points(), config.get_rdb_path())
+                };
+                if !save_points.is_empty() {
+                    println!("Saving the final RDB snapshot before exiting.");
+                    if let Err(e) = rdb::save(&db, &path).await {
+                        eprintln!("Error trying to save the DB: {}", e);
+                    }
+                }
+                if let Err(e) = aof.lock().await.close().await {
+                    eprintln!("Error syncing the append only file: {}", e);
+                }
                 break;
             },
             conn = listener.accept() => {
@@ -112,7 +259,7 @@ async fn main() -> Result<()> {
                     Ok((stream, addr)) => {
                         println!("Accepted connection from {}", addr);
                         let db = Arc::clone(&db);
-                        let (sender, receiver) = unbounded_channel::<Vec<u8>>();
+                        let (sender, receiver) = unbounded_channel::<RedisValue>();
                         {
                             let mut reg = ps_registry.write().await;
                             reg.senders.insert(current_thread_id, sender);
@@ -120,8 +267,10 @@ async fn main() -> Result<()> {
                         let ps_registry = Arc::clone(&ps_registry);
                         let replicadb = Arc::clone(&replicadb);
                         let repl_info = Arc::clone(&repl_info);
+                        let config = Arc::clone(&config);
+                        let aof = Arc::clone(&aof);
                         handles.spawn(async move {
-                            let mut client_handler = ClientHandler::new(current_thread_id, db, ps_registry, receiver, repl_info, replicadb);
+                            let mut client_handler = ClientHandler::new(current_thread_id, db, ps_registry, receiver, repl_info, replicadb, config, aof);
                             if let Err(e) = client_handler.handle_client_async(stream).await {
                                 eprintln!("Error handling client: {}", e);
                             }
@@ -2,3 +2,14 @@ pub mod values;
 pub mod parser;
 pub mod client_handler;
 pub mod db;
+pub mod config;
+pub mod rdb;
+pub mod lzf;
+pub mod crc64;
+pub mod aof;
+pub mod expire;
+pub mod replication;
+pub mod commands;
+pub mod error;
+pub mod glob;
+pub mod skiplist;
@@ -0,0 +1,262 @@
+use std::{path::{Path, PathBuf}, process, sync::Arc};
+use anyhow::{Result, anyhow};
+use tokio::{fs::{File, OpenOptions}, io::AsyncWriteExt, sync::{Mutex, RwLock}, time::{self, Duration}};
+
+use crate::modules::{client_handler::ClientHandler, config::AppendFsync, db::{DB, DbRecord}, parser::RedisParser, values::RedisValue};
+
+/// Elements per command when a list is rewritten, so no single command gets too big.
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+/// The append only file. Write commands are applied and logged while holding its lock,
+/// so the log always sees them in the same order they were applied to the database.
+pub struct Aof {
+    file: Option<File>,
+    fsync: AppendFsync,
+    fsync_pending: bool,
+    /// Commands logged while a rewrite is in progress, appended to the new file once it's written.
+    rewrite_buffer: Option<Vec<u8>>,
+    /// Set when the AOF is being turned on: logging starts once the first rewrite is done.
+    open_after_rewrite: bool,
+}
+
+impl Aof {
+    pub fn new(fsync: AppendFsync) -> Self {
+        Self { file: None, fsync, fsync_pending: false, rewrite_buffer: None, open_after_rewrite: false }
+    }
+
+    pub async fn open(&mut self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub async fn close(&mut self) -> Result<()> {
+        self.open_after_rewrite = false;
+        if let Some(file) = self.file.take() {
+            file.sync_data().await?;
+        }
+        self.fsync_pending = false;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some() || self.open_after_rewrite
+    }
+
+    pub fn is_rewrite_in_progress(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    pub fn set_fsync(&mut self, fsync: AppendFsync) {
+        self.fsync = fsync;
+    }
+
+    pub async fn append(&mut self, command: &[RedisValue]) -> Result<()> {
+        let encoded = RedisValue::Array(command.to_vec()).encode();
+        if let Some(buffer) = &mut self.rewrite_buffer {
+            buffer.extend(&encoded);
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&encoded).await?;
+            file.flush().await?;
+            match self.fsync {
+                AppendFsync::Always => file.sync_data().await?,
+                AppendFsync::EverySec => self.fsync_pending = true,
+                AppendFsync::No => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn fsync_if_pending(&mut self) -> Result<()> {
+        if self.fsync_pending && let Some(file) = &self.file {
+            file.sync_data().await?;
+            self.fsync_pending = false;
+        }
+        Ok(())
+    }
+}
+
+/// Flushes the file to disk once per second when `appendfsync` is `everysec`.
+pub async fn run_everysec_fsync(aof: Arc<Mutex<Aof>>) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut aof = aof.lock().await;
+        if aof.fsync == AppendFsync::EverySec && let Err(e) = aof.fsync_if_pending().await {
+            eprintln!("Error syncing the append only file: {}", e);
+        }
+    }
+}
+
+/// Replays the file at `path` through `handler`. A tail cut short by a crash is dropped
+/// (and the file truncated to its last complete command) when `load_truncated` is set.
+pub async fn load(path: &Path, handler: &mut ClientHandler, load_truncated: bool) -> Result<usize> {
+    let file = File::open(path).await?;
+    let len = file.metadata().await?.len() as usize;
+    let mut parser = RedisParser::new(file);
+    let mut commands = 0;
+    let mut valid_len = 0;
+    let mut multi_start = None;
+    while valid_len < len {
+        match parser.read_value().await {
+            Ok(RedisValue::Array(args)) if !args.is_empty() => {
+                if !handler.is_in_multi() {
+                    multi_start = Some(valid_len);
+                }
+                handler.handle_internal_command(args).await?;
+                commands += 1;
+                valid_len = parser.get_consumed();
+            },
+            Ok(_) => return Err(anyhow!("Bad file format reading the append only file {}", path.display())),
+            Err(e) => {
+                if !parser.is_eof() {
+                    return Err(anyhow!("Bad file format reading the append only file {}: {}", path.display(), e))
+                }
+                break;
+            },
+        }
+    }
+    // a transaction without its EXEC never happened
+    if handler.is_in_multi() && let Some(start) = multi_start {
+        println!("Revert incomplete MULTI/EXEC transaction in AOF file {}", path.display());
+        valid_len = start;
+    }
+    if valid_len < len {
+        if !load_truncated {
+            return Err(anyhow!("Unexpected end of file reading the append only file {}. Make a backup of it and remove its last incomplete command, or set 'aof-load-truncated' to yes", path.display()))
+        }
+        println!("!!! Warning: short read while loading the AOF file {}, truncating it to {} bytes !!!", path.display(), valid_len);
+        let file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(valid_len as u64).await?;
+        file.sync_all().await?;
+    }
+    Ok(commands)
+}
+
+/// Builds the shortest sequence of commands that recreates the current database.
+pub fn rewrite(db: &DB) -> Result<Vec<u8>> {
+    let mut output = vec![];
+    let mut append = |command: Vec<Vec<u8>>| {
+        output.extend(RedisValue::Array(command.into_iter().map(RedisValue::String).collect()).encode());
+    };
+    for (key, record) in db.iter() {
+        match record {
+            DbRecord::String(string_record) => {
+                append(vec![b"SET".to_vec(), key.clone(), string_record.get_value().to_vec()]);
+            },
+            DbRecord::List(list_record) => {
+                let items = list_record.iter().collect::<Vec<_>>();
+                for chunk in items.chunks(REWRITE_ITEMS_PER_COMMAND) {
+                    let mut command = vec![b"RPUSH".to_vec(), key.clone()];
+                    command.extend(chunk.iter().map(|item| item.to_vec()));
+                    append(command);
+                }
+            },
+            DbRecord::Stream(stream_record) => {
+                for entry in stream_record {
+                    let mut command = vec![b"XADD".to_vec(), key.clone(), entry.get_id().as_bytes().to_vec()];
+                    for (field, value) in entry {
+                        command.push(field.clone());
+                        command.push(value.clone());
+                    }
+                    append(command);
+                }
+            },
+            DbRecord::Set(set_record) => {
+                for chunk in set_record.members().chunks(REWRITE_ITEMS_PER_COMMAND) {
+                    let mut command = vec![b"SADD".to_vec(), key.clone()];
+                    command.extend(chunk.iter().cloned());
+                    append(command);
+                }
+            },
+            DbRecord::ZSet(zset_record) => {
+                let members = zset_record.iter_from(0, false).collect::<Vec<_>>();
+                for chunk in members.chunks(REWRITE_ITEMS_PER_COMMAND) {
+                    let mut command = vec![b"ZADD".to_vec(), key.clone()];
+                    for (member, score) in chunk {
+                        command.push(score.to_string().into_bytes());
+                        command.push(member.to_vec());
+                    }
+                    append(command);
+                }
+            },
+            DbRecord::Hash(hash_record) => {
+                let fields = hash_record.iter().collect::<Vec<_>>();
+                for chunk in fields.chunks(REWRITE_ITEMS_PER_COMMAND) {
+                    let mut command = vec![b"HSET".to_vec(), key.clone()];
+                    for (field, value) in chunk {
+                        command.push(field.to_vec());
+                        command.push(value.to_vec());
+                    }
+                    append(command);
+                }
+                for (field, _) in fields {
+                    if let Some(limit) = hash_record.get_expire(field) {
+                        append(vec![b"HPEXPIREAT".to_vec(), key.clone(), limit.timestamp_millis().to_string().into_bytes(), b"FIELDS".to_vec(), b"1".to_vec(), field.clone()]);
+                    }
+                }
+            },
+        }
+        if let Some(limit) = db.get_expire(key) {
+            append(vec![b"PEXPIREAT".to_vec(), key.clone(), limit.timestamp_millis().to_string().into_bytes()]);
+        }
+    }
+    Ok(output)
+}
+
+/// Turns the append only file on. Logging starts once a rewrite has written the current
+/// database contents to it. `aof` is what `handle` holds, already locked by the caller.
+pub async fn enable(handle: &Arc<Mutex<Aof>>, aof: &mut Aof, db: &Arc<RwLock<DB>>, path: PathBuf) -> Result<()> {
+    if aof.is_enabled() {
+        return Ok(())
+    }
+    aof.open_after_rewrite = true;
+    if aof.is_rewrite_in_progress() {
+        return Ok(())
+    }
+    bgrewrite(handle, aof, db, path).await
+}
+
+/// Rewrites the append only file from the database contents on a background task.
+/// Clients are only blocked while the commands are generated in memory. `aof` is what
+/// `handle` holds, already locked by the caller.
+pub async fn bgrewrite(handle: &Arc<Mutex<Aof>>, aof: &mut Aof, db: &Arc<RwLock<DB>>, path: PathBuf) -> Result<()> {
+    if aof.is_rewrite_in_progress() {
+        return Err(anyhow!("Background append only file rewriting already in progress"))
+    }
+    let snapshot = rewrite(&*db.read().await)?;
+    aof.rewrite_buffer = Some(vec![]);
+    let aof = Arc::clone(handle);
+    tokio::spawn(async move {
+        let temp_path = path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", process::id()));
+        let result = finish_rewrite(&aof, &path, &temp_path, &snapshot).await;
+        if let Err(e) = result {
+            eprintln!("Background AOF rewrite error: {}", e);
+            let mut aof = aof.lock().await;
+            aof.rewrite_buffer = None;
+            aof.open_after_rewrite = false;
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        } else {
+            println!("Background AOF rewrite finished successfully");
+        }
+    });
+    Ok(())
+}
+
+async fn finish_rewrite(aof: &Arc<Mutex<Aof>>, path: &Path, temp_path: &Path, snapshot: &[u8]) -> Result<()> {
+    let mut file = File::create(temp_path).await?;
+    file.write_all(snapshot).await?;
+    // hold the lock while the commands logged in the meantime are copied over and the files swapped
+    let mut aof = aof.lock().await;
+    let buffer = aof.rewrite_buffer.take().unwrap_or_default();
+    file.write_all(&buffer).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    tokio::fs::rename(temp_path, path).await?;
+    if aof.file.is_some() || aof.open_after_rewrite {
+        aof.open(path).await?;
+        aof.open_after_rewrite = false;
+    }
+    Ok(())
+}
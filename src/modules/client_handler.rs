@@ -1,20 +1,654 @@
-use std::{cmp::{max, min}, collections::{HashMap, HashSet, VecDeque}, sync::Arc, time::{SystemTime, UNIX_EPOCH}, usize};
+use std::{cmp::{max, min}, collections::{HashMap, HashSet, VecDeque}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 use anyhow::{Result, anyhow};
-use chrono::{TimeDelta, Utc};
+use chrono::{DateTime, Utc};
 use regex::Regex;
-use tokio::{io::AsyncWriteExt, net::{TcpStream, tcp::OwnedWriteHalf}, sync::{Mutex, RwLock, mpsc::{UnboundedReceiver, unbounded_channel}}, time::{self, Duration}};
+use tokio::{io::AsyncWriteExt, net::{TcpStream, tcp::OwnedWriteHalf}, sync::{Mutex, OwnedMutexGuard, RwLock, mpsc::{UnboundedReceiver, unbounded_channel}}, time::{self, Duration}};
 
-use crate::{ReplicaDb, ReplicaInfo, modules::{db::{DB, DbRecord, ListRecord, Registry, StreamEntry, StreamRecord, StringRecord}, parser::RedisParser, values::RedisValue}};
+use crate::{REDIS_VERSION, ReplicaDb, ReplicaInfo, generate_random_alphanumeric, modules::{aof::{self, Aof}, commands, config::{Config, ProtocolLimits}, error::RedisError, glob, db::{DB, DbRecord, HashRecord, ListRecord, Registry, SetRecord, StreamEntry, StreamRecord, StringRecord, ZSetEnd, ZSetPopped, ZSetRecord, ZSetWaiter}, parser::RedisParser, rdb, replication, values::{Protocol, RedisValue, format_redis_double}}};
 
 const SUBSCRIBE_MODE_COMMANDS: [&str; 6] = ["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PING", "QUIT"];
 const TRANSACTION_COMMANDS: [&str; 3] = ["MULTI", "EXEC", "DISCARD"];
-const WRITE_COMMANDS: [&str; 2] = ["SET", "DEL"];
+/// Values UNLINK frees on another thread once they take more work than this, see `DbRecord::free_effort`.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// What gets propagated for the command being executed.
+enum Propagation {
+    /// The command as it was received, if it's a write that changed something.
+    AsReceived,
+    /// A command with the same effect that replays the same way anywhere, e.g. with the
+    /// stream id that was generated or an absolute expire time.
+    Rewritten(Vec<RedisValue>),
+    Prevented,
+}
+
+/// When SET and the commands like it store the value.
+enum SetCondition {
+    Always,
+    /// NX
+    IfMissing,
+    /// XX
+    IfExists,
+}
+
+/// What happens to the time to live of a key when its value is set.
+enum SetExpire {
+    Clear,
+    /// KEEPTTL
+    Keep,
+    At(DateTime<Utc>),
+}
+
+/// Turns the argument of an `EX`, `PX`, `EXAT` or `PXAT` option into the time the key expires.
+/// Like Redis, times that aren't positive are refused.
+fn parse_expire_option(option: &str, value: &RedisValue, command: &str) -> Result<DateTime<Utc>, RedisError> {
+    let amount = value.get_string()?.parse::<i64>()?;
+    if amount <= 0 {
+        return Err(RedisError::Client(format!("invalid expire time in '{}' command", command)))
+    }
+    expire_limit(option, amount, command)
+}
+
+/// The time something expires, given an `amount` in the unit `unit` names: milliseconds if it
+/// starts with `P`, seconds otherwise, and a Unix time if it ends with `AT`, e.g. `PXAT` or `EXPIRE`.
+fn expire_limit(unit: &str, amount: i64, command: &str) -> Result<DateTime<Utc>, RedisError> {
+    let invalid_time = || RedisError::Client(format!("invalid expire time in '{}' command", command));
+    let milliseconds = if unit.starts_with('P') { amount } else { amount.checked_mul(1000).ok_or_else(invalid_time)? };
+    let timestamp = if unit.ends_with("AT") {
+        milliseconds
+    } else {
+        Utc::now().timestamp_millis().checked_add(milliseconds).ok_or_else(invalid_time)?
+    };
+    DateTime::from_timestamp_millis(timestamp).ok_or_else(invalid_time)
+}
+
+/// The timeout of a blocking command, given in seconds, or `None` for 0, which waits forever.
+fn parse_timeout(value: &RedisValue) -> Result<Option<Duration>, RedisError> {
+    let out_of_range = || RedisError::Client("timeout is not a float or out of range".to_string());
+    let seconds = value.get_string()?.parse::<f64>().ok().filter(|seconds| seconds.is_finite()).ok_or_else(out_of_range)?;
+    if seconds < 0.0 {
+        return Err(RedisError::Client("timeout is negative".to_string()))
+    }
+    if seconds == 0.0 {
+        return Ok(None)
+    }
+    Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| out_of_range())
+}
+
+/// The count of HRANDFIELD or SRANDMEMBER, in the range Redis takes. A negative count picks
+/// that many, maybe more than once, and with values the reply is twice as long.
+fn parse_random_count(value: &RedisValue, with_values: bool) -> Result<i64, RedisError> {
+    let count = value.get_string()?.parse::<i64>()?;
+    let limit = if with_values { i64::MAX / 2 } else { i64::MAX };
+    if !(-limit..=limit).contains(&count) {
+        return Err(RedisError::Client("value is out of range".to_string()))
+    }
+    Ok(count)
+}
+
+/// The arguments after `FIELDS numfields`, which must be all that's left: `numfields` fields,
+/// each followed by `per_field - 1` more arguments, like the value to set it to.
+fn parse_fields(args: &[RedisValue], per_field: usize) -> Result<&[RedisValue], RedisError> {
+    if args.len() < 2 || args[0].get_string()?.to_uppercase() != "FIELDS" {
+        return Err(RedisError::Client("Mandatory argument FIELDS is missing or not at the right position".to_string()))
+    }
+    let num_fields = args[1].get_string()?.parse::<i64>()?;
+    if num_fields <= 0 {
+        return Err(RedisError::Client("Parameter `numFields` should be greater than 0".to_string()))
+    }
+    let fields = &args[2..];
+    if fields.len() as i64 != num_fields * per_field as i64 {
+        return Err(RedisError::Client("The `numfields` parameter must match the number of arguments".to_string()))
+    }
+    Ok(fields)
+}
+
+/// The commands that give `fields` of the hash at `key` the values and times to live they have
+/// now, for replicas and the append only file: HSET, then HPEXPIREAT for each field that expires.
+/// That way a field that kept its time to live expires at the same time everywhere.
+fn hash_set_commands(key: &[u8], hash_record: &HashRecord, fields: &[Vec<u8>]) -> (Vec<RedisValue>, Vec<Vec<RedisValue>>) {
+    let mut hset = vec![RedisValue::String("HSET".into()), RedisValue::String(key.to_vec())];
+    let mut expires = vec![];
+    for field in fields {
+        let Some(value) = hash_record.get(field) else { continue };
+        hset.push(RedisValue::String(field.clone()));
+        hset.push(RedisValue::String(value.clone()));
+        if let Some(limit) = hash_record.get_expire(field) {
+            expires.push(vec![
+                RedisValue::String("HPEXPIREAT".into()),
+                RedisValue::String(key.to_vec()),
+                RedisValue::String(limit.timestamp_millis().to_string().into()),
+                RedisValue::String("FIELDS".into()),
+                RedisValue::String("1".into()),
+                RedisValue::String(field.clone()),
+            ]);
+        }
+    }
+    (hset, expires)
+}
+
+/// The sets at `keys`, `None` for the missing ones. Fails if any of them holds something else.
+fn get_sets<'a>(db: &'a DB, keys: &[RedisValue]) -> Result<Vec<Option<&'a SetRecord>>, RedisError> {
+    keys.iter().map(|key| db.get_as::<SetRecord>(&key.get_bytes()?)).collect()
+}
+
+/// The members in all of `sets`, at most `limit` of them.
+fn set_intersection(sets: &[&SetRecord], limit: usize) -> Vec<Vec<u8>> {
+    let Some(smallest) = sets.iter().min_by_key(|set_record| set_record.len()) else { return vec![] };
+    smallest.members().into_iter().filter(|member| sets.iter().all(|set_record| set_record.contains(member))).take(limit).collect()
+}
+
+/// The members SINTER, SUNION or SDIFF, or their STORE variants, give for `sets`, where missing
+/// keys count as empty sets.
+fn set_operation(command: &str, sets: Vec<Option<&SetRecord>>) -> Vec<Vec<u8>> {
+    if command.starts_with("SINTER") {
+        match sets.into_iter().collect::<Option<Vec<_>>>() {
+            Some(sets) => set_intersection(&sets, usize::MAX),
+            None => vec![],
+        }
+    } else if command.starts_with("SUNION") {
+        let mut union = HashSet::new();
+        for set_record in sets.into_iter().flatten() {
+            union.extend(set_record.members());
+        }
+        union.into_iter().collect()
+    } else {
+        match sets.split_first() {
+            Some((Some(first), others)) => {
+                first.members().into_iter().filter(|member| !others.iter().flatten().any(|set_record| set_record.contains(member))).collect()
+            },
+            _ => vec![],
+        }
+    }
+}
+
+/// A score given as an argument. Like Redis, it may be `inf` or `-inf`, but not NaN.
+fn parse_score(value: &RedisValue) -> Result<f64, RedisError> {
+    let score = value.get_string()?.parse::<f64>()?;
+    if score.is_nan() {
+        return Err(RedisError::NotFloat)
+    }
+    Ok(score)
+}
+
+/// One end of a range of scores, as ZCOUNT and ZRANGE BYSCORE take it: `(` makes it exclusive.
+struct ScoreBound {
+    score: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(value: &RedisValue) -> Result<Self, RedisError> {
+        let value = value.get_string()?;
+        let (score, exclusive) = match value.strip_prefix('(') {
+            Some(score) => (score, true),
+            None => (value.as_str(), false),
+        };
+        let score = score.parse::<f64>().ok().filter(|score| !score.is_nan())
+            .ok_or(RedisError::Client("min or max is not a float".to_string()))?;
+        Ok(Self { score, exclusive })
+    }
+    /// Whether `score` comes before a range that starts here.
+    fn is_below(&self, score: f64) -> bool {
+        if self.exclusive { score <= self.score } else { score < self.score }
+    }
+    /// Whether `score` doesn't come after a range that ends here.
+    fn is_not_above(&self, score: f64) -> bool {
+        if self.exclusive { score < self.score } else { score <= self.score }
+    }
+}
+
+/// One end of a range of members, as ZRANGE BYLEX takes it: `-` and `+` for the lowest and the
+/// highest there can be, or a member after `[` for an inclusive end and `(` for an exclusive one.
+enum LexBound {
+    Lowest,
+    Highest,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    fn parse(value: &RedisValue) -> Result<Self, RedisError> {
+        let value = value.get_bytes()?;
+        match value.as_slice() {
+            b"-" => Ok(Self::Lowest),
+            b"+" => Ok(Self::Highest),
+            [b'[', member @ ..] => Ok(Self::Inclusive(member.to_vec())),
+            [b'(', member @ ..] => Ok(Self::Exclusive(member.to_vec())),
+            _ => Err(RedisError::Client("min or max not valid string range item".to_string())),
+        }
+    }
+    fn is_below(&self, member: &[u8]) -> bool {
+        match self {
+            Self::Lowest => false,
+            Self::Highest => true,
+            Self::Inclusive(bound) => member < bound.as_slice(),
+            Self::Exclusive(bound) => member <= bound.as_slice(),
+        }
+    }
+    fn is_not_above(&self, member: &[u8]) -> bool {
+        match self {
+            Self::Lowest => false,
+            Self::Highest => true,
+            Self::Inclusive(bound) => member <= bound.as_slice(),
+            Self::Exclusive(bound) => member < bound.as_slice(),
+        }
+    }
+}
+
+/// The members ZRANGE and the commands like it pick: by position, by score or, among members
+/// with the same score, by member.
+enum ZRange {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+impl ZRange {
+    /// Where the members in range start and end, lowest score first. With `reverse`, positions
+    /// count from the highest score.
+    fn ranks(&self, zset_record: &ZSetRecord, reverse: bool) -> (usize, usize) {
+        match self {
+            Self::Rank(start, stop) => {
+                let len = zset_record.len() as i64;
+                let start = if *start < 0 { (start + len).max(0) } else { *start };
+                let stop = if *stop < 0 { stop + len } else { (*stop).min(len - 1) };
+                if start > stop || start >= len {
+                    return (0, 0)
+                }
+                if reverse {
+                    ((len - 1 - stop) as usize, (len - start) as usize)
+                } else {
+                    (start as usize, stop as usize + 1)
+                }
+            },
+            Self::Score(min, max) => {
+                let start = zset_record.count_while(|score, _| min.is_below(score));
+                let end = zset_record.count_while(|score, _| max.is_not_above(score));
+                (start, end.max(start))
+            },
+            Self::Lex(min, max) => {
+                let start = zset_record.count_while(|_, member| min.is_below(member));
+                let end = zset_record.count_while(|_, member| max.is_not_above(member));
+                (start, end.max(start))
+            },
+        }
+    }
+    /// The members in range with their scores, in order or, with `reverse`, highest score first.
+    /// `limit` skips `offset` of them and keeps up to `count`, or all the rest if it's negative.
+    fn members(&self, zset_record: &ZSetRecord, reverse: bool, limit: Option<(i64, i64)>) -> Vec<(Vec<u8>, f64)> {
+        let (start, end) = self.ranks(zset_record, reverse);
+        let (offset, count) = limit.unwrap_or((0, -1));
+        if offset < 0 || offset as usize >= end - start {
+            return vec![]
+        }
+        let offset = offset as usize;
+        let first = if reverse { end - 1 - offset } else { start + offset };
+        let count = if count < 0 { usize::MAX } else { count as usize };
+        zset_record.iter_from(first, reverse).take(count.min(end - start - offset)).map(|(member, score)| (member.clone(), score)).collect()
+    }
+}
+
+/// Members, with their scores if asked for, the way ZRANGE and the commands like it reply:
+/// pairs of a member and its score on RESP3, one flat list on RESP2.
+fn zset_reply(members: Vec<(Vec<u8>, f64)>, with_scores: bool, protocol: Protocol) -> RedisValue {
+    let reply = members.into_iter().flat_map(|(member, score)| {
+        let member = RedisValue::String(member);
+        match (with_scores, protocol) {
+            (false, _) => vec![member],
+            (true, Protocol::Resp3) => vec![RedisValue::Array(vec![member, RedisValue::Double(score)])],
+            (true, Protocol::Resp2) => vec![member, RedisValue::Double(score)],
+        }
+    }).collect();
+    RedisValue::Array(reply)
+}
+
+/// What ZRANGE and ZRANGESTORE take after the key: the range, and the options that go with it.
+struct ZRangeQuery {
+    range: ZRange,
+    reverse: bool,
+    limit: Option<(i64, i64)>,
+    with_scores: bool,
+}
+
+impl ZRangeQuery {
+    /// Parses `args`, which start at the two ends of the range.
+    fn parse(args: &[RedisValue]) -> Result<Self, RedisError> {
+        let (mut by, mut reverse, mut limit, mut with_scores) = (None, false, None, false);
+        let mut i = 2;
+        while i < args.len() {
+            let option = args[i].get_string()?.to_uppercase();
+            match option.as_str() {
+                "BYSCORE" | "BYLEX" if by.is_none() => by = Some(option),
+                "REV" => reverse = true,
+                "WITHSCORES" => with_scores = true,
+                "LIMIT" if i + 2 < args.len() => {
+                    limit = Some((args[i + 1].get_string()?.parse::<i64>()?, args[i + 2].get_string()?.parse::<i64>()?));
+                    i += 2;
+                },
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        if limit.is_some() && by.is_none() {
+            return Err(RedisError::Client("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".to_string()))
+        }
+        if with_scores && by.as_deref() == Some("BYLEX") {
+            return Err(RedisError::Client("syntax error, WITHSCORES not supported in combination with BYLEX".to_string()))
+        }
+        // with REV, ranges by score or member go from their high end to their low end
+        let (min, max) = if reverse { (&args[1], &args[0]) } else { (&args[0], &args[1]) };
+        let range = match by.as_deref() {
+            None => ZRange::Rank(args[0].get_string()?.parse::<i64>()?, args[1].get_string()?.parse::<i64>()?),
+            Some("BYSCORE") => ZRange::Score(ScoreBound::parse(min)?, ScoreBound::parse(max)?),
+            _ => ZRange::Lex(LexBound::parse(min)?, LexBound::parse(max)?),
+        };
+        Ok(Self { range, reverse, limit, with_scores })
+    }
+    fn members(&self, zset_record: &ZSetRecord) -> Vec<(Vec<u8>, f64)> {
+        self.range.members(zset_record, self.reverse, self.limit)
+    }
+}
+
+/// The command replicas run to pop the `count` members a command took from `end` of the
+/// sorted set at `key`.
+fn zset_pop_command(key: &[u8], end: ZSetEnd, count: usize) -> Vec<RedisValue> {
+    let command = if end == ZSetEnd::Min { "ZPOPMIN" } else { "ZPOPMAX" };
+    vec![RedisValue::String(command.into()), RedisValue::String(key.to_vec()), RedisValue::String(count.to_string().into())]
+}
+
+/// Serves the clients blocked on the sorted set at `key`, once members were added to it.
+/// Returns what they popped, as commands for replicas to do the same.
+fn serve_zset_waiters(db: &mut DB, key: &[u8]) -> Vec<Vec<RedisValue>> {
+    db.serve_zset_waiters(key).into_iter().map(|(end, count)| zset_pop_command(key, end, count)).collect()
+}
+
+/// Stores what one of the STORE forms of the Z* commands computed at `key`, in place of
+/// whatever was there. An empty result removes the key. Returns what the clients blocked
+/// on the key popped, as commands for replicas.
+fn store_zset(db: &mut DB, key: &[u8], zset_record: ZSetRecord) -> Vec<Vec<RedisValue>> {
+    if zset_record.is_empty() {
+        db.remove(key);
+        return vec![]
+    }
+    db.insert(key.to_vec(), DbRecord::ZSet(zset_record));
+    serve_zset_waiters(db, key)
+}
+
+/// The keys, the end to pop from and how many members, as ZMPOP and BZMPOP take them from
+/// `numkeys` on.
+fn parse_zmpop(args: &[RedisValue]) -> Result<(Vec<Vec<u8>>, ZSetEnd, usize), RedisError> {
+    let numkeys = args[0].get_string()?.parse::<i64>()?;
+    if numkeys <= 0 {
+        return Err(RedisError::Client("numkeys should be greater than 0".to_string()))
+    }
+    let numkeys = numkeys as usize;
+    if numkeys + 1 >= args.len() {
+        return Err(RedisError::Syntax)
+    }
+    let keys = args[1..=numkeys].iter().map(RedisValue::get_bytes).collect::<Result<Vec<_>, _>>()?;
+    let end = match args[numkeys + 1].get_string()?.to_uppercase().as_str() {
+        "MIN" => ZSetEnd::Min,
+        "MAX" => ZSetEnd::Max,
+        _ => return Err(RedisError::Syntax),
+    };
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [option, count] if option.get_string()?.to_uppercase() == "COUNT" => {
+            let count = count.get_string()?.parse::<i64>()?;
+            if count <= 0 {
+                return Err(RedisError::Client("count should be greater than 0".to_string()))
+            }
+            count as usize
+        },
+        _ => return Err(RedisError::Syntax),
+    };
+    Ok((keys, end, count))
+}
+
+/// Pops up to `count` members from `end` of the first of `keys` holding a sorted set, and
+/// returns them with that key.
+fn pop_first_zset(db: &mut DB, keys: &[Vec<u8>], end: ZSetEnd, count: usize) -> Result<Option<ZSetPopped>, RedisError> {
+    for key in keys {
+        if db.get_as::<ZSetRecord>(key)?.is_some() {
+            let popped = db.get_mut_as::<ZSetRecord>(key)?.unwrap().pop(end, count);
+            db.update_zset(key);
+            return Ok(Some((key.clone(), popped)))
+        }
+    }
+    Ok(None)
+}
+
+/// A client blocked by BZPOPMIN or the commands like it. Once it stops waiting, whether it
+/// was served, its time ran out or it went away with its connection, it is taken off the
+/// keys it waited on.
+struct ZSetWait {
+    db: Arc<RwLock<DB>>,
+    keys: Vec<Vec<u8>>,
+    receiver: UnboundedReceiver<ZSetPopped>,
+}
+
+impl Drop for ZSetWait {
+    fn drop(&mut self) {
+        self.receiver.close();
+        let db = Arc::clone(&self.db);
+        let keys = std::mem::take(&mut self.keys);
+        tokio::spawn(async move { db.write().await.unblock_zsets(&keys) });
+    }
+}
+
+/// The key ZMPOP and BZMPOP popped from, along with the members and their scores, in pairs.
+fn zmpop_reply(key: Vec<u8>, popped: Vec<(Vec<u8>, f64)>) -> RedisValue {
+    let popped = popped.into_iter().map(|(member, score)| RedisValue::Array(vec![RedisValue::String(member), RedisValue::Double(score)])).collect();
+    RedisValue::Array(vec![RedisValue::String(key), RedisValue::Array(popped)])
+}
+
+/// A sorted set or a set as ZUNION and the commands like it take them, where each member of
+/// a set has score 1.
+enum ZSetInput<'a> {
+    ZSet(&'a ZSetRecord),
+    Set(&'a SetRecord),
+}
+
+impl ZSetInput<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::ZSet(zset_record) => zset_record.len(),
+            Self::Set(set_record) => set_record.len(),
+        }
+    }
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Self::ZSet(zset_record) => zset_record.score(member),
+            Self::Set(set_record) => set_record.contains(member).then_some(1.0),
+        }
+    }
+    fn members(&self) -> Vec<(Vec<u8>, f64)> {
+        match self {
+            Self::ZSet(zset_record) => ZRange::Rank(0, -1).members(zset_record, false, None),
+            Self::Set(set_record) => set_record.members().into_iter().map(|member| (member, 1.0)).collect(),
+        }
+    }
+}
+
+/// The inputs at `keys`, where missing keys are `None`.
+fn get_zset_inputs<'a>(db: &'a DB, keys: &[Vec<u8>]) -> Result<Vec<Option<ZSetInput<'a>>>, RedisError> {
+    keys.iter().map(|key| match db.get(key) {
+        None => Ok(None),
+        Some(DbRecord::ZSet(zset_record)) => Ok(Some(ZSetInput::ZSet(zset_record))),
+        Some(DbRecord::Set(set_record)) => Ok(Some(ZSetInput::Set(set_record))),
+        Some(_) => Err(RedisError::WrongType),
+    }).collect()
+}
+
+/// How ZUNION and ZINTER combine the scores a member has in each input.
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // like in Redis, adding up infinities of both signs gives 0
+            Self::Sum => Some(a + b).filter(|sum| !sum.is_nan()).unwrap_or(0.0),
+            Self::Min => a.min(b),
+            Self::Max => a.max(b),
+        }
+    }
+}
+
+/// What ZUNION, ZINTER and ZDIFF, or their STORE forms, were asked for.
+struct ZSetOperation {
+    keys: Vec<Vec<u8>>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    with_scores: bool,
+}
+
+impl ZSetOperation {
+    /// Parses `args`, which start at `numkeys`.
+    fn parse(command: &str, args: &[RedisValue]) -> Result<Self, RedisError> {
+        let numkeys = args[0].get_string()?.parse::<i64>()?;
+        if numkeys <= 0 {
+            return Err(RedisError::Client(format!("at least 1 input key is needed for '{}' command", command.to_lowercase())))
+        }
+        let numkeys = numkeys as usize;
+        if numkeys >= args.len() {
+            return Err(RedisError::Syntax)
+        }
+        let keys = args[1..=numkeys].iter().map(RedisValue::get_bytes).collect::<Result<Vec<_>, _>>()?;
+        let (store, diff) = (command.ends_with("STORE"), command.starts_with("ZDIFF"));
+        let (mut weights, mut aggregate, mut with_scores) = (vec![1.0; numkeys], Aggregate::Sum, false);
+        let mut i = numkeys + 1;
+        while i < args.len() {
+            match args[i].get_string()?.to_uppercase().as_str() {
+                "WEIGHTS" if !diff && i + numkeys < args.len() => {
+                    for (weight, value) in weights.iter_mut().zip(&args[i + 1..]) {
+                        *weight = value.get_string()?.parse::<f64>().ok().filter(|weight| !weight.is_nan())
+                            .ok_or(RedisError::Client("weight value is not a float".to_string()))?;
+                    }
+                    i += numkeys;
+                },
+                "AGGREGATE" if !diff && i + 1 < args.len() => {
+                    aggregate = match args[i + 1].get_string()?.to_uppercase().as_str() {
+                        "SUM" => Aggregate::Sum,
+                        "MIN" => Aggregate::Min,
+                        "MAX" => Aggregate::Max,
+                        _ => return Err(RedisError::Syntax),
+                    };
+                    i += 1;
+                },
+                "WITHSCORES" if !store => with_scores = true,
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(Self { keys, weights, aggregate, with_scores })
+    }
+    /// The score a member with `score` in the input with `weight` adds. Like in Redis, 0
+    /// times an infinity is 0.
+    fn weighted(score: f64, weight: f64) -> f64 {
+        Some(score * weight).filter(|weighted| !weighted.is_nan()).unwrap_or(0.0)
+    }
+    /// Combines `inputs`, one for each key, where missing keys count as empty.
+    fn run(&self, command: &str, inputs: Vec<Option<ZSetInput>>) -> ZSetRecord {
+        let mut result = ZSetRecord::new();
+        if command.starts_with("ZUNION") {
+            let mut scores: HashMap<Vec<u8>, f64> = HashMap::new();
+            for (input, weight) in inputs.iter().zip(&self.weights) {
+                for (member, score) in input.iter().flat_map(ZSetInput::members) {
+                    let score = Self::weighted(score, *weight);
+                    scores.entry(member).and_modify(|total| *total = self.aggregate.apply(*total, score)).or_insert(score);
+                }
+            }
+            for (member, score) in scores {
+                result.insert(member, score);
+            }
+        } else if command.starts_with("ZINTER") {
+            let Some(inputs) = inputs.into_iter().collect::<Option<Vec<_>>>() else { return result };
+            // the members of the smallest input are the only ones that can be in all of them
+            let smallest = (0..inputs.len()).min_by_key(|&i| inputs[i].len()).unwrap();
+            'members: for (member, score) in inputs[smallest].members() {
+                let mut total = Self::weighted(score, self.weights[smallest]);
+                for (i, input) in inputs.iter().enumerate().filter(|&(i, _)| i != smallest) {
+                    let Some(score) = input.score(&member) else { continue 'members };
+                    total = self.aggregate.apply(total, Self::weighted(score, self.weights[i]));
+                }
+                result.insert(member, total);
+            }
+        } else if let Some((Some(first), others)) = inputs.split_first() {
+            for (member, score) in first.members() {
+                if !others.iter().flatten().any(|input| input.score(&member).is_some()) {
+                    result.insert(member, score);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn parse_cursor(value: &RedisValue) -> Result<u64, RedisError> {
+    value.get_string()?.parse::<u64>().map_err(|_| RedisError::Client("invalid cursor".to_string()))
+}
+
+/// Options of SCAN and the commands that scan a single key.
+struct ScanOptions {
+    pattern: Option<Vec<u8>>,
+    count: usize,
+    /// `TYPE`, only for SCAN.
+    key_type: Option<String>,
+    /// `NOVALUES`, only for HSCAN.
+    no_values: bool,
+}
+
+impl ScanOptions {
+    fn parse(args: &[RedisValue], command: &str) -> Result<Self, RedisError> {
+        let mut options = Self { pattern: None, count: 10, key_type: None, no_values: false };
+        let mut i = 0;
+        while i < args.len() {
+            let option = args[i].get_string()?.to_uppercase();
+            match option.as_str() {
+                "NOVALUES" if command == "HSCAN" => options.no_values = true,
+                "MATCH" | "COUNT" | "TYPE" if i + 1 < args.len() => {
+                    let value = &args[i + 1];
+                    match option.as_str() {
+                        "MATCH" => options.pattern = Some(value.get_bytes()?),
+                        "COUNT" => {
+                            options.count = value.get_string()?.parse::<usize>()?;
+                            if options.count == 0 {
+                                return Err(RedisError::Syntax)
+                            }
+                        },
+                        _ if command == "SCAN" => {
+                            let name = value.get_string()?.to_lowercase();
+                            if !["string", "list", "set", "zset", "hash", "stream"].contains(&name.as_str()) {
+                                return Err(RedisError::Client(format!("unknown type name '{}'", name)))
+                            }
+                            options.key_type = Some(name);
+                        },
+                        _ => return Err(RedisError::Syntax),
+                    }
+                    i += 1;
+                },
+                _ => return Err(RedisError::Syntax),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+
+    fn matches(&self, member: &[u8]) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| glob::matches(pattern, member))
+    }
+}
 
 pub struct ClientHandler {
     id: u32,
     db: Arc<RwLock<DB>>,
     ps_registry: Arc<RwLock<Registry>>,
-    receiver: UnboundedReceiver<Vec<u8>>,
+    receiver: UnboundedReceiver<RedisValue>,
     instruction_receiver: Option<UnboundedReceiver<Vec<RedisValue>>>,
     replicas: Arc<RwLock<ReplicaDb>>,
     subscribe_mode: bool,
@@ -22,13 +656,71 @@ pub struct ClientHandler {
     queued_commands: Vec<Vec<RedisValue>>,
     replica_info: Arc<RwLock<ReplicaInfo>>,
     write_stream: Option<Mutex<OwnedWriteHalf>>,
+    config: Arc<RwLock<Config>>,
+    aof: Arc<Mutex<Aof>>,
+    /// Held while a write command runs, see `Aof`.
+    write_lock: Option<OwnedMutexGuard<Aof>>,
+    /// Write commands executed by the current command, to be logged and sent to replicas.
+    pending_propagation: Vec<Vec<RedisValue>>,
+    propagate: bool,
+    /// Set on the handler applying the master's stream, which is forwarded to replicas as is.
+    master_link: bool,
+    propagation: Propagation,
+    /// Commands to propagate after the current one, e.g. the pops of the clients it unblocked.
+    also_propagated: Vec<Vec<RedisValue>>,
+    /// Blocking commands don't block inside a transaction.
+    in_exec: bool,
+    protocol: Protocol,
+    /// Set with `HELLO .. SETNAME`.
+    name: Option<String>,
 }
 
 
 impl ClientHandler {
-    pub fn new(id: u32, db: Arc<RwLock<DB>>, ps_registry: Arc<RwLock<Registry>>, receiver: UnboundedReceiver<Vec<u8>>, repl_info: Arc<RwLock<ReplicaInfo>>, replicadb: Arc<RwLock<ReplicaDb>>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(id: u32, db: Arc<RwLock<DB>>, ps_registry: Arc<RwLock<Registry>>, receiver: UnboundedReceiver<RedisValue>, repl_info: Arc<RwLock<ReplicaInfo>>, replicadb: Arc<RwLock<ReplicaDb>>, config: Arc<RwLock<Config>>, aof: Arc<Mutex<Aof>>) -> Self {
         Self { id, db, ps_registry, receiver, subscribe_mode: false, multi_mode: false, queued_commands: vec![],
-            replica_info: repl_info, write_stream: None, instruction_receiver: None, replicas: replicadb }
+            replica_info: repl_info, write_stream: None, instruction_receiver: None, replicas: replicadb, config, aof,
+            write_lock: None, pending_propagation: vec![], propagate: true, master_link: false,
+            propagation: Propagation::AsReceived, also_propagated: vec![], in_exec: false,
+            protocol: Protocol::Resp2, name: None }
+    }
+
+    /// Stops this handler from logging or replicating the writes it executes,
+    /// e.g. while it replays the append only file.
+    pub fn disable_propagation(&mut self) {
+        self.propagate = false;
+    }
+
+    /// Makes this the handler applying the master's replication stream. Its writes are still
+    /// logged, but replicas get the stream through `forward_to_replicas` instead.
+    pub fn set_master_link(&mut self) {
+        self.master_link = true;
+    }
+
+    pub async fn forward_to_replicas(&self, command: Vec<RedisValue>) {
+        self.propagate_to_replicas(&[command]).await;
+    }
+
+    /// Rewrites the append only file from the database contents, if it's enabled.
+    pub async fn rewrite_aof(&self) -> Result<()> {
+        let mut aof = self.aof.lock().await;
+        if aof.is_enabled() {
+            let path = self.config.read().await.get_aof_path();
+            aof::bgrewrite(&self.aof, &mut aof, &self.db, path).await?;
+        }
+        Ok(())
+    }
+
+    pub fn is_in_multi(&self) -> bool {
+        self.multi_mode
+    }
+
+    /// Runs a command that doesn't come from a connected client, discarding its reply.
+    pub async fn handle_internal_command(&mut self, args: Vec<RedisValue>) -> Result<()> {
+        let command = args[0].get_string()?.to_ascii_uppercase();
+        self.handle_commands(&command, args).await?;
+        Ok(())
     }
 
     async fn send(&mut self, src: &[u8]) -> Result<()>{
@@ -36,7 +728,7 @@ impl ClientHandler {
             Some(stream) => {
                 // Lock mutex guard
                 let mut stream = stream.lock().await;
-                stream.write(src).await?;
+                stream.write_all(src).await?;
                 Ok(())
             },
             None => Err(anyhow!("No stream to send message to. Line {}", line!())),
@@ -51,17 +743,32 @@ impl ClientHandler {
     }
 
     pub async fn handle_client_async(&mut self, stream: TcpStream) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
         let (read_stream, write_stream) = stream.into_split();
         self.write_stream = Some(Mutex::new(write_stream));
         let mut parser = RedisParser::new(read_stream);
+        parser.accept_inline();
+        let mut limits = self.config.read().await.subscribe_limits();
+        limits.mark_changed();
         loop {
+            // a CONFIG SET applies from the next command on
+            if limits.has_changed().unwrap_or(false) {
+                let ProtocolLimits { max_bulk_len, max_multibulk_len } = *limits.borrow_and_update();
+                parser.set_limits(max_bulk_len, max_multibulk_len);
+            }
             let receiver = &mut self.receiver;
             let instruction_receiver = self.instruction_receiver.as_mut();
             tokio::select! {
                 value_read = parser.read_value() => {
                     match value_read {
                         Err(e) => {
-                            println!("{}", e);
+                            if parser.is_eof() {
+                                println!("Client '{}' disconnected.", peer_addr);
+                            } else {
+                                // the rest of the input can't be made sense of, so the connection is closed
+                                println!("{}", e);
+                                self.send(&RedisValue::Error(format!("ERR {}", e)).encode()).await?;
+                            }
                             return Ok(())
                         },
                         Ok(value) => {
@@ -69,14 +776,26 @@ impl ClientHandler {
                                 if args.is_empty() {
                                     continue;
                                 }
-                                let command = args[0].get_string()?.to_ascii_uppercase();
+                                let command = match args[0].get_string() {
+                                    Ok(command) => command.to_ascii_uppercase(),
+                                    Err(e) => {
+                                        self.send(&RedisValue::Error(e.to_string()).encode()).await?;
+                                        continue;
+                                    },
+                                };
 
-                                if self.subscribe_mode && !SUBSCRIBE_MODE_COMMANDS.contains(&command.as_str()) {
+                                // RESP3 tells messages apart from replies, so any command can be run while subscribed
+                                if self.subscribe_mode && self.protocol == Protocol::Resp2 && !SUBSCRIBE_MODE_COMMANDS.contains(&command.as_str()) {
                                     let response = RedisValue::Error(format!("ERR Can't execute '{}' in subscribed mode", command)).encode();
                                     self.send(&response).await?;
 
                                     continue;
                                 }
+                                if commands::is_write(&command) && self.is_read_only().await {
+                                    let response = RedisValue::Error("READONLY You can't write against a read only replica.".to_string()).encode();
+                                    self.send(&response).await?;
+                                    continue;
+                                }
                                 let response = self.handle_commands(&command, args).await?;
                                 self.send(&response).await?;
                             }
@@ -89,7 +808,7 @@ impl ClientHandler {
                            return Err(anyhow!("The internal pipe broke. Line {}", line!())) 
                         },
                         Some(message) => {
-                            self.send(&message).await?;
+                            self.send(&message.encode_with(self.protocol)).await?;
                         }
                     }
                 },
@@ -107,102 +826,269 @@ impl ClientHandler {
         }
     }
 
+    /// Whether writes from clients are rejected, as this is a replica of some master.
+    async fn is_read_only(&self) -> bool {
+        self.replica_info.read().await.get_role() == "slave" && self.config.read().await.is_replica_read_only()
+    }
+
     async fn handle_commands(&mut self, command: &str, args: Vec<RedisValue>) -> Result<Vec<u8>> {
         if self.multi_mode && !TRANSACTION_COMMANDS.contains(&command) {
             self.queued_commands.push(args);
-            return Ok(RedisValue::String("QUEUED".to_string()).as_simple_string()?);
+            return RedisValue::String("QUEUED".into()).as_simple_string();
+        }
+        if commands::is_write(command) || command == "EXEC" {
+            self.write_lock = Some(Arc::clone(&self.aof).lock_owned().await);
         }
-        match command {
+        let response = match command {
             "EXEC" => self.exec_queued().await,
             _ => self.execute_command(command, args).await,
+        };
+        let flushed = self.flush_propagation().await;
+        self.write_lock = None;
+        flushed?;
+        response
+    }
+
+    /// Takes the write lock until the command ends, unless it's held already: write commands
+    /// and EXEC, with every command queued in the transaction, run holding it, and it can't be
+    /// locked twice. Commands use the append only file through it.
+    async fn hold_write_lock(&mut self) {
+        if self.write_lock.is_none() {
+            self.write_lock = Some(Arc::clone(&self.aof).lock_owned().await);
+        }
+    }
+
+    /// Logs the writes of the last command to the append only file and sends them to replicas.
+    async fn flush_propagation(&mut self) -> Result<()> {
+        if self.pending_propagation.is_empty() {
+            return Ok(())
+        }
+        let mut aof = match self.write_lock.take() {
+            Some(lock) => lock,
+            None => Arc::clone(&self.aof).lock_owned().await,
+        };
+        let mut commands = std::mem::take(&mut self.pending_propagation);
+        // writes made by a single command (or transaction) are applied all at once elsewhere too
+        if commands.len() > 1 {
+            commands.insert(0, vec![RedisValue::String("MULTI".into())]);
+            commands.push(vec![RedisValue::String("EXEC".into())]);
+        }
+        for command in &commands {
+            aof.append(command).await?;
+        }
+        if !self.master_link {
+            self.propagate_to_replicas(&commands).await;
+        }
+        Ok(())
+    }
+
+    /// Adds commands to the replication stream, advancing the master offset.
+    async fn propagate_to_replicas(&self, commands: &[Vec<RedisValue>]) {
+        let mut replicadb = self.replicas.write().await;
+        let mut replica_info = self.replica_info.write().await;
+        for command in commands {
+            let offset = replica_info.get_offset() + replicadb.propagate(command);
+            replica_info.set_offset(offset);
         }
     }
 
     async fn execute_command(&mut self, command: &str, args: Vec<RedisValue>) -> Result<Vec<u8>> {
-        // Send to replication replicas
-        if WRITE_COMMANDS.contains(&command) {
-            if !self.replicas.read().await.senders.is_empty() {
-                let replicadb = self.replicas.write().await;
-                for replica in &replicadb.senders {
-                    replica.send(args.clone())?;
+        self.propagation = Propagation::AsReceived;
+        let response = match self.run_command(command, &args).await {
+            Ok(response) => response,
+            // the client is told what was wrong with the command, and can go on
+            Err(e) if e.is_client_error() => {
+                // a rejected command changed nothing, unless it already said what it did
+                if matches!(self.propagation, Propagation::AsReceived) {
+                    self.propagation = Propagation::Prevented;
                 }
-            }
+                RedisValue::Error(e.to_string()).encode()
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let effective = match std::mem::replace(&mut self.propagation, Propagation::AsReceived) {
+            Propagation::AsReceived if commands::is_write(command) => Some(args),
+            Propagation::Rewritten(effective) => Some(effective),
+            _ => None,
+        };
+        if self.propagate {
+            self.pending_propagation.extend(effective);
+            self.pending_propagation.append(&mut self.also_propagated);
+        } else {
+            self.also_propagated.clear();
         }
+        Ok(response)
+    }
 
+    async fn run_command(&mut self, command: &str, args: &[RedisValue]) -> Result<Vec<u8>, RedisError> {
         let response = match command {
             "PING" =>  {
-                if self.subscribe_mode {
-                    let mut response = vec![];
-                    response.push(RedisValue::String("pong".to_string()));
-                    response.push(RedisValue::String("".to_string()));
-                    RedisValue::Array(response).encode()
+                if self.subscribe_mode && self.protocol == Protocol::Resp2 {
+                    let response = vec![
+                        RedisValue::String("pong".into()),
+                        RedisValue::String("".into()),
+                    ];
+                    RedisValue::Array(response).encode_with(self.protocol)
                 } else {
-                    RedisValue::String("PONG".to_string()).as_simple_string()?
+                    RedisValue::String("PONG".into()).as_simple_string()?
                 }
             },
             "ECHO" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'ECHO' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("ECHO".to_string()))
                 } else {
-                    args[1].encode()
+                    args[1].encode_with(self.protocol)
                 }
             },
             "SET" => {
                 if args.len() < 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'SET' command".to_string()).encode()
-                } else {
-                    let key = args[1].clone().get_string()?;
-                    let value = args[2].clone();
-                    let record;
-                    if args.len() > 4 && args[3].get_string()?.to_uppercase() == "PX" {
-                        let milliseconds_limit = usize::from_str_radix(args[4].get_string()?.as_str(), 10)?;
-                        let now = Utc::now();
-                        let delta = TimeDelta::milliseconds(milliseconds_limit as i64);
-                        let limit = now.checked_add_signed(delta).unwrap();
-                        record = StringRecord::new_with_limit(value, limit);
-                    } else if args.len() > 4 && args[3].get_string()?.to_uppercase() == "EX" {
-                        let seconds_limit = usize::from_str_radix(args[4].get_string()?.as_str(), 10)?;
-                        let now = Utc::now();
-                        let delta = TimeDelta::seconds(seconds_limit as i64);
-                        let limit = now.checked_add_signed(delta).unwrap();
-                        record = StringRecord::new_with_limit(value, limit);
-                    } else {
-                        record = StringRecord::new(value);
+                    return Err(RedisError::WrongArity("SET".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let value = args[2].get_bytes()?;
+                    let mut condition = SetCondition::Always;
+                    let mut expire = SetExpire::Clear;
+                    let mut get = false;
+                    let mut i = 3;
+                    while i < args.len() {
+                        let option = args[i].get_string()?.to_uppercase();
+                        match option.as_str() {
+                            "NX" if !matches!(condition, SetCondition::IfExists) => condition = SetCondition::IfMissing,
+                            "XX" if !matches!(condition, SetCondition::IfMissing) => condition = SetCondition::IfExists,
+                            "GET" => get = true,
+                            "KEEPTTL" if !matches!(expire, SetExpire::At(_)) => expire = SetExpire::Keep,
+                            "EX" | "PX" | "EXAT" | "PXAT" if matches!(expire, SetExpire::Clear) && i + 1 < args.len() => {
+                                expire = SetExpire::At(parse_expire_option(&option, &args[i + 1], "set")?);
+                                i += 1;
+                            },
+                            _ => return Err(RedisError::Syntax),
+                        }
+                        i += 1;
                     }
-                    {
-                        let mut w_db = self.db.write().await;
-                        w_db.insert(key, DbRecord::String(record));
+                    let (stored, previous) = self.set_string(key, value, condition, expire, get).await?;
+                    if get {
+                        previous.map_or(RedisValue::NullString, RedisValue::String).encode_with(self.protocol)
+                    } else if stored {
+                        RedisValue::String("OK".into()).as_simple_string()?
+                    } else {
+                        RedisValue::NullString.encode_with(self.protocol)
                     }
-                    RedisValue::String("OK".to_string()).as_simple_string()?
                 }
             },
-            "GET" => {
+            "SETNX" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("SETNX".to_string()))
+                } else {
+                    let (stored, _) = self.set_string(args[1].get_bytes()?, args[2].get_bytes()?, SetCondition::IfMissing, SetExpire::Clear, false).await?;
+                    RedisValue::Int(stored as i64).encode()
+                }
+            },
+            "SETEX" | "PSETEX" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let option = if command == "SETEX" { "EX" } else { "PX" };
+                    let limit = parse_expire_option(option, &args[2], &command.to_lowercase())?;
+                    self.set_string(args[1].get_bytes()?, args[3].get_bytes()?, SetCondition::Always, SetExpire::At(limit), false).await?;
+                    RedisValue::String("OK".into()).as_simple_string()?
+                }
+            },
+            "GETSET" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("GETSET".to_string()))
+                } else {
+                    let (_, previous) = self.set_string(args[1].get_bytes()?, args[2].get_bytes()?, SetCondition::Always, SetExpire::Clear, true).await?;
+                    previous.map_or(RedisValue::NullString, RedisValue::String).encode_with(self.protocol)
+                }
+            },
+            "GETDEL" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'GET' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("GETDEL".to_string()))
                 } else {
-                    let key = args[1].clone().get_string()?;
-                    let db = self.db.read().await;
-                    let record = db.get(&key);
-                    match record {
-                        Some(record) => {
-                            let string_record = record.get_string();
-                            if string_record.is_some() && string_record.unwrap().is_valid() {
-                                string_record.unwrap().get_value().encode()
-                            } else {
-                                RedisValue::NullString.encode()
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    match db.get_as::<StringRecord>(&key)?.map(|string_record| string_record.get_value().to_vec()) {
+                        Some(value) => {
+                            db.remove(&key);
+                            RedisValue::String(value).encode_with(self.protocol)
+                        },
+                        None => {
+                            self.propagation = Propagation::Prevented;
+                            RedisValue::NullString.encode_with(self.protocol)
+                        },
+                    }
+                }
+            },
+            "GETEX" => {
+                if args.len() < 2 {
+                    return Err(RedisError::WrongArity("GETEX".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    // without options the time to live is left alone, PERSIST clears it
+                    let expire = match &args[2..] {
+                        [] => SetExpire::Keep,
+                        [option] if option.get_string()?.to_uppercase() == "PERSIST" => SetExpire::Clear,
+                        [option, value] => {
+                            let option = option.get_string()?.to_uppercase();
+                            if !["EX", "PX", "EXAT", "PXAT"].contains(&option.as_str()) {
+                                return Err(RedisError::Syntax)
                             }
+                            SetExpire::At(parse_expire_option(&option, value, "getex")?)
+                        },
+                        _ => return Err(RedisError::Syntax),
+                    };
+                    let mut db = self.db.write().await;
+                    match db.get_as::<StringRecord>(&key)?.map(|string_record| string_record.get_value().to_vec()) {
+                        Some(value) => {
+                            self.propagation = match expire {
+                                SetExpire::Keep => Propagation::Prevented,
+                                SetExpire::Clear => {
+                                    db.persist(&key);
+                                    Propagation::Rewritten(vec![RedisValue::String("PERSIST".into()), RedisValue::String(key)])
+                                },
+                                SetExpire::At(limit) => {
+                                    if limit <= Utc::now() {
+                                        db.remove(&key);
+                                    } else {
+                                        db.set_expire(&key, limit);
+                                    }
+                                    Propagation::Rewritten(vec![
+                                        RedisValue::String("PEXPIREAT".into()),
+                                        RedisValue::String(key),
+                                        RedisValue::String(limit.timestamp_millis().to_string().into()),
+                                    ])
+                                },
+                            };
+                            RedisValue::String(value).encode_with(self.protocol)
                         },
                         None => {
-                            RedisValue::NullString.encode()
-                        }
+                            self.propagation = Propagation::Prevented;
+                            RedisValue::NullString.encode_with(self.protocol)
+                        },
                     }
                 }
             },
-            "SUBSCRIBE" =>  {
+            "GET" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'SUBSCRIBE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("GET".to_string()))
                 } else {
-                    let channel = args[1].get_string()?;
+                    let key = args[1].get_bytes()?;
+                    let db = self.db.read().await;
+                    match db.get_as::<StringRecord>(&key)? {
+                        Some(string_record) => {
+                            RedisValue::String(string_record.get_value().to_vec()).encode_with(self.protocol)
+                        },
+                        None => {
+                            RedisValue::NullString.encode_with(self.protocol)
+                        }
+                    }
+                }
+            },
+            "SUBSCRIBE" =>  {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("SUBSCRIBE".to_string()))
+                } else {
+                    let channel = args[1].get_bytes()?;
                     {
                         let mut reg = self.ps_registry.write().await;
                         match reg.channels.get_mut(&channel) {
@@ -225,43 +1111,44 @@ impl ClientHandler {
                     let reg = self.ps_registry.read().await;
                     let current_subscriptions = reg.subscriptions.get(&self.id).unwrap().len();
                     self.subscribe_mode = true;
-                    let mut response = vec![];
-                    response.push(RedisValue::String("subscribe".to_string()));
-                    response.push(RedisValue::String(channel));
-                    response.push(RedisValue::Int(current_subscriptions as i64));
-                    RedisValue::Array(response).encode()
+                    let response = vec![
+                        RedisValue::String("subscribe".into()),
+                        RedisValue::String(channel),
+                        RedisValue::Int(current_subscriptions as i64),
+                    ];
+                    RedisValue::Push(response).encode_with(self.protocol)
                 }
             },
             "PUBLISH" => {
                 if args.len() != 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'PUBLISH' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("PUBLISH".to_string()))
                 } else {
-                    let channel = args[1].get_string()?;
-                    let message_string = args[2].get_string()?;
+                    let channel = args[1].get_bytes()?;
+                    let message = args[2].get_bytes()?;
                     let reg = self.ps_registry.read().await;
-                    let current_subscriber_num;
-                    if reg.channels.contains_key(&channel) {
-                        let current_subscribers = reg.channels.get(&channel).unwrap();
+                    let current_subscriber_num = if let Some(current_subscribers) = reg.channels.get(&channel) {
                         for sub in current_subscribers {
                             let sender = reg.senders.get(sub).unwrap();
-                            let mut response = vec![];
-                            response.push(RedisValue::String("message".to_string()));
-                            response.push(RedisValue::String(channel.clone()));
-                            response.push(RedisValue::String(message_string.clone()));
-                            sender.send(RedisValue::Array(response).encode())?;
+                            let response = vec![
+                                RedisValue::String("message".into()),
+                                RedisValue::String(channel.clone()),
+                                RedisValue::String(message.clone()),
+                            ];
+                            // encoded by the subscriber, for the protocol it uses. One that went away just misses it
+                            let _ = sender.send(RedisValue::Push(response));
                         }
-                        current_subscriber_num = current_subscribers.len();
+                        current_subscribers.len()
                     } else {
-                        current_subscriber_num = 0;
-                    }
+                        0
+                    };
                     RedisValue::Int(current_subscriber_num as i64).encode()
                 }
             },
             "UNSUBSCRIBE" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'UNSUBSCRIBE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("UNSUBSCRIBE".to_string()))
                 } else {
-                    let channel = args[1].get_string()?;
+                    let channel = args[1].get_bytes()?;
                     {
                         let mut reg = self.ps_registry.write().await;
                         if let Some(map) = reg.channels.get_mut(&channel) {
@@ -276,68 +1163,61 @@ impl ClientHandler {
                     if current_subscriptions == 0 {
                         self.subscribe_mode = false;
                     }
-                    let mut response = vec![];
-                    response.push(RedisValue::String("unsubscribe".to_string()));
-                    response.push(RedisValue::String(channel));
-                    response.push(RedisValue::Int(current_subscriptions as i64));
-                    RedisValue::Array(response).encode()
+                    let response = vec![
+                        RedisValue::String("unsubscribe".into()),
+                        RedisValue::String(channel),
+                        RedisValue::Int(current_subscriptions as i64),
+                    ];
+                    RedisValue::Push(response).encode_with(self.protocol)
                 }
             },
             "RPUSH" => {
                 if args.len() < 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'RPUSH' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("RPUSH".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
+                    let list_name = args[1].get_bytes()?;
                     let prev_records;
                     let pushed_records = args.len() - 2;
-                    {
+                    let served = {
                         let mut db = self.db.write().await;
-                        match db.get_mut(&list_name) {
-                            Some(record) => {
-                                if let Some(list_record) = record.get_mut_list() {
-                                    prev_records = list_record.len();
-                                    for val in args.iter().skip(2) {
-                                        list_record.push_back(val.get_string()?);
-                                    }
-                                } else {
-                                    return Err(anyhow!("Record is not of type list. Line {}", line!()))
+                        match db.get_mut_with_waiters_as::<ListRecord>(&list_name)? {
+                            Some(list_record) => {
+                                prev_records = list_record.len();
+                                for val in args.iter().skip(2) {
+                                    list_record.push_back(val.get_bytes()?);
                                 }
                             },
                             None => {
                                 let mut values = VecDeque::new();
                                 prev_records = 0;
                                 for val in args.iter().skip(2) {
-                                    values.push_back(val.get_string()?);
+                                    values.push_back(val.get_bytes()?);
                                 }
                                 db.insert(list_name.clone(), DbRecord::List(ListRecord::from_list(values)));
                             }
                         }
+                        db.get_mut_with_waiters_as::<ListRecord>(&list_name)?.map_or(0, |list_record| list_record.serve_waiters())
+                    };
+                    // the clients served popped from the list, replicas have to do the same
+                    for _ in 0..served {
+                        self.also_propagated.push(vec![RedisValue::String("LPOP".into()), RedisValue::String(list_name.clone())]);
                     }
                     RedisValue::Int((prev_records + pushed_records) as i64).encode()
                 }
             },
             "LRANGE" => {
                 if args.len() != 4 {
-                    RedisValue::Error("Err wrong number of arguments for 'LRANGE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("LRANGE".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
+                    let list_name = args[1].get_bytes()?;
                     let start_string = args[2].get_string()?;
                     let stop_string = args[3].get_string()?;
 
-                    let mut start = i64::from_str_radix(&start_string, 10)?;
-                    let mut stop = i64::from_str_radix(&stop_string, 10)?;
+                    let mut start = start_string.parse::<i64>()?;
+                    let mut stop = stop_string.parse::<i64>()?;
 
                     let db = self.db.read().await;
-                    let list = match db.get(&list_name) {
-                        Some(record) => {
-                            if let Some(list_record) = record.get_list() {
-                                list_record.get_list()
-                            } else {
-                                VecDeque::new()
-                            }
-                        },
-                        None => VecDeque::new()
-                    };
+                    let list = db.get_as::<ListRecord>(&list_name)?.map(ListRecord::get_list).unwrap_or_default();
                     let list_len = list.len() as i64;
 
                     if start < 0 { start = max(list_len + start, 0) }
@@ -354,61 +1234,62 @@ impl ClientHandler {
                         }
                     }
 
-                    RedisValue::Array(return_list) .encode()
+                    RedisValue::Array(return_list) .encode_with(self.protocol)
                 }
             },
             "LPUSH" => {
                 if args.len() < 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'LPUSH' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("LPUSH".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
+                    let list_name = args[1].get_bytes()?;
                     let prev_records;
                     let pushed_records = args.len() - 2;
-                    {
+                    let served = {
                         let mut db = self.db.write().await;
-                        match db.get_mut(&list_name) {
-                            Some(record) => {
-                                if let Some(list_record) = record.get_mut_list() {
-                                    prev_records = list_record.len();
-                                    for val in args.iter().skip(2) {
-                                        list_record.push_front(val.get_string()?);
-                                    }
-                                } else {
-                                    return Err(anyhow!("Record is not of type list. Line {}", line!()))
+                        match db.get_mut_with_waiters_as::<ListRecord>(&list_name)? {
+                            Some(list_record) => {
+                                prev_records = list_record.len();
+                                for val in args.iter().skip(2) {
+                                    list_record.push_front(val.get_bytes()?);
                                 }
                             },
                             None => {
                                 let mut values = VecDeque::new();
                                 prev_records = 0;
                                 for val in args.iter().skip(2) {
-                                    values.push_front(val.get_string()?);
+                                    values.push_front(val.get_bytes()?);
                                 }
                                 db.insert(list_name.clone(), DbRecord::List(ListRecord::from_list(values)));
                             }
                         }
+                        db.get_mut_with_waiters_as::<ListRecord>(&list_name)?.map_or(0, |list_record| list_record.serve_waiters())
+                    };
+                    // the clients served popped from the list, replicas have to do the same
+                    for _ in 0..served {
+                        self.also_propagated.push(vec![RedisValue::String("LPOP".into()), RedisValue::String(list_name.clone())]);
                     }
                     RedisValue::Int((prev_records + pushed_records) as i64).encode()
                 }
             },
             "LLEN" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'LLEN' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("LLEN".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
-                    let list_len = self.db.read().await.get(&list_name).unwrap_or(&DbRecord::List(ListRecord::new())).get_list().unwrap_or(&ListRecord::new()).len();
+                    let list_name = args[1].get_bytes()?;
+                    let list_len = self.db.read().await.get_as::<ListRecord>(&list_name)?.map_or(0, ListRecord::len);
                     RedisValue::Int(list_len as i64).encode()
                 }
             },
             "LPOP" => {
                 if args.len() < 2 || args.len() > 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'LPOP' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("LPOP".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
-                    let pop_amount = if args.len() == 3 { usize::from_str_radix(&args[2].get_string()?, 10)? } else { 1 };
+                    let list_name = args[1].get_bytes()?;
+                    let pop_amount = if args.len() == 3 { args[2].get_string()?.parse::<usize>()? } else { 1 };
                     let mut returned_items = vec![];
                     {
                         let mut db = self.db.write().await;
-                        if let Some(record) = db.get_mut(&list_name) && let Some(list_record) = record.get_mut_list() {
+                        if let Some(list_record) = db.get_mut_as::<ListRecord>(&list_name)? {
                             for _ in 0..pop_amount {
                                 match list_record.pop_front() {
                                     Some(popped) => {
@@ -421,93 +1302,339 @@ impl ClientHandler {
                             }
                         }
                     }
-                    if pop_amount == 1 {
-                        returned_items[0].encode()
+                    if returned_items.is_empty() {
+                        self.propagation = Propagation::Prevented;
+                        RedisValue::NullString.encode_with(self.protocol)
+                    } else if pop_amount == 1 {
+                        returned_items[0].encode_with(self.protocol)
                     } else {
-                        RedisValue::Array(returned_items).encode()
+                        RedisValue::Array(returned_items).encode_with(self.protocol)
                     }
                 }
             },
             "BLPOP" => {
                 if args.len() != 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'BLPOP' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("BLPOP".to_string()))
                 } else {
-                    let list_name = args[1].get_string()?;
-                    let timeout = args[2].get_string()?.parse::<f64>()?;
+                    let list_name = args[1].get_bytes()?;
+                    let timeout = parse_timeout(&args[2])?;
                     let mut value = None;
                     let mut waiter = None;
                     // block to either get the value via pop or setup a waiter for when values come
                     {
                         let mut db = self.db.write().await;
                         let list_record = match db.get_mut(&list_name) {
-                            None => {
-                                db.insert(list_name.clone(), DbRecord::List(ListRecord::new()));
-                                db.get_mut(&list_name).unwrap().get_mut_list().unwrap()
-                            },
-                            Some(record) => {
-                                if let Some(list_record) = record.get_mut_list() {
-                                    list_record
-                                } else {
-                                    return Err(anyhow!("Record is not of type list. Line {}", line!()))
-                                }
-                            }
+                            None => None,
+                            Some(record) => Some(record.as_type_mut::<ListRecord>()?),
                         };
-                        if !list_record.is_empty() {
+                        if let Some(list_record) = list_record && !list_record.is_empty() {
                             value = list_record.pop_front();
-                        } else {
-                            let (sender, receiver) = unbounded_channel::<String>();
-                            list_record.subscribe_waiter(sender);
+                            self.propagation = Propagation::Rewritten(vec![RedisValue::String("LPOP".into()), RedisValue::String(list_name.clone())]);
+                        } else if !self.in_exec {
+                            // a missing list is only made here, to hold the waiter
+                            let (sender, receiver) = unbounded_channel::<Vec<u8>>();
+                            db.get_or_insert_as(&list_name, ListRecord::new)?.subscribe_waiter(sender);
                             waiter = Some(receiver);
                         }
                     }
                     // wait for some value, either with timeout or stay waiting
                     if let Some(mut receiver) = waiter {
-                        if timeout == 0.0 {
-                            value = receiver.recv().await;
-                        } else {
+                        // other writers must be able to run while this one is blocked
+                        self.write_lock = None;
+                        // the pop is propagated by the command that served this client
+                        self.propagation = Propagation::Prevented;
+                        if let Some(timeout) = timeout {
                             tokio::select! {
                                 result = receiver.recv() => {
                                     value = result;
                                 }
-                                _ = time::sleep(Duration::from_secs_f64(timeout)) => ()
+                                _ = time::sleep(timeout) => {
+                                    // a value may have been handed over right as the time ran out
+                                    receiver.close();
+                                    value = receiver.try_recv().ok();
+                                }
                             }
+                        } else {
+                            value = receiver.recv().await;
                         }
                     }
                     // actually respond to the client
                     if let Some(value) = value {
                         let array = vec![RedisValue::String(list_name), RedisValue::String(value)];
-                        RedisValue::Array(array).encode()
+                        RedisValue::Array(array).encode_with(self.protocol)
                     } else {
-                        RedisValue::NullArray.encode()
+                        self.propagation = Propagation::Prevented;
+                        RedisValue::NullArray.encode_with(self.protocol)
                     }
                 }
             },
             "TYPE" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'TYPE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("TYPE".to_string()))
                 } else {
-                    let varname = args[1].get_string()?;
+                    let varname = args[1].get_bytes()?;
                     let db = self.db.read().await;
                     match db.get(&varname) {
                         Some(record) => {
-                            RedisValue::String(record.get_type()).as_simple_string()?
+                            RedisValue::String(record.get_type().into()).as_simple_string()?
+                        },
+                        None => RedisValue::String("none".into()).as_simple_string()?
+                    }
+                }
+            },
+            "DEL" | "UNLINK" => {
+                if args.len() < 2 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let mut removed = 0;
+                    let mut freed_later = vec![];
+                    {
+                        let mut db = self.db.write().await;
+                        for key in &args[1..] {
+                            if let Some(record) = db.remove(&key.get_bytes()?) {
+                                removed += 1;
+                                if command == "UNLINK" && record.free_effort() > LAZYFREE_THRESHOLD {
+                                    freed_later.push(record);
+                                }
+                            }
+                        }
+                    }
+                    if !freed_later.is_empty() {
+                        tokio::task::spawn_blocking(move || drop(freed_later));
+                    }
+                    if removed == 0 {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(removed).encode()
+                }
+            },
+            "EXISTS" | "TOUCH" => {
+                if args.len() < 2 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    // keys given more than once are counted every time
+                    let db = self.db.read().await;
+                    let mut found = 0;
+                    for key in &args[1..] {
+                        if db.get(&key.get_bytes()?).is_some() {
+                            found += 1;
+                        }
+                    }
+                    RedisValue::Int(found).encode()
+                }
+            },
+            "RENAME" | "RENAMENX" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let source = args[1].get_bytes()?;
+                    let destination = args[2].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    if db.get(&source).is_none() {
+                        return Err(RedisError::Client("no such key".to_string()))
+                    }
+                    let renamed = if command == "RENAMENX" && db.get(&destination).is_some() {
+                        false
+                    } else {
+                        // the key keeps its time to live under the new name
+                        let limit = db.get_expire(&source);
+                        let record = db.remove(&source).unwrap();
+                        db.insert(destination.clone(), record);
+                        if let Some(limit) = limit {
+                            db.set_expire(&destination, limit);
+                        }
+                        let served = serve_zset_waiters(&mut db, &destination);
+                        self.also_propagated.extend(served);
+                        true
+                    };
+                    if command == "RENAME" {
+                        RedisValue::String("OK".into()).as_simple_string()?
+                    } else {
+                        if !renamed {
+                            self.propagation = Propagation::Prevented;
+                        }
+                        RedisValue::Int(renamed as i64).encode()
+                    }
+                }
+            },
+            "COPY" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("COPY".to_string()))
+                } else {
+                    let source = args[1].get_bytes()?;
+                    let destination = args[2].get_bytes()?;
+                    let mut replace = false;
+                    let mut i = 3;
+                    while i < args.len() {
+                        match args[i].get_string()?.to_uppercase().as_str() {
+                            "REPLACE" => replace = true,
+                            // there is only database 0
+                            "DB" if i + 1 < args.len() => {
+                                if args[i + 1].get_string()?.parse::<i64>()? != 0 {
+                                    return Err(RedisError::Client("DB index is out of range".to_string()))
+                                }
+                                i += 1;
+                            },
+                            _ => return Err(RedisError::Syntax),
+                        }
+                        i += 1;
+                    }
+                    if source == destination {
+                        return Err(RedisError::Client("source and destination objects are the same".to_string()))
+                    }
+                    let mut db = self.db.write().await;
+                    let copied = match db.get(&source).map(DbRecord::duplicate) {
+                        Some(record) if replace || db.get(&destination).is_none() => {
+                            let limit = db.get_expire(&source);
+                            db.insert(destination.clone(), record);
+                            if let Some(limit) = limit {
+                                db.set_expire(&destination, limit);
+                            }
+                            let served = serve_zset_waiters(&mut db, &destination);
+                            self.also_propagated.extend(served);
+                            true
+                        },
+                        _ => false,
+                    };
+                    if !copied {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(copied as i64).encode()
+                }
+            },
+            "KEYS" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("KEYS".to_string()))
+                } else {
+                    let pattern = args[1].get_bytes()?;
+                    let db = self.db.read().await;
+                    let keys = db.iter().filter(|(key, _)| glob::matches(&pattern, key)).map(|(key, _)| RedisValue::String(key.clone())).collect();
+                    RedisValue::Array(keys).encode_with(self.protocol)
+                }
+            },
+            "SCAN" => {
+                if args.len() < 2 {
+                    return Err(RedisError::WrongArity("SCAN".to_string()))
+                } else {
+                    let cursor = parse_cursor(&args[1])?;
+                    let options = ScanOptions::parse(&args[2..], command)?;
+                    let db = self.db.read().await;
+                    // like Redis, the filters apply to the keys walked, so there may be fewer than COUNT
+                    let (next_cursor, keys) = db.scan(cursor, options.count);
+                    let keys = keys.into_iter()
+                        .filter(|(key, record)| options.matches(key) && options.key_type.as_ref().is_none_or(|key_type| record.get_type() == *key_type))
+                        .map(|(key, _)| RedisValue::String(key.clone()))
+                        .collect();
+                    RedisValue::Array(vec![RedisValue::String(next_cursor.to_string().into()), RedisValue::Array(keys)]).encode_with(self.protocol)
+                }
+            },
+            "RANDOMKEY" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("RANDOMKEY".to_string()))
+                } else {
+                    match self.db.write().await.random_key() {
+                        Some(key) => RedisValue::String(key).encode_with(self.protocol),
+                        None => RedisValue::NullString.encode_with(self.protocol),
+                    }
+                }
+            },
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let amount = args[2].get_string()?.parse::<i64>()?;
+                    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+                    for option in &args[3..] {
+                        match option.get_string()?.to_uppercase().as_str() {
+                            "NX" => nx = true,
+                            "XX" => xx = true,
+                            "GT" => gt = true,
+                            "LT" => lt = true,
+                            other => return Err(RedisError::Client(format!("Unsupported option {}", other))),
+                        }
+                    }
+                    if nx && (xx || gt || lt) {
+                        return Err(RedisError::Client("NX and XX, GT or LT options at the same time are not compatible".to_string()))
+                    }
+                    if gt && lt {
+                        return Err(RedisError::Client("GT and LT options at the same time are not compatible".to_string()))
+                    }
+                    let limit = expire_limit(command, amount, &command.to_lowercase())?;
+                    let mut db = self.db.write().await;
+                    let current = db.get_expire(&key);
+                    // a key without a time to live counts as one that never expires
+                    let applies = db.get(&key).is_some()
+                        && (!nx || current.is_none())
+                        && (!xx || current.is_some())
+                        && (!gt || current.is_some_and(|current| limit > current))
+                        && (!lt || current.is_none_or(|current| limit < current));
+                    if !applies {
+                        self.propagation = Propagation::Prevented;
+                        RedisValue::Int(0).encode()
+                    } else {
+                        // a time in the past deletes the key right away
+                        if limit <= Utc::now() {
+                            db.remove(&key);
+                        } else {
+                            db.set_expire(&key, limit);
+                        }
+                        // replicas must expire the key at the same time, however late they get the command
+                        self.propagation = Propagation::Rewritten(vec![
+                            RedisValue::String("PEXPIREAT".into()),
+                            RedisValue::String(key),
+                            RedisValue::String(limit.timestamp_millis().to_string().into()),
+                        ]);
+                        RedisValue::Int(1).encode()
+                    }
+                }
+            },
+            "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let db = self.db.read().await;
+                    let reply = match (db.get(&key), db.get_expire(&key)) {
+                        (None, _) => -2,
+                        (Some(_), None) => -1,
+                        (Some(_), Some(limit)) => {
+                            let remaining = (limit - Utc::now()).num_milliseconds();
+                            match command {
+                                "TTL" => (remaining + 500) / 1000,
+                                "PTTL" => remaining,
+                                "EXPIRETIME" => limit.timestamp(),
+                                _ => limit.timestamp_millis(),
+                            }
                         },
-                        None => RedisValue::String("none".to_string()).as_simple_string()?
+                    };
+                    RedisValue::Int(reply).encode()
+                }
+            },
+            "PERSIST" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("PERSIST".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let persisted = self.db.write().await.persist(&key);
+                    if !persisted {
+                        self.propagation = Propagation::Prevented;
                     }
+                    RedisValue::Int(persisted as i64).encode()
                 }
             },
             "XADD" => {
                 if args.len() < 5 || args.len() % 2 != 1 {
-                    RedisValue::Error("Err wrong number of arguments for 'XADD' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("XADD".to_string()))
                 } else {
                     let mut error_response = None;
-                    let stream_name = args[1].get_string()?;
+                    let stream_name = args[1].get_bytes()?;
                     let mut entry_id = args[2].get_string()?;
 
                     let re = Regex::new(r"^((\d+|\*)-(\d+|\*)|\*)$").unwrap();
 
                     if !re.is_match(&entry_id) {
-                        return Err(anyhow!("Bad format for stream id. Line {}", line!()))
+                        return Err(RedisError::Client("Invalid stream ID specified as stream command argument".to_string()))
                     }
 
                     if entry_id == "*" {
@@ -516,9 +1643,9 @@ impl ClientHandler {
 
                     let mut id_split = entry_id.split("-");
                     let milliseconds_str = id_split.next().unwrap();
-                    let mut milliseconds = i64::from_str_radix(milliseconds_str, 10).unwrap_or(-1);
+                    let mut milliseconds = milliseconds_str.parse::<i64>().unwrap_or(-1);
                     let sequence_str = id_split.next().unwrap();
-                    let mut sequence = i64::from_str_radix(sequence_str, 10).unwrap_or(-1);
+                    let mut sequence = sequence_str.parse::<i64>().unwrap_or(-1);
 
                     if milliseconds == 0 && sequence == 0 {
                         error_response = Some(RedisValue::Error("ERR The ID specified in XADD must be greater than 0-0".to_string()).encode())
@@ -527,40 +1654,38 @@ impl ClientHandler {
                     let mut values = HashMap::new();
 
                     for i in (3..args.len()).step_by(2) {
-                        let key = args[i].get_string()?;
-                        let value = args[i+1].get_string()?;
+                        let key = args[i].get_bytes()?;
+                        let value = args[i+1].get_bytes()?;
                         values.insert(key, value);
                     }
                     
                     if error_response.is_none() {
                         let mut db = self.db.write().await;
-                        match db.get_mut(&stream_name) {
-                            Some(record) => {
-                                if let Some(stream_record) = record.get_mut_stream() {
-                                    let last_id = stream_record.peek_last();
-                                    let mut last_id_split = last_id.get_id().split("-");
-                                    let last_milli = i64::from_str_radix(last_id_split.next().unwrap(), 10).unwrap();
-                                    let last_seq = i64::from_str_radix(last_id_split.next().unwrap(), 10).unwrap();
-                                    if milliseconds_str == "*" {
-                                        let now = SystemTime::now();
-                                        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap();
-                                        milliseconds = since_epoch.as_millis() as i64;
-                                    }
-                                    if sequence_str == "*" {
-                                        if last_milli == milliseconds {
-                                            sequence = last_seq + 1;
-                                        } else {
-                                            sequence = 0;
-                                        }
-                                    }
-                                    entry_id = format!("{}-{}", milliseconds, sequence);
-                                    let stream_entry = StreamEntry::new(&entry_id, Some(values));
-                                    if last_milli > milliseconds || (last_milli == milliseconds && last_seq >= sequence ) {
-                                        error_response = Some(RedisValue::Error("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()).encode())
+                        match db.get_mut_with_waiters_as::<StreamRecord>(&stream_name)? {
+                            Some(stream_record) => {
+                                let last_id = stream_record.peek_last();
+                                let mut last_id_split = last_id.get_id().split("-");
+                                let last_milli = last_id_split.next().unwrap().parse::<i64>().unwrap();
+                                let last_seq = last_id_split.next().unwrap().parse::<i64>().unwrap();
+                                if milliseconds_str == "*" {
+                                    let now = SystemTime::now();
+                                    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap();
+                                    milliseconds = since_epoch.as_millis() as i64;
+                                }
+                                if sequence_str == "*" {
+                                    if last_milli == milliseconds {
+                                        sequence = last_seq + 1;
                                     } else {
-                                        stream_record.push(stream_entry);
+                                        sequence = 0;
                                     }
                                 }
+                                entry_id = format!("{}-{}", milliseconds, sequence);
+                                let stream_entry = StreamEntry::new(&entry_id, Some(values));
+                                if last_milli > milliseconds || (last_milli == milliseconds && last_seq >= sequence ) {
+                                    error_response = Some(RedisValue::Error("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()).encode())
+                                } else {
+                                    stream_record.push(stream_entry);
+                                }
                             },
                             None => {
                                 if milliseconds_str == "*" {
@@ -586,79 +1711,87 @@ impl ClientHandler {
                     }
 
                     match error_response {
-                        None => RedisValue::String(entry_id).encode(),
-                        Some(err) => err,
+                        None => {
+                            // replicas must store the entry under the same id
+                            let mut effective = args.to_vec();
+                            effective[2] = RedisValue::String(entry_id.clone().into());
+                            self.propagation = Propagation::Rewritten(effective);
+                            RedisValue::String(entry_id.into()).encode_with(self.protocol)
+                        },
+                        Some(err) => {
+                            self.propagation = Propagation::Prevented;
+                            err
+                        },
                     }
                 }
             },
             "XRANGE" => {
                 if args.len() != 4 {
-                    RedisValue::Error("Err wrong number of arguments for 'XRANGE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("XRANGE".to_string()))
                 } else {
-                    let stream_name = args[1].get_string()?;
+                    let stream_name = args[1].get_bytes()?;
                     let re = Regex::new(r"^\d+(-\d+)?$").unwrap();
                     let mut lower_end = args[2].get_string()?;
                     if lower_end == "-" { lower_end = "0".to_string() }
                     let mut higher_end = args[3].get_string()?;
                     if higher_end == "+" { higher_end = format!("{}", usize::MAX) }
                     if !re.is_match(&lower_end) {
-                        return Err(anyhow!("Bad format for stream id in range's lower end. Line {}", line!()))
+                        return Err(RedisError::Client("Invalid stream ID specified as stream command argument".to_string()))
                     }
                     if !re.is_match(&higher_end) {
-                        return Err(anyhow!("Bad format for stream id in range's lower end. Line {}", line!()))
+                        return Err(RedisError::Client("Invalid stream ID specified as stream command argument".to_string()))
                     }
                     let lower_milliseconds;
                     let lower_sequence;
                     if lower_end.contains('-') {
                         let mut lower_split = lower_end.split('-');
-                        lower_milliseconds = usize::from_str_radix(&lower_split.next().unwrap(), 10).unwrap();
-                        lower_sequence = usize::from_str_radix(&lower_split.next().unwrap(), 10).unwrap();
+                        lower_milliseconds = lower_split.next().unwrap().parse::<usize>().unwrap();
+                        lower_sequence = lower_split.next().unwrap().parse::<usize>().unwrap();
                     } else {
-                        lower_milliseconds = usize::from_str_radix(&lower_end, 10).unwrap();
+                        lower_milliseconds = lower_end.parse::<usize>().unwrap();
                         lower_sequence = 0;
                     }
                     let higher_milliseconds;
                     let higher_sequence;
                     if higher_end.contains('-') {
                         let mut higher_split = higher_end.split('-');
-                        higher_milliseconds = usize::from_str_radix(&higher_split.next().unwrap(), 10).unwrap();
-                        higher_sequence = usize::from_str_radix(&higher_split.next().unwrap(), 10).unwrap();
+                        higher_milliseconds = higher_split.next().unwrap().parse::<usize>().unwrap();
+                        higher_sequence = higher_split.next().unwrap().parse::<usize>().unwrap();
                     } else {
-                        higher_milliseconds = usize::from_str_radix(&higher_end, 10).unwrap();
+                        higher_milliseconds = higher_end.parse::<usize>().unwrap();
                         higher_sequence = usize::MAX;
                     }
                     let mut response_array = vec![];
                     let db = self.db.read().await;
-                    if let Some(record) = db.get(&stream_name) && let Some(stream_record) = record.get_stream() {
+                    if let Some(stream_record) = db.get_as::<StreamRecord>(&stream_name)? {
                         for entry in stream_record {
                             let mut entry_id = entry.get_id().split('-');
-                            let entry_millis = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
-                            let entry_seq = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
+                            let entry_millis = entry_id.next().unwrap().parse::<usize>().unwrap();
+                            let entry_seq = entry_id.next().unwrap().parse::<usize>().unwrap();
                             if entry_millis < lower_milliseconds || entry_millis == lower_milliseconds && entry_seq < lower_sequence {
                                 continue;
                             } else if entry_millis > higher_milliseconds || entry_millis == higher_milliseconds && entry_seq > higher_sequence {
                                 break;
                             }
                             let mut entry_array = vec![];
-                            entry_array.push(RedisValue::String(entry.get_id().to_string()));
-                            let mut values_array = vec![];
+                            entry_array.push(RedisValue::String(entry.get_id().into()));
+                            let mut values_map = vec![];
                             for (k, v) in entry {
-                                values_array.push(RedisValue::String(k.clone()));
-                                values_array.push(RedisValue::String(v.clone()));
+                                values_map.push((RedisValue::String(k.clone()), RedisValue::String(v.clone())));
                             }
-                            entry_array.push(RedisValue::Array(values_array));
+                            entry_array.push(RedisValue::Map(values_map));
                             response_array.push(RedisValue::Array(entry_array));
                         }
                     }
-                    RedisValue::Array(response_array).encode()
+                    RedisValue::Array(response_array).encode_with(self.protocol)
                 }
             },
             "XREAD" => {
                 if args.len() < 4 {
-                    RedisValue::Error("Err wrong number of arguments for 'XRANGE' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("XRANGE".to_string()))
                 } else {
                     let re = Regex::new(r"^\d+-\d+$").unwrap();
-                    let mut response_array = vec![];
+                    let mut streams = vec![];
                     let mut reached_deadline = false;
                     let is_blocked;
                     let block_args;
@@ -666,53 +1799,48 @@ impl ClientHandler {
                     if args[1].get_string()?.to_lowercase() == "block" {
                         is_blocked = true;
                         block_args = 2;
-                        block_timeout = u64::from_str_radix(&args[2].get_string()?, 10)?;
+                        block_timeout = args[2].get_string()?.parse::<u64>()?;
                     } else if args[1].get_string()?.to_lowercase() == "streams" {
                         is_blocked = false;
                         block_args = 0;
                         block_timeout = 0;
                     } else {
-                        return Err(anyhow!("XREAD only compatible with STREAMS"));
+                        return Err(RedisError::Syntax);
                     }
                     for i in 0..(args.len() - block_args - 2) / 2 {
-                        let stream_name = args[2+i+block_args].get_string()?;
+                        let stream_name = args[2+i+block_args].get_bytes()?;
                         let mut entry_id = args[(args.len() - block_args) / 2 + 1 + i + block_args].get_string()?;
                         if entry_id == "$" {
+                            // only entries added from now on, to a stream that may not exist yet
                             let db = self.db.read().await;
-                            if let Some(record) = db.get(&stream_name) && let Some(stream_record) = record.get_stream() {
-                                entry_id = stream_record.peek_last().get_id().to_string();
-                            }
+                            entry_id = db.get_as::<StreamRecord>(&stream_name)?.map_or("0-0".to_string(), |stream_record| stream_record.peek_last().get_id().to_string());
                         }
                         if !re.is_match(&entry_id) {
-                            return Err(anyhow!("Bad format for stream id. Line {}", line!()))
+                            return Err(RedisError::Client("Invalid stream ID specified as stream command argument".to_string()))
                         }
                         let mut entry_id_split = entry_id.split('-');
-                        let entry_milliseconds = usize::from_str_radix(&entry_id_split.next().unwrap(), 10).unwrap();
-                        let entry_sequence = usize::from_str_radix(&entry_id_split.next().unwrap(), 10).unwrap();
-                        
-                        let mut stream_array = vec![];
-                        stream_array.push(RedisValue::String(stream_name.clone()));
+                        let entry_milliseconds = entry_id_split.next().unwrap().parse::<usize>().unwrap();
+                        let entry_sequence = entry_id_split.next().unwrap().parse::<usize>().unwrap();
                         
                         let mut entries_array = vec![];
                         
                         {
                             let db = self.db.read().await;
-                            if let Some(record) = db.get(&stream_name) && let Some(stream_record) = record.get_stream() {
+                            if let Some(stream_record) = db.get_as::<StreamRecord>(&stream_name)? {
                                 for entry in stream_record {
                                     let mut entry_id = entry.get_id().split('-');
-                                    let entry_millis = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
-                                    let entry_seq = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
+                                    let entry_millis = entry_id.next().unwrap().parse::<usize>().unwrap();
+                                    let entry_seq = entry_id.next().unwrap().parse::<usize>().unwrap();
                                     if entry_millis < entry_milliseconds || entry_millis == entry_milliseconds && entry_seq <= entry_sequence {
                                         continue;
                                     }
                                     let mut entry_array = vec![];
-                                    entry_array.push(RedisValue::String(entry.get_id().to_string()));
-                                    let mut values_array = vec![];
+                                    entry_array.push(RedisValue::String(entry.get_id().into()));
+                                    let mut values_map = vec![];
                                     for (k, v) in entry {
-                                        values_array.push(RedisValue::String(k.clone()));
-                                        values_array.push(RedisValue::String(v.clone()));
+                                        values_map.push((RedisValue::String(k.clone()), RedisValue::String(v.clone())));
                                     }
-                                    entry_array.push(RedisValue::Array(values_array));
+                                    entry_array.push(RedisValue::Map(values_map));
                                     entries_array.push(RedisValue::Array(entry_array));
                                 }
                             }
@@ -721,16 +1849,10 @@ impl ClientHandler {
                             let (sender, mut receiver) = unbounded_channel();
                             {
                                 let mut db = self.db.write().await;
-                                if db.contains_key(&stream_name) {
-                                    let record = db.get_mut(&stream_name).unwrap();
-                                    if let Some(stream_record) = record.get_mut_stream() {
-                                        stream_record.subscribe_waiter(sender);
-                                    }
-                                } else {
-                                    let mut stream_record = StreamRecord::new();
-                                    stream_record.subscribe_waiter(sender);
-                                    db.insert(stream_name, DbRecord::Stream(stream_record));
+                                if db.get_mut(&stream_name).is_none() {
+                                    db.insert(stream_name.clone(), DbRecord::Stream(StreamRecord::new()));
                                 }
+                                db.get_mut(&stream_name).unwrap().as_type_mut::<StreamRecord>()?.subscribe_waiter(sender);
                             }
                             // wait for value
                             let mut value = None;
@@ -739,8 +1861,8 @@ impl ClientHandler {
                                     let msg = receiver.recv().await;
                                     if let Some(entry) = &msg {
                                         let mut entry_id = entry.get_id().split('-');
-                                        let entry_millis = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
-                                        let entry_seq = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
+                                        let entry_millis = entry_id.next().unwrap().parse::<usize>().unwrap();
+                                        let entry_seq = entry_id.next().unwrap().parse::<usize>().unwrap();
                                         if entry_millis > entry_milliseconds || entry_millis == entry_milliseconds && entry_seq > entry_sequence {
                                             value = msg;
                                             break;
@@ -755,8 +1877,8 @@ impl ClientHandler {
                                         msg = receiver.recv() => {
                                             if let Some(entry) = &msg {
                                                 let mut entry_id = entry.get_id().split('-');
-                                                let entry_millis = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
-                                                let entry_seq = usize::from_str_radix(entry_id.next().unwrap(), 10).unwrap();
+                                                let entry_millis = entry_id.next().unwrap().parse::<usize>().unwrap();
+                                                let entry_seq = entry_id.next().unwrap().parse::<usize>().unwrap();
                                                 if entry_millis > entry_milliseconds || entry_millis == entry_milliseconds && entry_seq > entry_sequence {
                                                     value = msg;
                                                     break;
@@ -773,142 +1895,1726 @@ impl ClientHandler {
                             }
                             if let Some(entry) = value {
                                 let mut entry_array = vec![];
-                                entry_array.push(RedisValue::String(entry.get_id().to_string()));
-                                let mut values_array = vec![];
+                                entry_array.push(RedisValue::String(entry.get_id().into()));
+                                let mut values_map = vec![];
                                 for (k, v) in &entry {
-                                    values_array.push(RedisValue::String(k.clone()));
-                                    values_array.push(RedisValue::String(v.clone()));
+                                    values_map.push((RedisValue::String(k.clone()), RedisValue::String(v.clone())));
                                 }
-                                entry_array.push(RedisValue::Array(values_array));
+                                entry_array.push(RedisValue::Map(values_map));
                                 entries_array.push(RedisValue::Array(entry_array));
                             }
                         }
-                        stream_array.push(RedisValue::Array(entries_array));
-                        response_array.push(RedisValue::Array(stream_array));
+                        streams.push((RedisValue::String(stream_name), RedisValue::Array(entries_array)));
                     }
                     if reached_deadline {
-                        RedisValue::NullArray.encode()
+                        RedisValue::NullArray.encode_with(self.protocol)
+                    } else if self.protocol == Protocol::Resp3 {
+                        RedisValue::Map(streams).encode_with(self.protocol)
                     } else {
-                        RedisValue::Array(response_array).encode()
+                        let response_array = streams.into_iter().map(|(name, entries)| RedisValue::Array(vec![name, entries])).collect();
+                        RedisValue::Array(response_array).encode_with(self.protocol)
                     }
                 }
             },
             "INCR" => {
                 if args.len() != 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'INCR' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("INCR".to_string()))
                 } else {
-                    let key = args[1].get_string()?;
-                    let mut new_value = 0;
+                    let key = args[1].get_bytes()?;
+                    let new_value;
                     let mut db = self.db.write().await;
-                    let mut error = None;
-                    match db.get_mut(&key) {
+                    match db.get_mut_as::<StringRecord>(&key)? {
                         Some(value) => {
-                            if let DbRecord::String(value) = value {
-                                if let Ok(number) = i64::from_str_radix(&value.get_value().get_string()?, 10) {
-                                    new_value = number + 1;
-                                    value.set_value(RedisValue::String(format!("{}", new_value)));
-                                }  else {
-                                    error = Some("ERR value is not an integer or out of range");
-                                }
-                            }
+                            let number = str::from_utf8(value.get_value()).ok().and_then(|value| value.parse::<i64>().ok()).ok_or(RedisError::NotInteger)?;
+                            new_value = number.checked_add(1).ok_or(RedisError::Client("increment or decrement would overflow".to_string()))?;
+                            value.set_value(new_value.to_string().into_bytes());
                         },
                         None => {
-                            db.insert(key, DbRecord::String(StringRecord::new(RedisValue::String("1".to_string()))));
+                            db.insert(key, DbRecord::String(StringRecord::new(b"1".to_vec())));
                             new_value = 1;
                         }
                     }
-                    if let Some(error) = error {
-                        RedisValue::Error(format!("{}", error)).encode()
-                    } else {
-                        RedisValue::Int(new_value).encode()
-                    }
+                    RedisValue::Int(new_value).encode()
                 }
             },
-            "MULTI" => {
-                if args.len() != 1 {
-                    RedisValue::Error("Err wrong number of arguments for 'MULTI' command".to_string()).encode()
+            "HSET" => {
+                if args.len() < 4 || !args.len().is_multiple_of(2) {
+                    return Err(RedisError::WrongArity("HSET".to_string()))
                 } else {
-                    self.multi_mode = true;
-                    RedisValue::String("OK".to_string()).as_simple_string()?
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let hash_record = db.get_or_insert_as(&key, HashRecord::new)?;
+                    let mut added = 0;
+                    for pair in args[2..].chunks(2) {
+                        if hash_record.insert(pair[0].get_bytes()?, pair[1].get_bytes()?) {
+                            added += 1;
+                        }
+                    }
+                    RedisValue::Int(added).encode()
                 }
             },
-            "DISCARD" => {
-                if args.len() != 1 {
-                    RedisValue::Error("Err wrong number of arguments for 'DISCARD' command".to_string()).encode()
+            "HSETNX" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("HSETNX".to_string()))
                 } else {
-                    if self.multi_mode {
-                        self.multi_mode = false;
-                        self.queued_commands = vec![];
-                        RedisValue::String("OK".to_string()).as_simple_string()?
+                    let key = args[1].get_bytes()?;
+                    let field = args[2].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let exists = db.get_as::<HashRecord>(&key)?.is_some_and(|hash_record| hash_record.get(&field).is_some());
+                    if exists {
+                        self.propagation = Propagation::Prevented;
                     } else {
-                        RedisValue::Error("ERR DISCARD without MULTI".to_string()).encode()
+                        db.get_or_insert_as(&key, HashRecord::new)?.insert(field, args[3].get_bytes()?);
                     }
+                    RedisValue::Int(!exists as i64).encode()
                 }
             },
-            "INFO" => {
-                if args.len() > 2 {
-                    RedisValue::Error("Err wrong number of arguments for 'INFO' command".to_string()).encode()
+            "HGET" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("HGET".to_string()))
                 } else {
-                    let mut response = String::new();
-                    if args.len() == 2 && args[1].get_string()?.to_lowercase() == "replication" {
-                        response.push_str("# Replication\n");
-                        response.push_str(&format!("role:{}\n", self.replica_info.read().await.get_role()));
-                        response.push_str(&format!("master_replid:{}\n", self.replica_info.read().await.get_replid()));
-                        response.push_str("master_repl_offset:0\n");
+                    let db = self.db.read().await;
+                    match db.get_as::<HashRecord>(&args[1].get_bytes()?)?.and_then(|hash_record| hash_record.get(&args[2].get_bytes().ok()?)) {
+                        Some(value) => RedisValue::String(value.clone()).encode_with(self.protocol),
+                        None => RedisValue::NullString.encode_with(self.protocol),
                     }
-                    RedisValue::String(response).encode()
                 }
             },
-            "REPLCONF" => {
+            "HMGET" => {
                 if args.len() < 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'REPLCONF' command".to_string()).encode()
+                    return Err(RedisError::WrongArity("HMGET".to_string()))
                 } else {
-                    RedisValue::String("OK".to_string()).as_simple_string()?
+                    let db = self.db.read().await;
+                    let hash_record = db.get_as::<HashRecord>(&args[1].get_bytes()?)?;
+                    let mut values = vec![];
+                    for field in &args[2..] {
+                        let field = field.get_bytes()?;
+                        values.push(hash_record.and_then(|hash_record| hash_record.get(&field)).map_or(RedisValue::NullString, |value| RedisValue::String(value.clone())));
+                    }
+                    RedisValue::Array(values).encode_with(self.protocol)
                 }
             },
-            "PSYNC" => {
+            "HGETALL" | "HKEYS" | "HVALS" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let hash_record = db.get_as::<HashRecord>(&args[1].get_bytes()?)?;
+                    let fields = hash_record.into_iter().flat_map(HashRecord::iter);
+                    match command {
+                        "HGETALL" => RedisValue::Map(fields.map(|(field, value)| (RedisValue::String(field.clone()), RedisValue::String(value.clone()))).collect()),
+                        "HKEYS" => RedisValue::Array(fields.map(|(field, _)| RedisValue::String(field.clone())).collect()),
+                        _ => RedisValue::Array(fields.map(|(_, value)| RedisValue::String(value.clone())).collect()),
+                    }.encode_with(self.protocol)
+                }
+            },
+            "HDEL" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("HDEL".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let mut removed = 0;
+                    if let Some(hash_record) = db.get_mut_as::<HashRecord>(&key)? {
+                        for field in &args[2..] {
+                            if hash_record.remove(&field.get_bytes()?) {
+                                removed += 1;
+                            }
+                        }
+                        // a hash without fields is no key at all
+                        db.update_hash(&key);
+                    }
+                    if removed == 0 {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(removed).encode()
+                }
+            },
+            "HEXISTS" | "HSTRLEN" => {
                 if args.len() != 3 {
-                    RedisValue::Error("Err wrong number of arguments for 'PSYNC' command".to_string()).encode()
+                    return Err(RedisError::WrongArity(command.to_string()))
                 } else {
-                    // Create communication channels for this replica
-                    let (sender, receiver) = unbounded_channel();
-                    self.instruction_receiver = Some(receiver);
-                    {
-                        let mut replicadb = self.replicas.write().await;
-                        replicadb.senders.push(sender);
+                    let db = self.db.read().await;
+                    let field = args[2].get_bytes()?;
+                    let value = db.get_as::<HashRecord>(&args[1].get_bytes()?)?.and_then(|hash_record| hash_record.get(&field));
+                    let reply = if command == "HEXISTS" { value.is_some() as usize } else { value.map_or(0, Vec::len) };
+                    RedisValue::Int(reply as i64).encode()
+                }
+            },
+            "HLEN" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("HLEN".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let len = db.get_as::<HashRecord>(&args[1].get_bytes()?)?.map_or(0, HashRecord::len);
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "HINCRBY" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("HINCRBY".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let field = args[2].get_bytes()?;
+                    let increment = args[3].get_string()?.parse::<i64>()?;
+                    let mut db = self.db.write().await;
+                    let hash_record = db.get_or_insert_as(&key, HashRecord::new)?;
+                    let current = match hash_record.get(&field) {
+                        Some(value) => str::from_utf8(value).ok().and_then(|value| value.parse::<i64>().ok())
+                            .ok_or(RedisError::Client("hash value is not an integer".to_string()))?,
+                        None => 0,
+                    };
+                    let new_value = current.checked_add(increment).ok_or(RedisError::Client("increment or decrement would overflow".to_string()))?;
+                    hash_record.insert_keeping_ttl(field.clone(), new_value.to_string().into_bytes());
+                    let (hset, expires) = hash_set_commands(&key, hash_record, &[field]);
+                    self.propagation = Propagation::Rewritten(hset);
+                    self.also_propagated.extend(expires);
+                    RedisValue::Int(new_value).encode()
+                }
+            },
+            "HINCRBYFLOAT" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("HINCRBYFLOAT".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let field = args[2].get_bytes()?;
+                    let increment = args[3].get_string()?.parse::<f64>()?;
+                    if !increment.is_finite() {
+                        return Err(RedisError::NotFloat)
                     }
-
-                    let response = RedisValue::String(format!("FULLRESYNC {} 0", self.replica_info.read().await.get_replid())).as_simple_string()?;
-                    self.send(&response).await?;
-                    let mut content = vec![];
-                    let hex_empty_rdb_file = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-                    let raw_content = hex::decode(hex_empty_rdb_file)?;
-                    content.extend(format!("${}\r\n", raw_content.len()).as_bytes());
-                    content.extend(raw_content);
-                    content
+                    let mut db = self.db.write().await;
+                    let hash_record = db.get_or_insert_as(&key, HashRecord::new)?;
+                    let current = match hash_record.get(&field) {
+                        Some(value) => str::from_utf8(value).ok().and_then(|value| value.parse::<f64>().ok()).filter(|value| value.is_finite())
+                            .ok_or(RedisError::Client("hash value is not a float".to_string()))?,
+                        None => 0.0,
+                    };
+                    let new_value = current + increment;
+                    if !new_value.is_finite() {
+                        return Err(RedisError::Client("increment would produce NaN or Infinity".to_string()))
+                    }
+                    let new_value = format_redis_double(new_value).into_bytes();
+                    hash_record.insert_keeping_ttl(field.clone(), new_value.clone());
+                    // float arithmetic may come out different elsewhere, so replicas get the result
+                    let (hset, expires) = hash_set_commands(&key, hash_record, &[field]);
+                    self.propagation = Propagation::Rewritten(hset);
+                    self.also_propagated.extend(expires);
+                    RedisValue::String(new_value).encode_with(self.protocol)
                 }
             },
-            c => RedisValue::Error(format!("Err unknown command '{}'", c)).encode(),
-        };
-        Ok(response)
-    }
-
-    async fn exec_queued(&mut self) -> Result<Vec<u8>> {
-        if self.multi_mode {
-            let mut outputs = vec![];
-            for queued_command in self.queued_commands.clone() {
-                let command = &queued_command[0];
-                let value = self.execute_command(&command.get_string()?, queued_command.clone()).await?;
-                outputs.push(value);
-            }
-            let mut exec_output = format!("*{}\r\n", outputs.len()).as_bytes().to_vec();
-            for output in outputs {
-                exec_output.extend(output);
-            }
-            self.multi_mode = false;
-            Ok(exec_output)
-        } else {
-            Ok(RedisValue::Error("ERR EXEC without MULTI".to_string()).encode())
-        }
+            "HRANDFIELD" => {
+                if args.len() < 2 || args.len() > 4 {
+                    return Err(RedisError::WrongArity("HRANDFIELD".to_string()))
+                } else {
+                    let with_values = match args.get(3) {
+                        Some(option) if option.get_string()?.to_uppercase() == "WITHVALUES" => true,
+                        Some(_) => return Err(RedisError::Syntax),
+                        None => false,
+                    };
+                    let count = match args.get(2) {
+                        Some(count) => Some(parse_random_count(count, with_values)?),
+                        None => None,
+                    };
+                    let db = self.db.read().await;
+                    let hash_record = db.get_as::<HashRecord>(&args[1].get_bytes()?)?;
+                    let mut rng = rand::rng();
+                    match (hash_record, count) {
+                        (None, None) => RedisValue::NullString.encode_with(self.protocol),
+                        (Some(hash_record), None) => {
+                            let (field, _) = hash_record.random_field(&mut rng).unwrap();
+                            RedisValue::String(field.clone()).encode_with(self.protocol)
+                        },
+                        (hash_record, Some(count)) => {
+                            let picked = match hash_record {
+                                None => vec![],
+                                // a negative count may pick the same field more than once
+                                Some(hash_record) if count < 0 => {
+                                    (0..count.unsigned_abs()).map(|_| hash_record.random_field(&mut rng).unwrap()).collect()
+                                },
+                                Some(hash_record) => {
+                                    let fields = hash_record.iter().collect::<Vec<_>>();
+                                    let amount = (count as usize).min(fields.len());
+                                    rand::seq::index::sample(&mut rng, fields.len(), amount).into_iter().map(|i| fields[i]).collect()
+                                },
+                            };
+                            let reply = picked.into_iter().flat_map(|(field, value)| {
+                                let field = RedisValue::String(field.clone());
+                                match (with_values, self.protocol) {
+                                    (false, _) => vec![field],
+                                    // pairs on RESP3, one flat list on RESP2
+                                    (true, Protocol::Resp3) => vec![RedisValue::Array(vec![field, RedisValue::String(value.clone())])],
+                                    (true, Protocol::Resp2) => vec![field, RedisValue::String(value.clone())],
+                                }
+                            }).collect();
+                            RedisValue::Array(reply).encode_with(self.protocol)
+                        },
+                    }
+                }
+            },
+            "HSCAN" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("HSCAN".to_string()))
+                } else {
+                    let cursor = parse_cursor(&args[2])?;
+                    let options = ScanOptions::parse(&args[3..], command)?;
+                    let db = self.db.read().await;
+                    let (next_cursor, fields) = match db.get_as::<HashRecord>(&args[1].get_bytes()?)? {
+                        Some(hash_record) => hash_record.scan(cursor, options.count),
+                        None => (0, vec![]),
+                    };
+                    let mut reply = vec![];
+                    for (field, value) in fields.into_iter().filter(|(field, _)| options.matches(field)) {
+                        reply.push(RedisValue::String(field.clone()));
+                        if !options.no_values {
+                            reply.push(RedisValue::String(value.clone()));
+                        }
+                    }
+                    RedisValue::Array(vec![RedisValue::String(next_cursor.to_string().into()), RedisValue::Array(reply)]).encode_with(self.protocol)
+                }
+            },
+            "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => {
+                if args.len() < 6 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let amount = args[2].get_string()?.parse::<i64>()?;
+                    let lowercase = command.to_lowercase();
+                    if amount < 0 {
+                        return Err(RedisError::Client(format!("invalid expire time in '{}' command", lowercase)))
+                    }
+                    let limit = expire_limit(&command[1..], amount, &lowercase)?;
+                    let condition = args[3].get_string()?.to_uppercase();
+                    let (condition, fields) = match condition.as_str() {
+                        "NX" | "XX" | "GT" | "LT" => (Some(condition), parse_fields(&args[4..], 1)?),
+                        _ => (None, parse_fields(&args[3..], 1)?),
+                    };
+                    let mut db = self.db.write().await;
+                    let mut replies = vec![];
+                    let mut changed = vec![];
+                    match db.get_mut_as::<HashRecord>(&key)? {
+                        None => replies.extend(fields.iter().map(|_| RedisValue::Int(-2))),
+                        Some(hash_record) => {
+                            for field in fields {
+                                let field = field.get_bytes()?;
+                                if hash_record.get(&field).is_none() {
+                                    replies.push(RedisValue::Int(-2));
+                                    continue;
+                                }
+                                // a field without a time to live counts as one that never expires
+                                let current = hash_record.get_expire(&field);
+                                let applies = match condition.as_deref() {
+                                    Some("NX") => current.is_none(),
+                                    Some("XX") => current.is_some(),
+                                    Some("GT") => current.is_some_and(|current| limit > current),
+                                    Some("LT") => current.is_none_or(|current| limit < current),
+                                    _ => true,
+                                };
+                                if !applies {
+                                    replies.push(RedisValue::Int(0));
+                                } else if limit <= Utc::now() {
+                                    // a time in the past deletes the field right away
+                                    hash_record.remove(&field);
+                                    replies.push(RedisValue::Int(2));
+                                    changed.push(RedisValue::String(field));
+                                } else {
+                                    hash_record.set_expire(&field, limit);
+                                    replies.push(RedisValue::Int(1));
+                                    changed.push(RedisValue::String(field));
+                                }
+                            }
+                        },
+                    }
+                    db.update_hash(&key);
+                    if changed.is_empty() {
+                        self.propagation = Propagation::Prevented;
+                    } else {
+                        // replicas must expire the fields at the same time, however late they get the command
+                        let mut effective = vec![
+                            RedisValue::String("HPEXPIREAT".into()),
+                            RedisValue::String(key),
+                            RedisValue::String(limit.timestamp_millis().to_string().into()),
+                            RedisValue::String("FIELDS".into()),
+                            RedisValue::String(changed.len().to_string().into()),
+                        ];
+                        effective.extend(changed);
+                        self.propagation = Propagation::Rewritten(effective);
+                    }
+                    RedisValue::Array(replies).encode_with(self.protocol)
+                }
+            },
+            "HTTL" | "HPTTL" => {
+                if args.len() < 5 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let fields = parse_fields(&args[2..], 1)?;
+                    let db = self.db.read().await;
+                    let hash_record = db.get_as::<HashRecord>(&args[1].get_bytes()?)?;
+                    let mut replies = vec![];
+                    for field in fields {
+                        let field = field.get_bytes()?;
+                        let reply = match hash_record.filter(|hash_record| hash_record.get(&field).is_some()) {
+                            None => -2,
+                            Some(hash_record) => match hash_record.get_expire(&field) {
+                                None => -1,
+                                Some(limit) => {
+                                    let remaining = (limit - Utc::now()).num_milliseconds();
+                                    if command == "HTTL" { (remaining + 500) / 1000 } else { remaining }
+                                },
+                            },
+                        };
+                        replies.push(RedisValue::Int(reply));
+                    }
+                    RedisValue::Array(replies).encode_with(self.protocol)
+                }
+            },
+            "HPERSIST" => {
+                if args.len() < 5 {
+                    return Err(RedisError::WrongArity("HPERSIST".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let fields = parse_fields(&args[2..], 1)?;
+                    let mut db = self.db.write().await;
+                    let mut replies = vec![];
+                    match db.get_mut_as::<HashRecord>(&key)? {
+                        None => replies.extend(fields.iter().map(|_| RedisValue::Int(-2))),
+                        Some(hash_record) => {
+                            for field in fields {
+                                let field = field.get_bytes()?;
+                                let reply = if hash_record.get(&field).is_none() {
+                                    -2
+                                } else if hash_record.persist(&field) {
+                                    1
+                                } else {
+                                    -1
+                                };
+                                replies.push(RedisValue::Int(reply));
+                            }
+                        },
+                    }
+                    db.update_hash(&key);
+                    if !replies.iter().any(|reply| matches!(reply, RedisValue::Int(1))) {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Array(replies).encode_with(self.protocol)
+                }
+            },
+            "HGETEX" => {
+                if args.len() < 5 {
+                    return Err(RedisError::WrongArity("HGETEX".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    // without options the times to live are left alone, PERSIST clears them
+                    let option = args[2].get_string()?.to_uppercase();
+                    let (expire, fields) = match option.as_str() {
+                        "PERSIST" => (SetExpire::Clear, parse_fields(&args[3..], 1)?),
+                        "EX" | "PX" | "EXAT" | "PXAT" => (SetExpire::At(parse_expire_option(&option, &args[3], "hgetex")?), parse_fields(&args[4..], 1)?),
+                        _ => (SetExpire::Keep, parse_fields(&args[2..], 1)?),
+                    };
+                    let mut db = self.db.write().await;
+                    let mut values = vec![];
+                    let mut changed = vec![];
+                    match db.get_mut_as::<HashRecord>(&key)? {
+                        None => values.extend(fields.iter().map(|_| RedisValue::NullString)),
+                        Some(hash_record) => {
+                            for field in fields {
+                                let field = field.get_bytes()?;
+                                let Some(value) = hash_record.get(&field).cloned() else {
+                                    values.push(RedisValue::NullString);
+                                    continue;
+                                };
+                                values.push(RedisValue::String(value));
+                                let changes = match expire {
+                                    SetExpire::Keep => false,
+                                    SetExpire::Clear => hash_record.persist(&field),
+                                    SetExpire::At(limit) if limit <= Utc::now() => hash_record.remove(&field),
+                                    SetExpire::At(limit) => hash_record.set_expire(&field, limit),
+                                };
+                                if changes {
+                                    changed.push(RedisValue::String(field));
+                                }
+                            }
+                        },
+                    }
+                    db.update_hash(&key);
+                    self.propagation = match expire {
+                        _ if changed.is_empty() => Propagation::Prevented,
+                        SetExpire::At(limit) => {
+                            let mut effective = vec![
+                                RedisValue::String("HPEXPIREAT".into()),
+                                RedisValue::String(key),
+                                RedisValue::String(limit.timestamp_millis().to_string().into()),
+                                RedisValue::String("FIELDS".into()),
+                                RedisValue::String(changed.len().to_string().into()),
+                            ];
+                            effective.extend(changed);
+                            Propagation::Rewritten(effective)
+                        },
+                        _ => {
+                            let mut effective = vec![
+                                RedisValue::String("HPERSIST".into()),
+                                RedisValue::String(key),
+                                RedisValue::String("FIELDS".into()),
+                                RedisValue::String(changed.len().to_string().into()),
+                            ];
+                            effective.extend(changed);
+                            Propagation::Rewritten(effective)
+                        },
+                    };
+                    RedisValue::Array(values).encode_with(self.protocol)
+                }
+            },
+            "HSETEX" => {
+                if args.len() < 6 {
+                    return Err(RedisError::WrongArity("HSETEX".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let mut condition = SetCondition::Always;
+                    let mut expire = SetExpire::Clear;
+                    let mut i = 2;
+                    while i < args.len() {
+                        let option = args[i].get_string()?.to_uppercase();
+                        match option.as_str() {
+                            "FIELDS" => break,
+                            "FNX" if matches!(condition, SetCondition::Always) => condition = SetCondition::IfMissing,
+                            "FXX" if matches!(condition, SetCondition::Always) => condition = SetCondition::IfExists,
+                            "KEEPTTL" if matches!(expire, SetExpire::Clear) => expire = SetExpire::Keep,
+                            "EX" | "PX" | "EXAT" | "PXAT" if matches!(expire, SetExpire::Clear) && i + 1 < args.len() => {
+                                expire = SetExpire::At(parse_expire_option(&option, &args[i + 1], "hsetex")?);
+                                i += 1;
+                            },
+                            _ => return Err(RedisError::Syntax),
+                        }
+                        i += 1;
+                    }
+                    let pairs = parse_fields(&args[i..], 2)?;
+                    let mut db = self.db.write().await;
+                    let hash_record = db.get_as::<HashRecord>(&key)?;
+                    let mut existing = 0;
+                    for pair in pairs.chunks(2) {
+                        let field = pair[0].get_bytes()?;
+                        if hash_record.is_some_and(|hash_record| hash_record.get(&field).is_some()) {
+                            existing += 1;
+                        }
+                    }
+                    // FNX sets the fields only if none of them is there, FXX only if all of them are
+                    let applies = match condition {
+                        SetCondition::Always => true,
+                        SetCondition::IfMissing => existing == 0,
+                        SetCondition::IfExists => existing == pairs.len() / 2,
+                    };
+                    if !applies {
+                        self.propagation = Propagation::Prevented;
+                        return Ok(RedisValue::Int(0).encode())
+                    }
+                    let hash_record = db.get_or_insert_as(&key, HashRecord::new)?;
+                    let mut fields = vec![];
+                    for pair in pairs.chunks(2) {
+                        let field = pair[0].get_bytes()?;
+                        let value = pair[1].get_bytes()?;
+                        match expire {
+                            SetExpire::Clear => {
+                                hash_record.insert(field.clone(), value);
+                            },
+                            SetExpire::Keep => {
+                                hash_record.insert_keeping_ttl(field.clone(), value);
+                            },
+                            SetExpire::At(limit) if limit <= Utc::now() => {
+                                hash_record.remove(&field);
+                            },
+                            SetExpire::At(limit) => {
+                                hash_record.insert(field.clone(), value);
+                                hash_record.set_expire(&field, limit);
+                            },
+                        }
+                        fields.push(field);
+                    }
+                    // the condition held, and replicas must expire the fields at the same time
+                    if let SetExpire::Keep = expire {
+                        let (hset, expires) = hash_set_commands(&key, hash_record, &fields);
+                        self.propagation = Propagation::Rewritten(hset);
+                        self.also_propagated.extend(expires);
+                    } else {
+                        let mut effective = vec![RedisValue::String("HSETEX".into()), RedisValue::String(key.clone())];
+                        if let SetExpire::At(limit) = expire {
+                            effective.push(RedisValue::String("PXAT".into()));
+                            effective.push(RedisValue::String(limit.timestamp_millis().to_string().into()));
+                        }
+                        effective.extend(args[i..].iter().cloned());
+                        self.propagation = Propagation::Rewritten(effective);
+                    }
+                    db.update_hash(&key);
+                    RedisValue::Int(1).encode()
+                }
+            },
+            "SADD" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("SADD".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let set_record = db.get_or_insert_as(&key, SetRecord::new)?;
+                    let mut added = 0;
+                    for member in &args[2..] {
+                        if set_record.insert(member.get_bytes()?) {
+                            added += 1;
+                        }
+                    }
+                    if added == 0 {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(added).encode()
+                }
+            },
+            "SREM" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("SREM".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let mut removed = 0;
+                    if let Some(set_record) = db.get_mut_as::<SetRecord>(&key)? {
+                        for member in &args[2..] {
+                            if set_record.remove(&member.get_bytes()?) {
+                                removed += 1;
+                            }
+                        }
+                        // a set without members is no key at all
+                        if set_record.is_empty() {
+                            db.remove(&key);
+                        }
+                    }
+                    if removed == 0 {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(removed).encode()
+                }
+            },
+            "SMEMBERS" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("SMEMBERS".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let members = db.get_as::<SetRecord>(&args[1].get_bytes()?)?.map_or(vec![], SetRecord::members);
+                    RedisValue::Set(members.into_iter().map(RedisValue::String).collect()).encode_with(self.protocol)
+                }
+            },
+            "SISMEMBER" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("SISMEMBER".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let member = args[2].get_bytes()?;
+                    let is_member = db.get_as::<SetRecord>(&args[1].get_bytes()?)?.is_some_and(|set_record| set_record.contains(&member));
+                    RedisValue::Int(is_member as i64).encode()
+                }
+            },
+            "SMISMEMBER" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("SMISMEMBER".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let set_record = db.get_as::<SetRecord>(&args[1].get_bytes()?)?;
+                    let mut replies = vec![];
+                    for member in &args[2..] {
+                        let member = member.get_bytes()?;
+                        replies.push(RedisValue::Int(set_record.is_some_and(|set_record| set_record.contains(&member)) as i64));
+                    }
+                    RedisValue::Array(replies).encode_with(self.protocol)
+                }
+            },
+            "SCARD" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("SCARD".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let len = db.get_as::<SetRecord>(&args[1].get_bytes()?)?.map_or(0, SetRecord::len);
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "SPOP" => {
+                if args.len() < 2 || args.len() > 3 {
+                    return Err(RedisError::WrongArity("SPOP".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let count = match args.get(2) {
+                        Some(count) => {
+                            let count = count.get_string()?.parse::<i64>()?;
+                            if count < 0 {
+                                return Err(RedisError::Client("value is out of range, must be positive".to_string()))
+                            }
+                            Some(count as usize)
+                        },
+                        None => None,
+                    };
+                    let mut db = self.db.write().await;
+                    let mut popped = vec![];
+                    if let Some(set_record) = db.get_mut_as::<SetRecord>(&key)? {
+                        let mut rng = rand::rng();
+                        for _ in 0..count.unwrap_or(1).min(set_record.len()) {
+                            let member = set_record.random_member(&mut rng).unwrap();
+                            set_record.remove(&member);
+                            popped.push(member);
+                        }
+                        if set_record.is_empty() {
+                            db.remove(&key);
+                        }
+                    }
+                    // the members are picked at random, so replicas are told which ones went
+                    if popped.is_empty() {
+                        self.propagation = Propagation::Prevented;
+                    } else {
+                        let mut effective = vec![RedisValue::String("SREM".into()), RedisValue::String(key)];
+                        effective.extend(popped.iter().cloned().map(RedisValue::String));
+                        self.propagation = Propagation::Rewritten(effective);
+                    }
+                    match count {
+                        Some(_) => RedisValue::Set(popped.into_iter().map(RedisValue::String).collect()),
+                        None => popped.pop().map_or(RedisValue::NullString, RedisValue::String),
+                    }.encode_with(self.protocol)
+                }
+            },
+            "SRANDMEMBER" => {
+                if args.len() < 2 || args.len() > 3 {
+                    return Err(RedisError::WrongArity("SRANDMEMBER".to_string()))
+                } else {
+                    let count = match args.get(2) {
+                        Some(count) => Some(parse_random_count(count, false)?),
+                        None => None,
+                    };
+                    let db = self.db.read().await;
+                    let set_record = db.get_as::<SetRecord>(&args[1].get_bytes()?)?;
+                    let mut rng = rand::rng();
+                    match (set_record, count) {
+                        (None, None) => RedisValue::NullString.encode_with(self.protocol),
+                        (Some(set_record), None) => RedisValue::String(set_record.random_member(&mut rng).unwrap()).encode_with(self.protocol),
+                        (set_record, Some(count)) => {
+                            let picked = match set_record {
+                                None => vec![],
+                                // a negative count may pick the same member more than once
+                                Some(set_record) if count < 0 => {
+                                    (0..count.unsigned_abs()).map(|_| set_record.random_member(&mut rng).unwrap()).collect()
+                                },
+                                Some(set_record) => {
+                                    let members = set_record.members();
+                                    let amount = (count as usize).min(members.len());
+                                    rand::seq::index::sample(&mut rng, members.len(), amount).into_iter().map(|i| members[i].clone()).collect()
+                                },
+                            };
+                            RedisValue::Array(picked.into_iter().map(RedisValue::String).collect()).encode_with(self.protocol)
+                        },
+                    }
+                }
+            },
+            "SMOVE" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("SMOVE".to_string()))
+                } else {
+                    let source = args[1].get_bytes()?;
+                    let destination = args[2].get_bytes()?;
+                    let member = args[3].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    // the destination has to be a set even when nothing moves
+                    db.get_as::<SetRecord>(&destination)?;
+                    let exists = db.get_as::<SetRecord>(&source)?.is_some_and(|set_record| set_record.contains(&member));
+                    if !exists || source == destination {
+                        self.propagation = Propagation::Prevented;
+                        RedisValue::Int(exists as i64).encode()
+                    } else {
+                        let set_record = db.get_mut_as::<SetRecord>(&source)?.unwrap();
+                        set_record.remove(&member);
+                        if set_record.is_empty() {
+                            db.remove(&source);
+                        }
+                        db.get_or_insert_as(&destination, SetRecord::new)?.insert(member);
+                        RedisValue::Int(1).encode()
+                    }
+                }
+            },
+            "SINTER" | "SUNION" | "SDIFF" => {
+                if args.len() < 2 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let members = set_operation(command, get_sets(&db, &args[1..])?);
+                    RedisValue::Set(members.into_iter().map(RedisValue::String).collect()).encode_with(self.protocol)
+                }
+            },
+            "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let destination = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let members = set_operation(command, get_sets(&db, &args[2..])?);
+                    let len = members.len();
+                    // an empty result leaves no key behind
+                    if members.is_empty() {
+                        db.remove(&destination);
+                    } else {
+                        let mut set_record = SetRecord::new();
+                        for member in members {
+                            set_record.insert(member);
+                        }
+                        db.insert(destination, DbRecord::Set(set_record));
+                    }
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "SINTERCARD" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("SINTERCARD".to_string()))
+                } else {
+                    let num_keys = args[1].get_string()?.parse::<i64>()?;
+                    if num_keys <= 0 {
+                        return Err(RedisError::Client("numkeys should be greater than 0".to_string()))
+                    }
+                    if num_keys as usize > args.len() - 2 {
+                        return Err(RedisError::Client("Number of keys can't be greater than number of args".to_string()))
+                    }
+                    let (keys, options) = args[2..].split_at(num_keys as usize);
+                    let limit = match options {
+                        [] => 0,
+                        [option, limit] if option.get_string()?.to_uppercase() == "LIMIT" => {
+                            let limit = limit.get_string()?.parse::<i64>()?;
+                            if limit < 0 {
+                                return Err(RedisError::Client("LIMIT can't be negative".to_string()))
+                            }
+                            limit as usize
+                        },
+                        _ => return Err(RedisError::Syntax),
+                    };
+                    let db = self.db.read().await;
+                    // a LIMIT of 0 means no limit
+                    let len = match get_sets(&db, keys)?.into_iter().collect::<Option<Vec<_>>>() {
+                        Some(sets) => set_intersection(&sets, if limit == 0 { usize::MAX } else { limit }).len(),
+                        None => 0,
+                    };
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "SSCAN" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("SSCAN".to_string()))
+                } else {
+                    let cursor = parse_cursor(&args[2])?;
+                    let options = ScanOptions::parse(&args[3..], command)?;
+                    let db = self.db.read().await;
+                    let (next_cursor, members) = match db.get_as::<SetRecord>(&args[1].get_bytes()?)? {
+                        Some(set_record) => set_record.scan(cursor, options.count),
+                        None => (0, vec![]),
+                    };
+                    let members = members.into_iter().filter(|member| options.matches(member)).map(RedisValue::String).collect();
+                    RedisValue::Array(vec![RedisValue::String(next_cursor.to_string().into()), RedisValue::Array(members)]).encode_with(self.protocol)
+                }
+            },
+            "ZADD" => {
+                if args.len() < 4 {
+                    return Err(RedisError::WrongArity("ZADD".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) = (false, false, false, false, false, false);
+                    let mut i = 2;
+                    while i < args.len() {
+                        match args[i].get_string()?.to_uppercase().as_str() {
+                            "NX" => nx = true,
+                            "XX" => xx = true,
+                            "GT" => gt = true,
+                            "LT" => lt = true,
+                            "CH" => ch = true,
+                            "INCR" => incr = true,
+                            _ => break,
+                        }
+                        i += 1;
+                    }
+                    let pairs = &args[i..];
+                    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+                        return Err(RedisError::Syntax)
+                    }
+                    if nx && xx {
+                        return Err(RedisError::Client("XX and NX options at the same time are not compatible".to_string()))
+                    }
+                    if (gt && lt) || (nx && (gt || lt)) {
+                        return Err(RedisError::Client("GT, LT, and/or NX options at the same time are not compatible".to_string()))
+                    }
+                    if incr && pairs.len() > 2 {
+                        return Err(RedisError::Client("INCR option supports a single increment-element pair".to_string()))
+                    }
+                    let mut elements = vec![];
+                    for pair in pairs.chunks(2) {
+                        elements.push((parse_score(&pair[0])?, pair[1].get_bytes()?));
+                    }
+                    let mut db = self.db.write().await;
+                    // with XX there is nothing to add, so a missing key stays missing
+                    let mut zset_record = if xx { db.get_mut_as::<ZSetRecord>(&key)? } else { Some(db.get_or_insert_as(&key, ZSetRecord::new)?) };
+                    let (mut added, mut changed) = (0, 0);
+                    let mut incr_result = None;
+                    for (score, member) in elements {
+                        let Some(zset_record) = zset_record.as_deref_mut() else { break };
+                        match zset_record.score(&member) {
+                            Some(current) => {
+                                if nx {
+                                    continue;
+                                }
+                                let new_score = if incr { current + score } else { score };
+                                if new_score.is_nan() {
+                                    return Err(RedisError::Client("resulting score is not a number (NaN)".to_string()))
+                                }
+                                if (gt && new_score <= current) || (lt && new_score >= current) {
+                                    continue;
+                                }
+                                incr_result = Some((member.clone(), new_score));
+                                if new_score != current {
+                                    zset_record.insert(member, new_score);
+                                    changed += 1;
+                                }
+                            },
+                            None => {
+                                if xx {
+                                    continue;
+                                }
+                                incr_result = Some((member.clone(), score));
+                                zset_record.insert(member, score);
+                                added += 1;
+                            },
+                        }
+                    }
+                    let served = serve_zset_waiters(&mut db, &key);
+                    self.also_propagated.extend(served);
+                    if added + changed == 0 {
+                        self.propagation = Propagation::Prevented;
+                    } else if incr && let Some((member, score)) = &incr_result {
+                        // float arithmetic may come out different elsewhere, so replicas get the result
+                        self.propagation = Propagation::Rewritten(vec![
+                            RedisValue::String("ZADD".into()),
+                            RedisValue::String(key),
+                            RedisValue::String(score.to_string().into()),
+                            RedisValue::String(member.clone()),
+                        ]);
+                    }
+                    if incr {
+                        incr_result.map_or(RedisValue::NullString, |(_, score)| RedisValue::Double(score)).encode_with(self.protocol)
+                    } else {
+                        RedisValue::Int(if ch { added + changed } else { added }).encode()
+                    }
+                }
+            },
+            "ZINCRBY" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("ZINCRBY".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let increment = parse_score(&args[2])?;
+                    let member = args[3].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let zset_record = db.get_or_insert_as(&key, ZSetRecord::new)?;
+                    let score = zset_record.score(&member).unwrap_or(0.0) + increment;
+                    if score.is_nan() {
+                        return Err(RedisError::Client("resulting score is not a number (NaN)".to_string()))
+                    }
+                    zset_record.insert(member.clone(), score);
+                    let served = serve_zset_waiters(&mut db, &key);
+                    self.also_propagated.extend(served);
+                    // float arithmetic may come out different elsewhere, so replicas get the result
+                    self.propagation = Propagation::Rewritten(vec![
+                        RedisValue::String("ZADD".into()),
+                        RedisValue::String(key),
+                        RedisValue::String(score.to_string().into()),
+                        RedisValue::String(member),
+                    ]);
+                    RedisValue::Double(score).encode_with(self.protocol)
+                }
+            },
+            "ZREM" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("ZREM".to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let mut db = self.db.write().await;
+                    let mut removed = 0;
+                    if let Some(zset_record) = db.get_mut_as::<ZSetRecord>(&key)? {
+                        for member in &args[2..] {
+                            if zset_record.remove(&member.get_bytes()?) {
+                                removed += 1;
+                            }
+                        }
+                        db.update_zset(&key);
+                    }
+                    if removed == 0 {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    RedisValue::Int(removed).encode()
+                }
+            },
+            "ZSCORE" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("ZSCORE".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let member = args[2].get_bytes()?;
+                    match db.get_as::<ZSetRecord>(&args[1].get_bytes()?)?.and_then(|zset_record| zset_record.score(&member)) {
+                        Some(score) => RedisValue::Double(score).encode_with(self.protocol),
+                        None => RedisValue::NullString.encode_with(self.protocol),
+                    }
+                }
+            },
+            "ZMSCORE" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("ZMSCORE".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let zset_record = db.get_as::<ZSetRecord>(&args[1].get_bytes()?)?;
+                    let mut scores = vec![];
+                    for member in &args[2..] {
+                        let member = member.get_bytes()?;
+                        scores.push(zset_record.and_then(|zset_record| zset_record.score(&member)).map_or(RedisValue::NullString, RedisValue::Double));
+                    }
+                    RedisValue::Array(scores).encode_with(self.protocol)
+                }
+            },
+            "ZCARD" => {
+                if args.len() != 2 {
+                    return Err(RedisError::WrongArity("ZCARD".to_string()))
+                } else {
+                    let db = self.db.read().await;
+                    let len = db.get_as::<ZSetRecord>(&args[1].get_bytes()?)?.map_or(0, ZSetRecord::len);
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "ZCOUNT" => {
+                if args.len() != 4 {
+                    return Err(RedisError::WrongArity("ZCOUNT".to_string()))
+                } else {
+                    let range = ZRange::Score(ScoreBound::parse(&args[2])?, ScoreBound::parse(&args[3])?);
+                    let db = self.db.read().await;
+                    let count = match db.get_as::<ZSetRecord>(&args[1].get_bytes()?)? {
+                        Some(zset_record) => {
+                            let (start, end) = range.ranks(zset_record, false);
+                            end - start
+                        },
+                        None => 0,
+                    };
+                    RedisValue::Int(count as i64).encode()
+                }
+            },
+            "ZRANK" | "ZREVRANK" => {
+                if args.len() < 3 || args.len() > 4 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let with_score = match args.get(3) {
+                        Some(option) if option.get_string()?.to_uppercase() == "WITHSCORE" => true,
+                        Some(_) => return Err(RedisError::Syntax),
+                        None => false,
+                    };
+                    let member = args[2].get_bytes()?;
+                    let db = self.db.read().await;
+                    let zset_record = db.get_as::<ZSetRecord>(&args[1].get_bytes()?)?;
+                    match zset_record.and_then(|zset_record| Some((zset_record, zset_record.rank(&member)?))) {
+                        None if with_score => RedisValue::NullArray,
+                        None => RedisValue::NullString,
+                        Some((zset_record, rank)) => {
+                            let rank = if command == "ZREVRANK" { zset_record.len() - 1 - rank } else { rank };
+                            if with_score {
+                                RedisValue::Array(vec![RedisValue::Int(rank as i64), RedisValue::Double(zset_record.score(&member).unwrap())])
+                            } else {
+                                RedisValue::Int(rank as i64)
+                            }
+                        },
+                    }.encode_with(self.protocol)
+                }
+            },
+            "ZRANGE" => {
+                if args.len() < 4 {
+                    return Err(RedisError::WrongArity("ZRANGE".to_string()))
+                } else {
+                    let query = ZRangeQuery::parse(&args[2..])?;
+                    let db = self.db.read().await;
+                    let members = db.get_as::<ZSetRecord>(&args[1].get_bytes()?)?.map_or(vec![], |zset_record| query.members(zset_record));
+                    zset_reply(members, query.with_scores, self.protocol).encode_with(self.protocol)
+                }
+            },
+            "ZRANGESTORE" => {
+                if args.len() < 5 {
+                    return Err(RedisError::WrongArity("ZRANGESTORE".to_string()))
+                } else {
+                    let destination = args[1].get_bytes()?;
+                    let query = ZRangeQuery::parse(&args[3..])?;
+                    if query.with_scores {
+                        return Err(RedisError::Syntax)
+                    }
+                    let mut db = self.db.write().await;
+                    let mut stored = ZSetRecord::new();
+                    if let Some(zset_record) = db.get_as::<ZSetRecord>(&args[2].get_bytes()?)? {
+                        for (member, score) in query.members(zset_record) {
+                            stored.insert(member, score);
+                        }
+                    }
+                    let len = stored.len();
+                    let served = store_zset(&mut db, &destination, stored);
+                    self.also_propagated.extend(served);
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "ZUNION" | "ZINTER" | "ZDIFF" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let operation = ZSetOperation::parse(command, &args[1..])?;
+                    let db = self.db.read().await;
+                    let result = operation.run(command, get_zset_inputs(&db, &operation.keys)?);
+                    let members = ZRange::Rank(0, -1).members(&result, false, None);
+                    zset_reply(members, operation.with_scores, self.protocol).encode_with(self.protocol)
+                }
+            },
+            "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                if args.len() < 4 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let destination = args[1].get_bytes()?;
+                    let operation = ZSetOperation::parse(command, &args[2..])?;
+                    let mut db = self.db.write().await;
+                    let result = operation.run(command, get_zset_inputs(&db, &operation.keys)?);
+                    let len = result.len();
+                    let served = store_zset(&mut db, &destination, result);
+                    self.also_propagated.extend(served);
+                    RedisValue::Int(len as i64).encode()
+                }
+            },
+            "ZPOPMIN" | "ZPOPMAX" => {
+                if args.len() < 2 || args.len() > 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let key = args[1].get_bytes()?;
+                    let count = match args.get(2) {
+                        Some(count) => Some(usize::try_from(count.get_string()?.parse::<i64>()?)
+                            .map_err(|_| RedisError::Client("value is out of range, must be positive".to_string()))?),
+                        None => None,
+                    };
+                    let end = if command == "ZPOPMIN" { ZSetEnd::Min } else { ZSetEnd::Max };
+                    let mut db = self.db.write().await;
+                    let mut popped = vec![];
+                    if db.get_as::<ZSetRecord>(&key)?.is_some() {
+                        popped = db.get_mut_as::<ZSetRecord>(&key)?.unwrap().pop(end, count.unwrap_or(1));
+                        db.update_zset(&key);
+                    }
+                    if popped.is_empty() {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    // a single member comes with its score in one flat list, even on RESP3
+                    if count.is_some() {
+                        zset_reply(popped, true, self.protocol)
+                    } else {
+                        zset_reply(popped, true, Protocol::Resp2)
+                    }.encode_with(self.protocol)
+                }
+            },
+            "ZMPOP" => {
+                if args.len() < 4 {
+                    return Err(RedisError::WrongArity("ZMPOP".to_string()))
+                } else {
+                    let (keys, end, count) = parse_zmpop(&args[1..])?;
+                    let mut db = self.db.write().await;
+                    match pop_first_zset(&mut db, &keys, end, count)? {
+                        Some((key, popped)) => {
+                            self.propagation = Propagation::Rewritten(zset_pop_command(&key, end, popped.len()));
+                            zmpop_reply(key, popped)
+                        },
+                        None => {
+                            self.propagation = Propagation::Prevented;
+                            RedisValue::NullArray
+                        },
+                    }.encode_with(self.protocol)
+                }
+            },
+            "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" => {
+                if args.len() < 3 || (command == "BZMPOP" && args.len() < 5) {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let (keys, end, count, timeout) = if command == "BZMPOP" {
+                        let (keys, end, count) = parse_zmpop(&args[2..])?;
+                        (keys, end, count, &args[1])
+                    } else {
+                        let keys = args[1..args.len() - 1].iter().map(RedisValue::get_bytes).collect::<Result<Vec<_>, _>>()?;
+                        let end = if command == "BZPOPMIN" { ZSetEnd::Min } else { ZSetEnd::Max };
+                        (keys, end, 1, &args[args.len() - 1])
+                    };
+                    let timeout = parse_timeout(timeout)?;
+                    let mut popped;
+                    let mut waiter = None;
+                    // either pop from the first key with members, or wait on all of them for some
+                    {
+                        let mut db = self.db.write().await;
+                        popped = pop_first_zset(&mut db, &keys, end, count)?;
+                        if let Some((key, members)) = &popped {
+                            self.propagation = Propagation::Rewritten(zset_pop_command(key, end, members.len()));
+                        } else if !self.in_exec {
+                            let (sender, receiver) = unbounded_channel();
+                            let zset_waiter = ZSetWaiter::new(end, count, sender);
+                            for key in &keys {
+                                db.block_on_zset(key, zset_waiter.clone());
+                            }
+                            waiter = Some(ZSetWait { db: Arc::clone(&self.db), keys, receiver });
+                        }
+                    }
+                    if let Some(mut waiter) = waiter {
+                        // other writers must be able to run while this one is blocked
+                        self.write_lock = None;
+                        // the pop is propagated by the command that served this client
+                        self.propagation = Propagation::Prevented;
+                        if let Some(timeout) = timeout {
+                            tokio::select! {
+                                result = waiter.receiver.recv() => {
+                                    popped = result;
+                                }
+                                _ = time::sleep(timeout) => {
+                                    // members may have been handed over right as the time ran out
+                                    waiter.receiver.close();
+                                    popped = waiter.receiver.try_recv().ok();
+                                }
+                            }
+                        } else {
+                            popped = waiter.receiver.recv().await;
+                        }
+                    }
+                    // nothing popped when called inside MULTI, or when the time ran out
+                    if popped.is_none() {
+                        self.propagation = Propagation::Prevented;
+                    }
+                    match popped {
+                        Some((key, popped)) if command == "BZMPOP" => zmpop_reply(key, popped),
+                        Some((key, mut popped)) if !popped.is_empty() => {
+                            let (member, score) = popped.remove(0);
+                            RedisValue::Array(vec![RedisValue::String(key), RedisValue::String(member), RedisValue::Double(score)])
+                        },
+                        _ => RedisValue::NullArray,
+                    }.encode_with(self.protocol)
+                }
+            },
+            "MULTI" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("MULTI".to_string()))
+                } else {
+                    self.multi_mode = true;
+                    RedisValue::String("OK".into()).as_simple_string()?
+                }
+            },
+            "DISCARD" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("DISCARD".to_string()))
+                } else {
+                    if self.multi_mode {
+                        self.multi_mode = false;
+                        self.queued_commands = vec![];
+                        RedisValue::String("OK".into()).as_simple_string()?
+                    } else {
+                        RedisValue::Error("ERR DISCARD without MULTI".to_string()).encode()
+                    }
+                }
+            },
+            "INFO" => {
+                if args.len() > 2 {
+                    return Err(RedisError::WrongArity("INFO".to_string()))
+                } else {
+                    let mut response = String::new();
+                    let section = if args.len() == 2 { args[1].get_string()?.to_lowercase() } else { "all".to_string() };
+                    if section == "persistence" || section == "all" {
+                        self.hold_write_lock().await;
+                        let db = self.db.read().await;
+                        response.push_str("# Persistence\n");
+                        response.push_str(&format!("rdb_changes_since_last_save:{}\n", db.get_dirty()));
+                        response.push_str(&format!("rdb_bgsave_in_progress:{}\n", db.is_bgsave_in_progress() as u8));
+                        response.push_str(&format!("rdb_last_save_time:{}\n", db.get_lastsave().timestamp()));
+                        response.push_str(&format!("rdb_last_bgsave_status:{}\n", if db.is_last_bgsave_ok() { "ok" } else { "err" }));
+                        let aof = self.write_lock.as_deref().unwrap();
+                        response.push_str(&format!("aof_enabled:{}\n", aof.is_enabled() as u8));
+                        response.push_str(&format!("aof_rewrite_in_progress:{}\n", aof.is_rewrite_in_progress() as u8));
+                    }
+                    if section == "stats" || section == "all" {
+                        let db = self.db.read().await;
+                        response.push_str("# Stats\n");
+                        response.push_str(&format!("expired_keys:{}\n", db.get_expired_keys()));
+                        response.push_str(&format!("expired_subkeys:{}\n", db.get_expired_subkeys()));
+                        response.push_str(&format!("expired_stale_perc:{:.2}\n", db.get_expired_stale_perc()));
+                    }
+                    if section == "replication" || section == "all" {
+                        response.push_str("# Replication\n");
+                        response.push_str(&format!("role:{}\n", self.replica_info.read().await.get_role()));
+                        if self.replica_info.read().await.get_role() == "slave" {
+                            let address = self.replica_info.read().await.get_address();
+                            let (host, port) = address.rsplit_once(':').unwrap_or((&address, ""));
+                            response.push_str(&format!("master_host:{}\nmaster_port:{}\n", host, port));
+                        }
+                        response.push_str(&format!("master_replid:{}\n", self.replica_info.read().await.get_replid()));
+                        let replica_info = self.replica_info.read().await;
+                        response.push_str(&format!("master_replid2:{}\n", replica_info.get_replid2()));
+                        response.push_str(&format!("master_repl_offset:{}\n", replica_info.get_offset()));
+                        response.push_str(&format!("second_repl_offset:{}\n", replica_info.get_second_replid_offset().map_or(-1, |offset| offset as i64)));
+                        let replicadb = self.replicas.read().await;
+                        response.push_str(&format!("connected_slaves:{}\n", replicadb.senders.len()));
+                        response.push_str("repl_backlog_active:1\n");
+                        response.push_str(&format!("repl_backlog_size:{}\n", replicadb.backlog.get_size()));
+                        // Redis counts backlog positions from 1
+                        response.push_str(&format!("repl_backlog_first_byte_offset:{}\n", replicadb.backlog.get_start_offset() + 1));
+                        response.push_str(&format!("repl_backlog_histlen:{}\n", replicadb.backlog.len()));
+                    }
+                    RedisValue::Verbatim("txt".to_string(), response.into()).encode_with(self.protocol)
+                }
+            },
+            "REPLCONF" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("REPLCONF".to_string()))
+                } else if args[1].get_string()?.to_uppercase() == "ACK" {
+                    // acknowledgements from replicas don't get a reply
+                    let offset = args[2].get_string()?.parse::<u64>()?;
+                    self.replicas.write().await.ack(self.id, offset);
+                    vec![]
+                } else {
+                    RedisValue::String("OK".into()).as_simple_string()?
+                }
+            },
+            "WAIT" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("WAIT".to_string()))
+                } else {
+                    let numreplicas = args[1].get_string()?.parse::<usize>()?;
+                    let timeout = args[2].get_string()?.parse::<u64>()?;
+                    let offset = self.replica_info.read().await.get_offset();
+                    let acks = Arc::clone(&self.replicas.read().await.acks);
+                    let mut acked = self.replicas.read().await.count_acked(offset);
+                    if acked < numreplicas {
+                        let getack = vec![RedisValue::String("REPLCONF".into()), RedisValue::String("GETACK".into()), RedisValue::String("*".into())];
+                        self.propagate_to_replicas(&[getack]).await;
+                        let deadline = time::Instant::now() + Duration::from_millis(timeout);
+                        loop {
+                            // created before counting so an acknowledgement in between isn't missed
+                            let notified = acks.notified();
+                            acked = self.replicas.read().await.count_acked(offset);
+                            if acked >= numreplicas {
+                                break;
+                            }
+                            if timeout == 0 {
+                                notified.await;
+                            } else if time::timeout_at(deadline, notified).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    RedisValue::Int(acked as i64).encode()
+                }
+            },
+            "REPLICAOF" | "SLAVEOF" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity(command.to_string()))
+                } else {
+                    let host = args[1].get_string()?;
+                    let port = args[2].get_string()?;
+                    let role = self.replica_info.read().await.get_role();
+                    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                        if role == "slave" {
+                            replication::stop_replica_link(&self.replica_info).await;
+                            // the stream goes on under a new id, replicas that had the old one can still continue it
+                            self.replica_info.write().await.promote(&generate_random_alphanumeric(40));
+                            println!("MASTER MODE enabled");
+                        }
+                        RedisValue::String("OK".into()).as_simple_string()?
+                    } else if port.parse::<u16>().is_err() {
+                        RedisValue::Error("ERR Invalid master port".to_string()).encode()
+                    } else {
+                        let address = format!("{}:{}", host, port);
+                        if role == "slave" && self.replica_info.read().await.get_address() == address {
+                            RedisValue::String("OK Already connected to specified master".into()).as_simple_string()?
+                        } else {
+                            self.replica_info.write().await.set_master(&address);
+                            let (_, receiver) = unbounded_channel::<RedisValue>();
+                            let mut master_handler = ClientHandler::new(u32::MAX, Arc::clone(&self.db), Arc::clone(&self.ps_registry), receiver,
+                                Arc::clone(&self.replica_info), Arc::clone(&self.replicas), Arc::clone(&self.config), Arc::clone(&self.aof));
+                            master_handler.set_master_link();
+                            // with the current id and offset the new master may be able to continue our stream
+                            replication::start_replica_link(master_handler, Arc::clone(&self.replica_info), Arc::clone(&self.replicas),
+                                Arc::clone(&self.db), true).await;
+                            println!("Connecting to MASTER {}", address);
+                            RedisValue::String("OK".into()).as_simple_string()?
+                        }
+                    }
+                }
+            },
+            "HELLO" => {
+                let mut error = None;
+                let mut protocol = self.protocol;
+                let mut name = None;
+                if args.len() > 1 {
+                    protocol = match args[1].get_string()?.as_str() {
+                        "2" => Protocol::Resp2,
+                        "3" => Protocol::Resp3,
+                        _ => {
+                            error = Some("NOPROTO unsupported protocol version".to_string());
+                            protocol
+                        },
+                    };
+                }
+                let mut i = 2;
+                while error.is_none() && i < args.len() {
+                    let option = args[i].get_string()?.to_uppercase();
+                    if option == "AUTH" && i + 2 < args.len() {
+                        // there are no users or passwords, everyone is the default user
+                        if args[i+1].get_string()? != "default" {
+                            error = Some("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+                        }
+                        i += 3;
+                    } else if option == "SETNAME" && i + 1 < args.len() {
+                        let client_name = args[i+1].get_string()?;
+                        if client_name.chars().any(|c| !c.is_ascii_graphic()) {
+                            error = Some("ERR Client names cannot contain spaces, newlines or special characters.".to_string());
+                        }
+                        name = Some(client_name);
+                        i += 2;
+                    } else {
+                        error = Some(format!("ERR Syntax error in HELLO option '{}'", args[i].get_string()?));
+                    }
+                }
+                match error {
+                    Some(error) => RedisValue::Error(error).encode(),
+                    None => {
+                        self.protocol = protocol;
+                        if name.is_some() {
+                            self.name = name;
+                        }
+                        let role = if self.replica_info.read().await.get_role() == "slave" { "replica" } else { "master" };
+                        let response = vec![
+                            (RedisValue::String("server".into()), RedisValue::String("redis".into())),
+                            (RedisValue::String("version".into()), RedisValue::String(REDIS_VERSION.into())),
+                            (RedisValue::String("proto".into()), RedisValue::Int(if protocol == Protocol::Resp3 { 3 } else { 2 })),
+                            (RedisValue::String("id".into()), RedisValue::Int(self.id as i64)),
+                            (RedisValue::String("mode".into()), RedisValue::String("standalone".into())),
+                            (RedisValue::String("role".into()), RedisValue::String(role.into())),
+                            (RedisValue::String("modules".into()), RedisValue::Array(vec![])),
+                        ];
+                        RedisValue::Map(response).encode_with(self.protocol)
+                    },
+                }
+            },
+            "PSYNC" => {
+                if args.len() != 3 {
+                    return Err(RedisError::WrongArity("PSYNC".to_string()))
+                } else {
+                    let replid = args[1].get_string()?;
+                    // the replica asks for the stream from this offset on, counting from 1
+                    let psync_offset = args[2].get_string()?.parse::<i64>().unwrap_or(-1);
+                    // Snapshot the database (or take the part of the stream the replica is missing) and register
+                    // the replica while no write is running, so every write is either in what the replica gets
+                    // now or buffered in the channel until that is sent
+                    self.hold_write_lock().await;
+                    let mut replicadb = self.replicas.write().await;
+                    let replica_info = self.replica_info.read().await;
+                    let offset = replica_info.get_offset();
+                    let same_stream = replid == replica_info.get_replid()
+                        || (replid == replica_info.get_replid2() && replica_info.get_second_replid_offset().is_some_and(|limit| psync_offset as u64 <= limit));
+                    let missing = if same_stream && psync_offset > 0 { replicadb.backlog.get_from(psync_offset as u64 - 1) } else { None };
+                    let (sender, receiver) = unbounded_channel();
+                    self.instruction_receiver = Some(receiver);
+                    match missing {
+                        Some(missing) => {
+                            replicadb.add(self.id, sender, psync_offset as u64 - 1);
+                            let mut content = RedisValue::String(format!("CONTINUE {}", replica_info.get_replid()).into()).as_simple_string()?;
+                            content.extend(missing);
+                            content
+                        },
+                        None => {
+                            let snapshot = rdb::dump(&*self.db.read().await)?;
+                            replicadb.add(self.id, sender, offset);
+                            let mut content = RedisValue::String(format!("FULLRESYNC {} {}", replica_info.get_replid(), offset).into()).as_simple_string()?;
+                            content.extend(format!("${}\r\n", snapshot.len()).as_bytes());
+                            content.extend(snapshot);
+                            content
+                        },
+                    }
+                }
+            },
+            "SAVE" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("SAVE".to_string()))
+                } else {
+                    let path = self.config.read().await.get_rdb_path();
+                    match rdb::save(&self.db, &path).await {
+                        Ok(()) => RedisValue::String("OK".into()).as_simple_string()?,
+                        Err(e) => RedisValue::Error(format!("ERR {}", e)).encode(),
+                    }
+                }
+            },
+            "BGSAVE" => {
+                if args.len() > 2 {
+                    return Err(RedisError::WrongArity("BGSAVE".to_string()))
+                } else {
+                    let path = self.config.read().await.get_rdb_path();
+                    match rdb::bgsave(&self.db, path).await {
+                        Ok(()) => RedisValue::String("Background saving started".into()).as_simple_string()?,
+                        Err(e) => RedisValue::Error(format!("ERR {}", e)).encode(),
+                    }
+                }
+            },
+            "BGREWRITEAOF" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("BGREWRITEAOF".to_string()))
+                } else {
+                    let path = self.config.read().await.get_aof_path();
+                    self.hold_write_lock().await;
+                    match aof::bgrewrite(&self.aof, self.write_lock.as_deref_mut().unwrap(), &self.db, path).await {
+                        Ok(()) => RedisValue::String("Background append only file rewriting started".into()).as_simple_string()?,
+                        Err(e) => RedisValue::Error(format!("ERR {}", e)).encode(),
+                    }
+                }
+            },
+            "LASTSAVE" => {
+                if args.len() != 1 {
+                    return Err(RedisError::WrongArity("LASTSAVE".to_string()))
+                } else {
+                    RedisValue::Int(self.db.read().await.get_lastsave().timestamp()).encode()
+                }
+            },
+            "CONFIG" => {
+                if args.len() < 3 {
+                    return Err(RedisError::WrongArity("CONFIG".to_string()))
+                } else {
+                    let subcommand = args[1].get_string()?.to_uppercase();
+                    match subcommand.as_str() {
+                        "GET" => {
+                            let config = self.config.read().await;
+                            let mut response = vec![];
+                            for arg in args.iter().skip(2) {
+                                let name = arg.get_string()?.to_lowercase();
+                                if let Some(value) = config.get(&name) {
+                                    response.push((RedisValue::String(name.into()), RedisValue::String(value.into())));
+                                }
+                            }
+                            RedisValue::Map(response).encode_with(self.protocol)
+                        },
+                        "SET" => {
+                            if !args.len().is_multiple_of(2) {
+                                return Err(RedisError::WrongArity("CONFIG|SET".to_string()))
+                            } else {
+                                let mut error = None;
+                                let (appendonly, appendfsync, aof_path, backlog_size) = {
+                                    let mut config = self.config.write().await;
+                                    for i in (2..args.len()).step_by(2) {
+                                        let name = args[i].get_string()?.to_lowercase();
+                                        if let Err(e) = config.set(&name, &args[i+1].get_string()?) {
+                                            error = Some(format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", name, e));
+                                            break;
+                                        }
+                                    }
+                                    (config.is_appendonly(), config.get_appendfsync(), config.get_aof_path(), config.get_repl_backlog_size())
+                                };
+                                self.replicas.write().await.backlog.resize(backlog_size);
+                                self.hold_write_lock().await;
+                                let aof = self.write_lock.as_deref_mut().unwrap();
+                                aof.set_fsync(appendfsync);
+                                let aof_enabled = aof.is_enabled();
+                                if appendonly && !aof_enabled {
+                                    if let Err(e) = aof::enable(&self.aof, aof, &self.db, aof_path).await {
+                                        error = Some(format!("ERR {}", e));
+                                    }
+                                } else if !appendonly && aof_enabled {
+                                    aof.close().await?;
+                                }
+                                match error {
+                                    Some(error) => RedisValue::Error(error).encode(),
+                                    None => RedisValue::String("OK".into()).as_simple_string()?,
+                                }
+                            }
+                        },
+                        _ => RedisValue::Error(format!("ERR unknown subcommand '{}'. Try CONFIG HELP.", subcommand)).encode(),
+                    }
+                }
+            },
+            c => RedisValue::Error(format!("Err unknown command '{}'", c)).encode(),
+        };
+        Ok(response)
+    }
+
+    /// Stores `value` at `key` the way SET does, if `condition` allows it. Returns whether it was
+    /// stored and, with `get`, the previous value, which has to be a string.
+    async fn set_string(&mut self, key: Vec<u8>, value: Vec<u8>, condition: SetCondition, expire: SetExpire, get: bool) -> Result<(bool, Option<Vec<u8>>), RedisError> {
+        let mut db = self.db.write().await;
+        let previous = if get { db.get_as::<StringRecord>(&key)?.map(|string_record| string_record.get_value().to_vec()) } else { None };
+        let exists = db.get(&key).is_some();
+        let allowed = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfMissing => !exists,
+            SetCondition::IfExists => exists,
+        };
+        if !allowed {
+            self.propagation = Propagation::Prevented;
+            return Ok((false, previous))
+        }
+        let limit = match expire {
+            SetExpire::Clear => None,
+            SetExpire::Keep => db.get_expire(&key),
+            SetExpire::At(limit) => Some(limit),
+        };
+        db.insert(key.clone(), DbRecord::String(StringRecord::new(value.clone())));
+        // a relative expire would end later on replicas, so they get the absolute time
+        let mut effective = vec![RedisValue::String("SET".into()), RedisValue::String(key.clone()), RedisValue::String(value)];
+        if let Some(limit) = limit {
+            db.set_expire(&key, limit);
+            effective.push(RedisValue::String("PXAT".into()));
+            effective.push(RedisValue::String(limit.timestamp_millis().to_string().into()));
+        }
+        self.propagation = Propagation::Rewritten(effective);
+        Ok((true, previous))
+    }
+
+    async fn exec_queued(&mut self) -> Result<Vec<u8>> {
+        if self.multi_mode {
+            let mut outputs = vec![];
+            self.in_exec = true;
+            for queued_command in std::mem::take(&mut self.queued_commands) {
+                let command = queued_command[0].get_string()?.to_ascii_uppercase();
+                let value = self.execute_command(&command, queued_command).await;
+                outputs.push(value);
+            }
+            self.in_exec = false;
+            let outputs = outputs.into_iter().collect::<Result<Vec<_>>>()?;
+            let mut exec_output = format!("*{}\r\n", outputs.len()).as_bytes().to_vec();
+            for output in outputs {
+                exec_output.extend(output);
+            }
+            self.multi_mode = false;
+            Ok(exec_output)
+        } else {
+            Ok(RedisValue::Error("ERR EXEC without MULTI".to_string()).encode())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> ClientHandler {
+        let config = Config::new();
+        let (_, receiver) = unbounded_channel();
+        let replica_info = ReplicaInfo::new("master", &"0".repeat(40), "", "6379");
+        let replicas = ReplicaDb::new(config.get_repl_backlog_size());
+        let aof = Aof::new(config.get_appendfsync());
+        ClientHandler::new(1, Arc::new(RwLock::new(DB::new())), Arc::new(RwLock::new(Registry::new())), receiver,
+            Arc::new(RwLock::new(replica_info)), Arc::new(RwLock::new(replicas)), Arc::new(RwLock::new(config)), Arc::new(Mutex::new(aof)))
+    }
+
+    /// Runs a command the way it comes from a client, failing if it doesn't finish.
+    async fn run(handler: &mut ClientHandler, args: &[&str]) -> String {
+        let args = args.iter().map(|arg| RedisValue::String(arg.as_bytes().to_vec())).collect::<Vec<_>>();
+        let command = args[0].get_string().unwrap().to_ascii_uppercase();
+        let response = time::timeout(Duration::from_secs(5), handler.handle_commands(&command, args)).await
+            .expect("the command never finished").unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn transactions_use_the_write_lock_they_hold() {
+        let mut handler = handler();
+        run(&mut handler, &["MULTI"]).await;
+        run(&mut handler, &["SET", "k", "v"]).await;
+        run(&mut handler, &["INFO", "persistence"]).await;
+        run(&mut handler, &["CONFIG", "SET", "appendfsync", "always"]).await;
+        let response = run(&mut handler, &["EXEC"]).await;
+        assert!(response.starts_with("*3\r\n+OK\r\n"));
+        assert!(response.contains("aof_enabled:0"));
+        assert!(response.ends_with("+OK\r\n"));
+        // the lock was given back
+        assert_eq!(run(&mut handler, &["SET", "k", "w"]).await, "+OK\r\n");
+        assert_eq!(run(&mut handler, &["INFO", "persistence"]).await.matches("aof_enabled:0").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_refuse_bad_timeouts() {
+        let mut handler = handler();
+        for command in ["BZPOPMIN", "BZPOPMAX"] {
+            for timeout in ["inf", "-inf", "nan", "1e300", "x"] {
+                assert_eq!(run(&mut handler, &[command, "k", timeout]).await, "-ERR timeout is not a float or out of range\r\n");
+            }
+            assert_eq!(run(&mut handler, &[command, "k", "-1"]).await, "-ERR timeout is negative\r\n");
+        }
+        assert_eq!(run(&mut handler, &["BZMPOP", "inf", "1", "k", "MIN"]).await, "-ERR timeout is not a float or out of range\r\n");
+        assert_eq!(run(&mut handler, &["BZPOPMIN", "k", "0.01"]).await, "*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn blpop_refuses_bad_timeouts() {
+        let mut handler = handler();
+        for timeout in ["inf", "nan", "1e300"] {
+            assert_eq!(run(&mut handler, &["BLPOP", "l", timeout]).await, "-ERR timeout is not a float or out of range\r\n");
+        }
+        assert_eq!(run(&mut handler, &["BLPOP", "l", "-1"]).await, "-ERR timeout is negative\r\n");
+        assert_eq!(run(&mut handler, &["BLPOP", "l", "0.01"]).await, "*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn incr_refuses_to_overflow() {
+        let mut handler = handler();
+        run(&mut handler, &["SET", "n", &(i64::MAX - 1).to_string()]).await;
+        assert_eq!(run(&mut handler, &["INCR", "n"]).await, format!(":{}\r\n", i64::MAX));
+        assert_eq!(run(&mut handler, &["INCR", "n"]).await, "-ERR increment or decrement would overflow\r\n");
+        assert_eq!(run(&mut handler, &["GET", "n"]).await, format!("$19\r\n{}\r\n", i64::MAX));
+    }
+
+    #[tokio::test]
+    async fn keepttl_leaves_out_a_time_to_live_that_is_over() {
+        let mut handler = handler();
+        run(&mut handler, &["SET", "k", "v", "PX", "1"]).await;
+        time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(run(&mut handler, &["SET", "k", "w", "KEEPTTL"]).await, "+OK\r\n");
+        assert_eq!(run(&mut handler, &["GET", "k"]).await, "$1\r\nw\r\n");
+        assert_eq!(run(&mut handler, &["TTL", "k"]).await, ":-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn hrandfield_refuses_counts_out_of_range() {
+        let mut handler = handler();
+        run(&mut handler, &["HSET", "h", "f", "v"]).await;
+        let min = i64::MIN.to_string();
+        let half = (i64::MAX / 2 + 1).to_string();
+        assert_eq!(run(&mut handler, &["HRANDFIELD", "h", &min]).await, "-ERR value is out of range\r\n");
+        assert_eq!(run(&mut handler, &["HRANDFIELD", "h", &format!("-{}", half), "WITHVALUES"]).await, "-ERR value is out of range\r\n");
+        assert_eq!(run(&mut handler, &["HRANDFIELD", "h", &half, "WITHVALUES"]).await, "-ERR value is out of range\r\n");
+        assert_eq!(run(&mut handler, &["HRANDFIELD", "h", "-2"]).await, "*2\r\n$1\r\nf\r\n$1\r\nf\r\n");
+    }
+
+    #[tokio::test]
+    async fn srandmember_refuses_counts_out_of_range() {
+        let mut handler = handler();
+        run(&mut handler, &["SADD", "s", "m"]).await;
+        assert_eq!(run(&mut handler, &["SRANDMEMBER", "s", &i64::MIN.to_string()]).await, "-ERR value is out of range\r\n");
+        assert_eq!(run(&mut handler, &["SRANDMEMBER", "s", "-2"]).await, "*2\r\n$1\r\nm\r\n$1\r\nm\r\n");
+    }
+
+    #[tokio::test]
+    async fn hincrbyfloat_refuses_infinities_and_formats_like_redis() {
+        let mut handler = handler();
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "f", "inf"]).await, "-ERR value is not a valid float\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "f", "nan"]).await, "-ERR value is not a valid float\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "f", "1e300"]).await, "$6\r\n1e+300\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "g", "0.1"]).await, "$3\r\n0.1\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "g", "0.2"]).await, "$19\r\n0.30000000000000004\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "i", "3"]).await, "$1\r\n3\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "i", "-3"]).await, "$1\r\n0\r\n");
+        assert_eq!(run(&mut handler, &["HINCRBYFLOAT", "h", "j", "0.00001"]).await, "$5\r\n1e-05\r\n");
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_stop_waiting_when_the_time_runs_out() {
+        let mut handler = handler();
+        assert_eq!(run(&mut handler, &["BZPOPMIN", "a", "b", "0.01"]).await, "*-1\r\n");
+        time::sleep(Duration::from_millis(10)).await;
+        let db = handler.db.read().await;
+        assert_eq!((db.blocked_on_zset(b"a"), db.blocked_on_zset(b"b")), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn blpop_in_a_transaction_leaves_missing_lists_out() {
+        let mut handler = handler();
+        run(&mut handler, &["MULTI"]).await;
+        run(&mut handler, &["BLPOP", "l", "0"]).await;
+        assert_eq!(run(&mut handler, &["EXEC"]).await, "*1\r\n*-1\r\n");
+        assert_eq!(run(&mut handler, &["EXISTS", "l"]).await, ":0\r\n");
+        assert_eq!(handler.db.read().await.len(), 0);
     }
 }
@@ -0,0 +1,143 @@
+/// What the server needs to know about a command before running it.
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// Changes the dataset, so it's logged to the append only file and propagated to replicas.
+    pub write: bool,
+}
+
+const fn command(name: &'static str, write: bool) -> CommandInfo {
+    CommandInfo { name, write }
+}
+
+pub const COMMAND_TABLE: &[CommandInfo] = &[
+    command("PING", false),
+    command("ECHO", false),
+    command("SET", true),
+    command("GET", false),
+    command("SETNX", true),
+    command("SETEX", true),
+    command("PSETEX", true),
+    command("GETSET", true),
+    command("GETDEL", true),
+    command("GETEX", true),
+    command("DEL", true),
+    command("UNLINK", true),
+    command("EXISTS", false),
+    command("RENAME", true),
+    command("RENAMENX", true),
+    command("COPY", true),
+    command("TOUCH", false),
+    command("RANDOMKEY", false),
+    command("KEYS", false),
+    command("SCAN", false),
+    command("INCR", true),
+    command("TYPE", false),
+    command("EXPIRE", true),
+    command("PEXPIRE", true),
+    command("EXPIREAT", true),
+    command("PEXPIREAT", true),
+    command("TTL", false),
+    command("PTTL", false),
+    command("EXPIRETIME", false),
+    command("PEXPIRETIME", false),
+    command("PERSIST", true),
+    command("RPUSH", true),
+    command("LPUSH", true),
+    command("LRANGE", false),
+    command("LLEN", false),
+    command("LPOP", true),
+    command("BLPOP", true),
+    command("XADD", true),
+    command("XRANGE", false),
+    command("XREAD", false),
+    command("HSET", true),
+    command("HSETNX", true),
+    command("HGET", false),
+    command("HMGET", false),
+    command("HGETALL", false),
+    command("HKEYS", false),
+    command("HVALS", false),
+    command("HDEL", true),
+    command("HEXISTS", false),
+    command("HSTRLEN", false),
+    command("HLEN", false),
+    command("HINCRBY", true),
+    command("HINCRBYFLOAT", true),
+    command("HRANDFIELD", false),
+    command("HSCAN", false),
+    command("HEXPIRE", true),
+    command("HPEXPIRE", true),
+    command("HEXPIREAT", true),
+    command("HPEXPIREAT", true),
+    command("HTTL", false),
+    command("HPTTL", false),
+    command("HPERSIST", true),
+    command("HGETEX", true),
+    command("HSETEX", true),
+    command("SADD", true),
+    command("SREM", true),
+    command("SMEMBERS", false),
+    command("SISMEMBER", false),
+    command("SMISMEMBER", false),
+    command("SCARD", false),
+    command("SPOP", true),
+    command("SRANDMEMBER", false),
+    command("SMOVE", true),
+    command("SINTER", false),
+    command("SUNION", false),
+    command("SDIFF", false),
+    command("SINTERSTORE", true),
+    command("SUNIONSTORE", true),
+    command("SDIFFSTORE", true),
+    command("SINTERCARD", false),
+    command("SSCAN", false),
+    command("ZADD", true),
+    command("ZINCRBY", true),
+    command("ZREM", true),
+    command("ZSCORE", false),
+    command("ZMSCORE", false),
+    command("ZCARD", false),
+    command("ZCOUNT", false),
+    command("ZRANK", false),
+    command("ZREVRANK", false),
+    command("ZRANGE", false),
+    command("ZRANGESTORE", true),
+    command("ZUNION", false),
+    command("ZINTER", false),
+    command("ZDIFF", false),
+    command("ZUNIONSTORE", true),
+    command("ZINTERSTORE", true),
+    command("ZDIFFSTORE", true),
+    command("ZPOPMIN", true),
+    command("ZPOPMAX", true),
+    command("ZMPOP", true),
+    command("BZPOPMIN", true),
+    command("BZPOPMAX", true),
+    command("BZMPOP", true),
+    command("SUBSCRIBE", false),
+    command("UNSUBSCRIBE", false),
+    command("PUBLISH", false),
+    command("MULTI", false),
+    command("EXEC", false),
+    command("DISCARD", false),
+    command("INFO", false),
+    command("HELLO", false),
+    command("CONFIG", false),
+    command("REPLCONF", false),
+    command("PSYNC", false),
+    command("REPLICAOF", false),
+    command("SLAVEOF", false),
+    command("WAIT", false),
+    command("SAVE", false),
+    command("BGSAVE", false),
+    command("BGREWRITEAOF", false),
+    command("LASTSAVE", false),
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandInfo> {
+    COMMAND_TABLE.iter().find(|command| command.name == name)
+}
+
+pub fn is_write(name: &str) -> bool {
+    lookup(name).is_some_and(|command| command.write)
+}
@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+use anyhow::{Result, anyhow};
+use tokio::sync::watch;
+
+use crate::modules::parser::DEFAULT_MAX_BULK_LEN;
+
+/// Default for the number of elements of an array from a client, what Redis allows clients
+/// that aren't authenticated.
+const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Names of the parameters that can be set from the command line (`--<name> <value>`)
+/// and read or changed at runtime with `CONFIG GET` / `CONFIG SET`.
+pub const PARAMETERS: [&str; 12] = ["dir", "dbfilename", "save", "appendonly", "appendfilename", "appendfsync", "aof-load-truncated", "repl-backlog-size", "replica-read-only", "proto-max-bulk-len", "proto-max-multibulk-len", "hz"];
+
+pub struct Config {
+    dir: String,
+    dbfilename: String,
+    /// Snapshot after `seconds` have elapsed with at least `changes` writes: `(seconds, changes)`.
+    save_points: Vec<(u64, u64)>,
+    appendonly: bool,
+    appendfilename: String,
+    appendfsync: AppendFsync,
+    aof_load_truncated: bool,
+    repl_backlog_size: usize,
+    replica_read_only: bool,
+    /// Largest bulk string accepted from clients.
+    proto_max_bulk_len: usize,
+    /// Most elements in an array accepted from clients.
+    proto_max_multibulk_len: usize,
+    /// Tells connections about new protocol limits as they're set.
+    limits: watch::Sender<ProtocolLimits>,
+    /// How many times per second background tasks like the active expire cycle run.
+    hz: u32,
+}
+
+/// What the parser accepts from clients, see `RedisParser::set_limits`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtocolLimits {
+    pub max_bulk_len: usize,
+    pub max_multibulk_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("argument must be 'yes' or 'no'")),
+    }
+}
+
+fn format_bool(value: bool) -> String {
+    if value { "yes".to_string() } else { "no".to_string() }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self { dir: ".".to_string(), dbfilename: "dump.rdb".to_string(), save_points: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false, appendfilename: "appendonly.aof".to_string(), appendfsync: AppendFsync::EverySec, aof_load_truncated: true, repl_backlog_size: 1024 * 1024, replica_read_only: true,
+            proto_max_bulk_len: DEFAULT_MAX_BULK_LEN, proto_max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN, hz: 10,
+            limits: watch::channel(ProtocolLimits { max_bulk_len: DEFAULT_MAX_BULK_LEN, max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN }).0 }
+    }
+
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut config = Self::new();
+        for name in PARAMETERS {
+            let flag = format!("--{}", name);
+            if let Some(value) = args.iter().skip_while(|a| **a != flag).nth(1) {
+                config.set(name, value)?;
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "dir" => Some(self.dir.clone()),
+            "dbfilename" => Some(self.dbfilename.clone()),
+            "save" => Some(self.save_points.iter().map(|(seconds, changes)| format!("{} {}", seconds, changes)).collect::<Vec<_>>().join(" ")),
+            "appendonly" => Some(format_bool(self.appendonly)),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appendfsync" => Some(match self.appendfsync {
+                AppendFsync::Always => "always".to_string(),
+                AppendFsync::EverySec => "everysec".to_string(),
+                AppendFsync::No => "no".to_string(),
+            }),
+            "aof-load-truncated" => Some(format_bool(self.aof_load_truncated)),
+            "repl-backlog-size" => Some(self.repl_backlog_size.to_string()),
+            "replica-read-only" => Some(format_bool(self.replica_read_only)),
+            "proto-max-bulk-len" => Some(self.proto_max_bulk_len.to_string()),
+            "proto-max-multibulk-len" => Some(self.proto_max_multibulk_len.to_string()),
+            "hz" => Some(self.hz.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err(anyhow!("dbfilename can't be a path, just a filename"))
+                }
+                self.dbfilename = value.to_string();
+            },
+            "save" => {
+                let numbers = value.split_whitespace().map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| anyhow!("Invalid save parameters"))?;
+                if !numbers.len().is_multiple_of(2) {
+                    return Err(anyhow!("Invalid save parameters"))
+                }
+                self.save_points = numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+            },
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "appendfilename" => {
+                if value.contains('/') {
+                    return Err(anyhow!("appendfilename can't be a path, just a filename"))
+                }
+                self.appendfilename = value.to_string();
+            },
+            "appendfsync" => {
+                self.appendfsync = match value.to_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => return Err(anyhow!("argument must be one of 'always', 'everysec' or 'no'")),
+                }
+            },
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "repl-backlog-size" => {
+                let size = value.parse::<usize>().map_err(|_| anyhow!("argument must be a number of bytes"))?;
+                if size == 0 {
+                    return Err(anyhow!("argument must be greater than 0"))
+                }
+                self.repl_backlog_size = size;
+            },
+            "replica-read-only" => self.replica_read_only = parse_bool(value)?,
+            "proto-max-bulk-len" => {
+                let len = value.parse::<usize>().map_err(|_| anyhow!("argument must be a number of bytes"))?;
+                if len < 1024 * 1024 {
+                    return Err(anyhow!("argument must be at least 1048576"))
+                }
+                self.proto_max_bulk_len = len;
+                self.limits.send_replace(self.get_protocol_limits());
+            },
+            "proto-max-multibulk-len" => {
+                let len = value.parse::<usize>().map_err(|_| anyhow!("argument must be a number of elements"))?;
+                if !(1024..=i32::MAX as usize).contains(&len) {
+                    return Err(anyhow!("argument must be between 1024 and 2147483647"))
+                }
+                self.proto_max_multibulk_len = len;
+                self.limits.send_replace(self.get_protocol_limits());
+            },
+            "hz" => {
+                let hz = value.parse::<u32>().map_err(|_| anyhow!("argument must be a number"))?;
+                // Redis clamps it instead of refusing
+                self.hz = hz.clamp(1, 500);
+            },
+            _ => return Err(anyhow!("Unknown option or number of arguments for CONFIG SET - '{}'", name)),
+        }
+        Ok(())
+    }
+
+    pub fn get_save_points(&self) -> Vec<(u64, u64)> {
+        self.save_points.clone()
+    }
+
+    pub fn get_rdb_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+
+    pub fn is_appendonly(&self) -> bool {
+        self.appendonly
+    }
+
+    pub fn get_appendfsync(&self) -> AppendFsync {
+        self.appendfsync
+    }
+
+    pub fn is_aof_load_truncated(&self) -> bool {
+        self.aof_load_truncated
+    }
+
+    pub fn get_aof_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.appendfilename)
+    }
+
+    pub fn get_repl_backlog_size(&self) -> usize {
+        self.repl_backlog_size
+    }
+
+    pub fn is_replica_read_only(&self) -> bool {
+        self.replica_read_only
+    }
+
+    pub fn get_protocol_limits(&self) -> ProtocolLimits {
+        ProtocolLimits { max_bulk_len: self.proto_max_bulk_len, max_multibulk_len: self.proto_max_multibulk_len }
+    }
+
+    /// The protocol limits, now and whenever they change.
+    pub fn subscribe_limits(&self) -> watch::Receiver<ProtocolLimits> {
+        self.limits.subscribe()
+    }
+
+    pub fn get_hz(&self) -> u32 {
+        self.hz
+    }
+}
@@ -1,45 +1,529 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{collections::{BTreeSet, HashMap, HashSet, VecDeque}, hash::{DefaultHasher, Hash, Hasher}, sync::{Arc, Mutex}};
 use chrono::{DateTime, Utc};
+use rand::Rng;
 use tokio::sync::mpsc::UnboundedSender;
-use crate::modules::values::RedisValue;
+use crate::modules::{error::RedisError, skiplist::{self, SkipList}, values::RedisValue};
 
-pub type DB = HashMap<String, DbRecord>;
+/// The keyspace, along with the bookkeeping needed to decide when to snapshot it.
+/// Every mutable access to a record counts as a change since the last save.
+///
+/// Keys with a time to live have it in `expires`. An expired key is gone for readers right
+/// away, and it's removed for good the next time a writer touches it. The same goes for the
+/// fields of hashes, whose keys are in `field_expires` along with when their next field expires.
+pub struct DB {
+    records: HashMap<Vec<u8>, DbRecord>,
+    /// Keys in an order that lets them be picked at random and walked with SCAN.
+    order: ScanOrder,
+    expires: Expires,
+    field_expires: Expires,
+    dirty: u64,
+    /// Keys removed because their time to live was over.
+    expired_keys: u64,
+    /// Hash fields removed because their time to live was over.
+    expired_subkeys: u64,
+    /// Clients blocked on sorted sets, by key. They are kept out of `records`, so the key
+    /// doesn't exist for anyone else until members are added.
+    zset_waiters: HashMap<Vec<u8>, VecDeque<ZSetWaiter>>,
+    /// Estimate of how many keys with a time to live are expired but still there, in percent.
+    expired_stale_perc: f64,
+    lastsave: DateTime<Utc>,
+    bgsave_in_progress: bool,
+    last_bgsave_ok: bool,
+    last_bgsave_try: Option<DateTime<Utc>>,
+}
+
+impl DB {
+    pub fn new() -> Self {
+        Self { records: HashMap::new(), order: ScanOrder::new(), expires: Expires::new(), field_expires: Expires::new(), dirty: 0, expired_keys: 0, expired_subkeys: 0, zset_waiters: HashMap::new(), expired_stale_perc: 0.0, lastsave: Utc::now(), bgsave_in_progress: false, last_bgsave_ok: true, last_bgsave_try: None }
+    }
+    /// The record at `key`, if it's a key: expired records and the empty ones left behind by
+    /// blocked clients are not.
+    pub fn get(&self, key: &[u8]) -> Option<&DbRecord> {
+        if self.is_expired(key) {
+            return None
+        }
+        self.records.get(key).filter(|record| !record.is_empty())
+    }
+    /// Like `get`, but includes the empty records blocked clients wait on.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut DbRecord> {
+        self.remove_if_expired(key);
+        let record = self.records.get_mut(key);
+        if record.is_some() {
+            self.dirty += 1;
+        }
+        record
+    }
+    /// The record at `key` as the type a command works on: `None` if there is no such key,
+    /// or a WRONGTYPE error if it holds something else.
+    pub fn get_as<T: Record>(&self, key: &[u8]) -> Result<Option<&T>, RedisError> {
+        self.get(key).map(DbRecord::as_type).transpose()
+    }
+    pub fn get_mut_as<T: Record>(&mut self, key: &[u8]) -> Result<Option<&mut T>, RedisError> {
+        if self.get(key).is_none() {
+            return Ok(None)
+        }
+        self.get_mut_with_waiters_as(key)
+    }
+    /// Like `get_mut_as`, but includes the empty records blocked clients wait on, for the
+    /// commands that serve them.
+    pub fn get_mut_with_waiters_as<T: Record>(&mut self, key: &[u8]) -> Result<Option<&mut T>, RedisError> {
+        self.get_mut(key).map(DbRecord::as_type_mut).transpose()
+    }
+    /// Like `get_mut_as`, but a missing key is created with `new` first.
+    pub fn get_or_insert_as<T: Record>(&mut self, key: &[u8], new: impl FnOnce() -> T) -> Result<&mut T, RedisError> {
+        if self.get(key).is_none() {
+            self.insert(key.to_vec(), new().into_record());
+        }
+        self.get_mut(key).unwrap().as_type_mut()
+    }
+    /// Stores `record` at `key`, replacing whatever was there along with its time to live.
+    pub fn insert(&mut self, key: Vec<u8>, record: DbRecord) -> Option<DbRecord> {
+        self.dirty += 1;
+        let previous_expire = self.expires.remove(&key);
+        self.order.insert(&key);
+        let previous = self.records.insert(key.clone(), record);
+        self.track_field_expires(&key);
+        // an expired record was already gone
+        if previous_expire.is_some_and(|limit| limit <= Utc::now()) { None } else { previous }
+    }
+    /// Removes `key` along with its time to live. The empty records blocked clients wait on
+    /// aren't keys, so they stay.
+    pub fn remove(&mut self, key: &[u8]) -> Option<DbRecord> {
+        self.remove_if_expired(key);
+        self.get(key)?;
+        self.dirty += 1;
+        self.remove_record(key)
+    }
+    fn remove_record(&mut self, key: &[u8]) -> Option<DbRecord> {
+        self.expires.remove(key);
+        self.field_expires.remove(key);
+        self.order.remove(key);
+        self.records.remove(key)
+    }
+    /// The keys among the next `count` or so from `cursor`, and the cursor to go on from. See `ScanOrder::scan`.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&Vec<u8>, &DbRecord)>) {
+        let (next_cursor, keys) = self.order.scan(cursor, count);
+        (next_cursor, keys.into_iter().filter_map(|key| Some((key, self.get(key)?))).collect())
+    }
+    /// A key picked at random, removing the expired ones found along the way.
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        let mut rng = rand::rng();
+        // how often to land on the empty records of blocked clients before giving up on luck
+        let mut tries = 100;
+        while let Some(key) = self.order.random(&mut rng) {
+            let key = key.clone();
+            if self.remove_if_expired(&key) {
+                continue;
+            }
+            if self.get(&key).is_some() {
+                return Some(key)
+            }
+            tries -= 1;
+            if tries == 0 {
+                return self.iter().next().map(|(key, _)| key.clone())
+            }
+        }
+        None
+    }
+    /// When `key` expires. `None` if it doesn't, or already did but hasn't been removed yet.
+    pub fn get_expire(&self, key: &[u8]) -> Option<DateTime<Utc>> {
+        self.expires.get(key).filter(|limit| *limit > Utc::now())
+    }
+    /// Makes `key` expire at `limit`. Does nothing if there is no such key.
+    pub fn set_expire(&mut self, key: &[u8], limit: DateTime<Utc>) {
+        if self.get(key).is_some() {
+            self.dirty += 1;
+            self.expires.insert(key.to_vec(), limit);
+        }
+    }
+    /// Makes `key` live forever again. Returns whether it had a time to live.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        self.remove_if_expired(key);
+        let had_expire = self.expires.remove(key).is_some();
+        if had_expire {
+            self.dirty += 1;
+        }
+        had_expire
+    }
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        self.expires.get(key).is_some_and(|limit| limit <= Utc::now())
+    }
+    /// Removes `key` if its time to live is over, or else the fields of its hash whose time to
+    /// live is over, along with the key when they were the last ones. Returns whether the key went.
+    pub fn remove_if_expired(&mut self, key: &[u8]) -> bool {
+        if !self.is_expired(key) {
+            return self.remove_expired_fields(key) > 0 && !self.records.contains_key(key)
+        }
+        self.remove_record(key);
+        self.dirty += 1;
+        self.expired_keys += 1;
+        true
+    }
+    /// Returns how many fields were removed.
+    fn remove_expired_fields(&mut self, key: &[u8]) -> usize {
+        if self.field_expires.get(key).is_none_or(|limit| limit > Utc::now()) {
+            return 0
+        }
+        let Some(DbRecord::Hash(hash_record)) = self.records.get_mut(key) else { return 0 };
+        let removed = hash_record.remove_expired();
+        if hash_record.is_empty() {
+            self.remove_record(key);
+        } else {
+            self.track_field_expires(key);
+        }
+        self.dirty += 1;
+        self.expired_subkeys += removed as u64;
+        removed
+    }
+    /// To be called after removing fields from the hash at `key` or changing when they expire:
+    /// removes the key if it has no fields left, and makes sure the rest go when they expire.
+    pub fn update_hash(&mut self, key: &[u8]) {
+        self.remove_if_expired(key);
+        if let Some(DbRecord::Hash(hash_record)) = self.records.get(key) && hash_record.is_empty() {
+            self.remove_record(key);
+        } else {
+            self.track_field_expires(key);
+        }
+    }
+    /// To be called after removing members from the sorted set at `key`: removes the key if it
+    /// has no members left.
+    pub fn update_zset(&mut self, key: &[u8]) {
+        if let Some(DbRecord::ZSet(zset_record)) = self.records.get(key) && zset_record.is_empty() {
+            self.remove_record(key);
+        }
+    }
+    /// Blocks a client on the sorted set at `key` until it has members.
+    pub fn block_on_zset(&mut self, key: &[u8], waiter: ZSetWaiter) {
+        let waiters = self.zset_waiters.entry(key.to_vec()).or_default();
+        // the ones served through another key or gone would otherwise pile up
+        waiters.retain(ZSetWaiter::is_waiting);
+        waiters.push_back(waiter);
+    }
+    /// Takes the clients that are no longer blocked, e.g. because their time ran out, off the
+    /// sorted sets at `keys`.
+    pub fn unblock_zsets(&mut self, keys: &[Vec<u8>]) {
+        for key in keys {
+            if let Some(waiters) = self.zset_waiters.get_mut(key) {
+                waiters.retain(ZSetWaiter::is_waiting);
+                if waiters.is_empty() {
+                    self.zset_waiters.remove(key);
+                }
+            }
+        }
+    }
+    #[cfg(test)]
+    pub fn blocked_on_zset(&self, key: &[u8]) -> usize {
+        self.zset_waiters.get(key).map_or(0, VecDeque::len)
+    }
+    /// Pops members for the clients blocked on the sorted set at `key`, in the order they
+    /// blocked, while there are members left. Returns the end each served client popped from
+    /// and how many members it got.
+    pub fn serve_zset_waiters(&mut self, key: &[u8]) -> Vec<(ZSetEnd, usize)> {
+        let mut served = vec![];
+        let Some(waiters) = self.zset_waiters.get_mut(key) else { return served };
+        let Some(DbRecord::ZSet(zset_record)) = self.records.get_mut(key) else { return served };
+        while !zset_record.is_empty() && let Some(waiter) = waiters.pop_front() {
+            // a client served through another key already has its members
+            let Some(sender) = waiter.sender.lock().unwrap().take() else { continue };
+            let popped = zset_record.pop(waiter.end, waiter.count);
+            let count = popped.len();
+            // a waiter that went away (e.g. timed out) doesn't take anything
+            if let Err(returned) = sender.send((key.to_vec(), popped)) {
+                for (member, score) in returned.0.1 {
+                    zset_record.insert(member, score);
+                }
+            } else {
+                served.push((waiter.end, count));
+            }
+        }
+        if waiters.is_empty() {
+            self.zset_waiters.remove(key);
+        }
+        self.update_zset(key);
+        served
+    }
+    fn track_field_expires(&mut self, key: &[u8]) {
+        let next_expire = match self.records.get(key) {
+            Some(DbRecord::Hash(hash_record)) => hash_record.next_expire(),
+            _ => None,
+        };
+        match next_expire {
+            Some(limit) => self.field_expires.insert(key.to_vec(), limit),
+            None => {
+                self.field_expires.remove(key);
+            },
+        }
+    }
+    /// Looks at up to `count` random keys with a time to live and removes the expired ones.
+    /// Returns how many keys were looked at and how many of them were removed.
+    pub fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        let mut rng = rand::rng();
+        let sampled = count.min(self.expires.len());
+        let mut expired = 0;
+        for _ in 0..sampled {
+            let Some(key) = self.expires.random_key(&mut rng) else { break };
+            let key = key.to_vec();
+            if self.remove_if_expired(&key) {
+                expired += 1;
+            }
+        }
+        (sampled, expired)
+    }
+    /// Looks at up to `count` random hashes with fields that have a time to live and removes
+    /// their expired fields. Returns how many hashes were looked at and how many had some.
+    pub fn expire_fields_sample(&mut self, count: usize) -> (usize, usize) {
+        let mut rng = rand::rng();
+        let sampled = count.min(self.field_expires.len());
+        let mut expired = 0;
+        for _ in 0..sampled {
+            let Some(key) = self.field_expires.random_key(&mut rng) else { break };
+            let key = key.to_vec();
+            if self.remove_expired_fields(&key) > 0 {
+                expired += 1;
+            }
+        }
+        (sampled, expired)
+    }
+    pub fn get_expired_keys(&self) -> u64 {
+        self.expired_keys
+    }
+    pub fn get_expired_subkeys(&self) -> u64 {
+        self.expired_subkeys
+    }
+    pub fn get_expired_stale_perc(&self) -> f64 {
+        self.expired_stale_perc
+    }
+    /// Folds the share of expired keys found by the last expire cycle into the running estimate.
+    pub fn update_expired_stale_perc(&mut self, current: f64) {
+        self.expired_stale_perc = current * 0.05 + self.expired_stale_perc * 0.95;
+    }
+    /// Number of keys, counting expired ones that weren't removed yet.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn reserve(&mut self, additional: usize) {
+        self.records.reserve(additional);
+    }
+    /// The keys that haven't expired, with their records.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &DbRecord)> {
+        self.records.iter().filter(|(key, record)| !self.is_expired(key) && !record.is_empty())
+    }
+    pub fn get_dirty(&self) -> u64 {
+        self.dirty
+    }
+    pub fn get_lastsave(&self) -> DateTime<Utc> {
+        self.lastsave
+    }
+    /// Records a successful save of a snapshot taken when `dirty_at_snapshot` changes were pending.
+    pub fn mark_saved(&mut self, dirty_at_snapshot: u64) {
+        self.dirty = self.dirty.saturating_sub(dirty_at_snapshot);
+        self.lastsave = Utc::now();
+    }
+    pub fn is_bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress
+    }
+    pub fn start_bgsave(&mut self) {
+        self.bgsave_in_progress = true;
+        self.last_bgsave_try = Some(Utc::now());
+    }
+    pub fn finish_bgsave(&mut self, ok: bool) {
+        self.bgsave_in_progress = false;
+        self.last_bgsave_ok = ok;
+    }
+    pub fn is_last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok
+    }
+    pub fn get_last_bgsave_try(&self) -> Option<DateTime<Utc>> {
+        self.last_bgsave_try
+    }
+}
+
+/// Members `ScanOrder::random` picks among.
+const RANDOM_SAMPLE: usize = 20;
+
+fn member_hash(member: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    member.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The members of a collection (keys, hash fields, ...) sorted by their hash, an order that
+/// doesn't change as other members come and go. That lets SCAN use the hash to go on from as
+/// its cursor, and lets members be picked at random without going through all of them.
+#[derive(Clone)]
+pub struct ScanOrder {
+    by_hash: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl ScanOrder {
+    pub fn new() -> Self {
+        Self { by_hash: BTreeSet::new() }
+    }
+    pub fn insert(&mut self, member: &[u8]) {
+        self.by_hash.insert((member_hash(member), member.to_vec()));
+    }
+    pub fn remove(&mut self, member: &[u8]) {
+        self.by_hash.remove(&(member_hash(member), member.to_vec()));
+    }
+    /// Walks the members from the hash `cursor` on. Returns the next `count` or so, and the
+    /// cursor to continue from, which is 0 at the end. Members with the same hash always go
+    /// together, so the ones there during the whole walk are all seen exactly once.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&Vec<u8>>) {
+        let mut members = vec![];
+        let mut last_hash = None;
+        for (seen, (hash, member)) in self.by_hash.range((cursor, vec![])..).enumerate() {
+            if seen >= count && last_hash != Some(*hash) {
+                return (*hash, members)
+            }
+            last_hash = Some(*hash);
+            members.push(member);
+        }
+        (0, members)
+    }
+    /// A member picked at random. Members after a wider gap between hashes would come up more
+    /// often, so like Redis it picks among a few members in a row, which evens that out.
+    pub fn random(&self, rng: &mut impl Rng) -> Option<&Vec<u8>> {
+        let start = (rng.random::<u64>(), vec![]);
+        let in_a_row = RANDOM_SAMPLE.min(self.by_hash.len());
+        let candidates = self.by_hash.range(start..).chain(self.by_hash.iter()).take(in_a_row).collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None
+        }
+        Some(&candidates[rng.random_range(0..candidates.len())].1)
+    }
+}
+
+/// Keys along with when they expire, indexed so random ones can be picked in constant time.
+struct Expires {
+    limits: HashMap<Vec<u8>, (DateTime<Utc>, usize)>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Expires {
+    fn new() -> Self {
+        Self { limits: HashMap::new(), keys: vec![] }
+    }
+    fn get(&self, key: &[u8]) -> Option<DateTime<Utc>> {
+        self.limits.get(key).map(|(limit, _)| *limit)
+    }
+    fn insert(&mut self, key: Vec<u8>, limit: DateTime<Utc>) {
+        match self.limits.get_mut(&key) {
+            Some((current, _)) => *current = limit,
+            None => {
+                self.limits.insert(key.clone(), (limit, self.keys.len()));
+                self.keys.push(key);
+            },
+        }
+    }
+    fn remove(&mut self, key: &[u8]) -> Option<DateTime<Utc>> {
+        let (limit, index) = self.limits.remove(key)?;
+        self.keys.swap_remove(index);
+        // the last key took the place of the removed one
+        if let Some(moved) = self.keys.get(index) {
+            self.limits.get_mut(moved).unwrap().1 = index;
+        }
+        Some(limit)
+    }
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+    fn random_key(&self, rng: &mut impl Rng) -> Option<&[u8]> {
+        if self.keys.is_empty() {
+            return None
+        }
+        Some(&self.keys[rng.random_range(0..self.keys.len())])
+    }
+}
 
 pub enum DbRecord {
     String(StringRecord),
     List(ListRecord),
     Stream(StreamRecord),
+    Hash(HashRecord),
+    Set(SetRecord),
+    ZSet(ZSetRecord),
 }
 
-impl DbRecord {
-    pub fn get_string(&self) -> Option<&StringRecord> {
-        match self {
-            Self::String(string_record) => Some(string_record),
-            _ => None
+/// Implemented by the record of each data type, so commands can ask for the type they work on.
+pub trait Record {
+    fn from_record(record: &DbRecord) -> Option<&Self>;
+    fn from_record_mut(record: &mut DbRecord) -> Option<&mut Self>;
+    fn into_record(self) -> DbRecord;
+}
+
+macro_rules! impl_record {
+    ($variant:ident, $record:ty) => {
+        impl Record for $record {
+            fn from_record(record: &DbRecord) -> Option<&Self> {
+                match record {
+                    DbRecord::$variant(record) => Some(record),
+                    _ => None,
+                }
+            }
+            fn from_record_mut(record: &mut DbRecord) -> Option<&mut Self> {
+                match record {
+                    DbRecord::$variant(record) => Some(record),
+                    _ => None,
+                }
+            }
+            fn into_record(self) -> DbRecord {
+                DbRecord::$variant(self)
+            }
         }
+    };
+}
+
+impl_record!(String, StringRecord);
+impl_record!(List, ListRecord);
+impl_record!(Stream, StreamRecord);
+impl_record!(Hash, HashRecord);
+impl_record!(Set, SetRecord);
+impl_record!(ZSet, ZSetRecord);
+
+impl DbRecord {
+    /// The record as the type a command works on, or a WRONGTYPE error if it's something else.
+    pub fn as_type<T: Record>(&self) -> Result<&T, RedisError> {
+        T::from_record(self).ok_or(RedisError::WrongType)
     }
-    pub fn get_list(&self) -> Option<&ListRecord> {
-        match self {
-            Self::List(list_record) => Some(list_record),
-            _ => None,
-        }
+    pub fn as_type_mut<T: Record>(&mut self) -> Result<&mut T, RedisError> {
+        T::from_record_mut(self).ok_or(RedisError::WrongType)
     }
-    pub fn get_mut_list(&mut self) -> Option<&mut ListRecord> {
+    /// Blocking pops and reads leave empty lists and streams behind, and hashes are left empty
+    /// when all their fields expire. None of them are real keys.
+    pub fn is_empty(&self) -> bool {
         match self {
-            Self::List(list_record) => Some(list_record),
-            _ => None,
+            Self::List(list_record) => list_record.is_empty(),
+            Self::Stream(stream_record) => stream_record.len() == 0,
+            Self::Hash(hash_record) => hash_record.is_empty(),
+            Self::String(_) | Self::Set(_) | Self::ZSet(_) => false,
         }
     }
-    pub fn get_mut_stream(&mut self) -> Option<&mut StreamRecord> {
+    /// A copy of the data, without the clients waiting on it.
+    pub fn duplicate(&self) -> DbRecord {
         match self {
-            Self::Stream(stream_record) => Some(stream_record),
-            _ => None,
+            Self::String(string_record) => Self::String(string_record.clone()),
+            Self::List(list_record) => Self::List(ListRecord::from_list(list_record.get_list())),
+            Self::Stream(stream_record) => {
+                let mut copy = StreamRecord::new();
+                for entry in stream_record {
+                    copy.push(entry.clone());
+                }
+                Self::Stream(copy)
+            },
+            Self::Hash(hash_record) => Self::Hash(hash_record.clone()),
+            Self::Set(set_record) => Self::Set(set_record.clone()),
+            Self::ZSet(zset_record) => Self::ZSet(zset_record.clone()),
         }
     }
-    pub fn get_stream(&self) -> Option<&StreamRecord> {
+    /// Roughly how much work freeing the record takes, e.g. to do it on another thread when it's a lot.
+    pub fn free_effort(&self) -> usize {
         match self {
-            Self::Stream(stream_record) => Some(stream_record),
-            _ => None,
+            Self::String(_) => 1,
+            Self::List(list_record) => list_record.len(),
+            Self::Stream(stream_record) => stream_record.len(),
+            Self::Hash(hash_record) => hash_record.len(),
+            Self::Set(set_record) => set_record.len(),
+            Self::ZSet(zset_record) => zset_record.len(),
         }
     }
     pub fn get_type(&self) -> String{
@@ -47,40 +531,28 @@ impl DbRecord {
             Self::List(_) => "list".to_string(),
             Self::String(_) => "string".to_string(),
             Self::Stream(_) => "stream".to_string(),
+            Self::Hash(_) => "hash".to_string(),
+            Self::Set(_) => "set".to_string(),
+            Self::ZSet(_) => "zset".to_string(),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct StringRecord {
-    value: RedisValue,
-    time_limit: Option<DateTime<Utc>>,
+    value: Vec<u8>,
 }
 
 impl StringRecord {
-    pub fn new(value: RedisValue) -> Self {
-        Self { value, time_limit: None }
-    }
-
-    pub fn new_with_limit(value: RedisValue, limit: DateTime<Utc>) -> Self {
-        Self { value, time_limit: Some(limit) }
+    pub fn new(value: Vec<u8>) -> Self {
+        Self { value }
     }
 
-    pub fn is_valid(&self) -> bool {
-        if let Some(limit) = self.time_limit {
-            let now = Utc::now();
-            if now >= limit {
-                return false
-            }
-        }
-        true
-    }
-
-    pub fn set_value(&mut self, value: RedisValue) {
+    pub fn set_value(&mut self, value: Vec<u8>) {
         self.value = value;
     } 
 
-    pub fn get_value(&self) -> &RedisValue {
+    pub fn get_value(&self) -> &[u8] {
         &self.value
     }
 }
@@ -112,6 +584,9 @@ impl StreamRecord {
     pub fn subscribe_waiter(&mut self, waiter: UnboundedSender<StreamEntry>) {
         self.waiters.push_back(waiter);
     }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
     pub fn peek_last(&self) -> StreamEntry {
         match self.entries.last() {
             None => StreamEntry::new("0-0", None),
@@ -132,16 +607,12 @@ impl<'a> IntoIterator for &'a StreamRecord {
 #[derive(Debug, Clone)]
 pub struct StreamEntry {
     id: String,
-    kv: HashMap<String, String>,
+    kv: HashMap<Vec<u8>, Vec<u8>>,
 }
 
 impl StreamEntry {
-    pub fn new(id: &str, values: Option<HashMap<String, String>>) -> Self {
-        let stream = match values {
-            Some(val) => val,
-            None => HashMap::new(),
-        };
-        Self { id: id.to_string(), kv: stream }
+    pub fn new(id: &str, values: Option<HashMap<Vec<u8>, Vec<u8>>>) -> Self {
+        Self { id: id.to_string(), kv: values.unwrap_or_default() }
     }
     pub fn get_id(&self) -> &str {
         &self.id
@@ -149,79 +620,491 @@ impl StreamEntry {
 }
 
 impl<'a> IntoIterator for &'a StreamEntry {
-    type Item = (&'a String, &'a String);
-    type IntoIter = std::collections::hash_map::Iter<'a, String, String>;
+    type Item = (&'a Vec<u8>, &'a Vec<u8>);
+    type IntoIter = std::collections::hash_map::Iter<'a, Vec<u8>, Vec<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.kv.iter()
     }
 }
 
+/// A field of a hash along with its value.
+pub type HashField<'a> = (&'a Vec<u8>, &'a Vec<u8>);
+
+/// A hash, whose fields may each have a time to live. Like expired keys, expired fields are
+/// gone for readers right away, and removed for good by the keyspace, see `DB::remove_if_expired`.
+#[derive(Clone)]
+pub struct HashRecord {
+    fields: HashMap<Vec<u8>, Vec<u8>>,
+    order: ScanOrder,
+    /// When the fields with a time to live expire.
+    expires: HashMap<Vec<u8>, DateTime<Utc>>,
+    /// The same, soonest first.
+    by_limit: BTreeSet<(DateTime<Utc>, Vec<u8>)>,
+}
+
+impl HashRecord {
+    pub fn new() -> Self {
+        Self { fields: HashMap::new(), order: ScanOrder::new(), expires: HashMap::new(), by_limit: BTreeSet::new() }
+    }
+    pub fn get(&self, field: &[u8]) -> Option<&Vec<u8>> {
+        if self.is_expired(field) {
+            return None
+        }
+        self.fields.get(field)
+    }
+    /// Sets `field` to `value`, clearing its time to live. Returns whether the field is new.
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        let new = self.get(&field).is_none();
+        self.persist(&field);
+        self.order.insert(&field);
+        self.fields.insert(field, value);
+        new
+    }
+    /// Like `insert`, but a field that is already there keeps its time to live.
+    pub fn insert_keeping_ttl(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.get(&field).is_none() {
+            return self.insert(field, value)
+        }
+        self.fields.insert(field, value);
+        false
+    }
+    /// Returns whether the field was there.
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        let existed = self.get(field).is_some();
+        self.persist(field);
+        self.order.remove(field);
+        self.fields.remove(field);
+        existed
+    }
+    /// Number of fields, leaving out the expired ones.
+    pub fn len(&self) -> usize {
+        let now = Utc::now();
+        self.fields.len() - self.by_limit.iter().take_while(|(limit, _)| *limit <= now).count()
+    }
+    /// Whether there are no fields left, e.g. because all of them expired.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The fields that haven't expired, with their values.
+    pub fn iter(&self) -> impl Iterator<Item = HashField<'_>> {
+        self.fields.iter().filter(|(field, _)| !self.is_expired(field))
+    }
+    /// Fields and values from `cursor` on, see `ScanOrder::scan`.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<HashField<'_>>) {
+        let (next_cursor, fields) = self.order.scan(cursor, count);
+        (next_cursor, fields.into_iter().filter(|field| !self.is_expired(field)).map(|field| (field, &self.fields[field])).collect())
+    }
+    pub fn random_field(&self, rng: &mut impl Rng) -> Option<HashField<'_>> {
+        // expired fields are skipped, until it's quicker to look through all of them
+        for _ in 0..100 {
+            let field = self.order.random(rng)?;
+            if !self.is_expired(field) {
+                return Some((field, &self.fields[field]))
+            }
+        }
+        self.iter().next()
+    }
+    pub fn get_expire(&self, field: &[u8]) -> Option<DateTime<Utc>> {
+        self.expires.get(field).copied()
+    }
+    /// Makes `field` expire at `limit`. Returns false if there is no such field.
+    pub fn set_expire(&mut self, field: &[u8], limit: DateTime<Utc>) -> bool {
+        if self.get(field).is_none() {
+            return false
+        }
+        self.persist(field);
+        self.expires.insert(field.to_vec(), limit);
+        self.by_limit.insert((limit, field.to_vec()));
+        true
+    }
+    /// Makes `field` live forever again. Returns whether it had a time to live.
+    pub fn persist(&mut self, field: &[u8]) -> bool {
+        match self.expires.remove(field) {
+            Some(limit) => self.by_limit.remove(&(limit, field.to_vec())),
+            None => false,
+        }
+    }
+    pub fn is_expired(&self, field: &[u8]) -> bool {
+        self.expires.get(field).is_some_and(|limit| *limit <= Utc::now())
+    }
+    /// When the next field expires.
+    pub fn next_expire(&self) -> Option<DateTime<Utc>> {
+        self.by_limit.first().map(|(limit, _)| *limit)
+    }
+    /// Removes the fields whose time to live is over. Returns how many there were.
+    fn remove_expired(&mut self) -> usize {
+        let now = Utc::now();
+        let mut removed = 0;
+        while let Some((limit, _)) = self.by_limit.first() && *limit <= now {
+            let (_, field) = self.by_limit.pop_first().unwrap();
+            self.expires.remove(&field);
+            self.order.remove(&field);
+            self.fields.remove(&field);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+/// Sets of integers with more members than this are kept as a hash table instead.
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+
+/// Sorted integers, each stored in the narrowest width that fits all of them: 2, 4 or 8 bytes,
+/// little endian. The same layout Redis uses for small sets of integers.
+#[derive(Clone)]
+pub struct IntSet {
+    width: usize,
+    contents: Vec<u8>,
+}
+
+impl IntSet {
+    fn new() -> Self {
+        Self { width: 2, contents: vec![] }
+    }
+    fn width_for(value: i64) -> usize {
+        if i16::try_from(value).is_ok() {
+            2
+        } else if i32::try_from(value).is_ok() {
+            4
+        } else {
+            8
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.contents.len() / self.width
+    }
+    fn get(&self, index: usize) -> i64 {
+        let raw = &self.contents[index * self.width..(index + 1) * self.width];
+        match self.width {
+            2 => i16::from_le_bytes(raw.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(raw.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(raw.try_into().unwrap()),
+        }
+    }
+    fn encode(&self, value: i64) -> Vec<u8> {
+        match self.width {
+            2 => (value as i16).to_le_bytes().to_vec(),
+            4 => (value as i32).to_le_bytes().to_vec(),
+            _ => value.to_le_bytes().to_vec(),
+        }
+    }
+    /// Where `value` is, or where it would go.
+    fn search(&self, value: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.get(middle).cmp(&value) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(middle),
+            }
+        }
+        Err(low)
+    }
+    fn contains(&self, value: i64) -> bool {
+        self.search(value).is_ok()
+    }
+    /// Returns whether the value is new.
+    fn insert(&mut self, value: i64) -> bool {
+        let width = Self::width_for(value);
+        if width > self.width {
+            // every value is stored wider from now on
+            let values = self.iter().collect::<Vec<_>>();
+            self.width = width;
+            self.contents = values.into_iter().flat_map(|value| self.encode(value)).collect();
+        }
+        match self.search(value) {
+            Ok(_) => false,
+            Err(index) => {
+                let at = index * self.width;
+                self.contents.splice(at..at, self.encode(value));
+                true
+            },
+        }
+    }
+    /// Returns whether the value was there.
+    fn remove(&mut self, value: i64) -> bool {
+        match self.search(value) {
+            Ok(index) => {
+                self.contents.drain(index * self.width..(index + 1) * self.width);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+    /// The set as stored in RDB files: the width and the number of values as 32 bit little
+    /// endian integers, then the values.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.width as u32).to_le_bytes().to_vec();
+        bytes.extend((self.len() as u32).to_le_bytes());
+        bytes.extend(&self.contents);
+        bytes
+    }
+}
+
+/// The member as an integer, if it's written the one way Redis writes that integer, so it
+/// comes out of an `IntSet` the same as it went in.
+fn as_intset_member(member: &[u8]) -> Option<i64> {
+    let value = str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    (value.to_string().as_bytes() == member).then_some(value)
+}
+
+/// A set, kept as an `IntSet` while it holds a few integers and as a hash table from the
+/// first member that doesn't fit on, for good.
+#[derive(Clone)]
+pub struct SetRecord {
+    encoding: SetEncoding,
+}
+
+#[derive(Clone)]
+enum SetEncoding {
+    IntSet(IntSet),
+    HashTable {
+        members: HashSet<Vec<u8>>,
+        order: ScanOrder,
+    },
+}
+
+impl SetRecord {
+    pub fn new() -> Self {
+        Self { encoding: SetEncoding::IntSet(IntSet::new()) }
+    }
+    /// Returns whether the member is new.
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        if let SetEncoding::IntSet(intset) = &mut self.encoding {
+            match as_intset_member(&member) {
+                Some(value) if intset.contains(value) => return false,
+                Some(value) if intset.len() < SET_MAX_INTSET_ENTRIES => return intset.insert(value),
+                _ => self.convert_to_hash_table(),
+            }
+        }
+        match &mut self.encoding {
+            SetEncoding::HashTable { members, order } => {
+                order.insert(&member);
+                members.insert(member)
+            },
+            SetEncoding::IntSet(_) => unreachable!(),
+        }
+    }
+    fn convert_to_hash_table(&mut self) {
+        let (mut members, mut order) = (HashSet::new(), ScanOrder::new());
+        for member in self.members() {
+            order.insert(&member);
+            members.insert(member);
+        }
+        self.encoding = SetEncoding::HashTable { members, order };
+    }
+    /// Returns whether the member was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match &mut self.encoding {
+            SetEncoding::IntSet(intset) => as_intset_member(member).is_some_and(|value| intset.remove(value)),
+            SetEncoding::HashTable { members, order } => {
+                order.remove(member);
+                members.remove(member)
+            },
+        }
+    }
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match &self.encoding {
+            SetEncoding::IntSet(intset) => as_intset_member(member).is_some_and(|value| intset.contains(value)),
+            SetEncoding::HashTable { members, .. } => members.contains(member),
+        }
+    }
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            SetEncoding::IntSet(intset) => intset.len(),
+            SetEncoding::HashTable { members, .. } => members.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        match &self.encoding {
+            SetEncoding::IntSet(intset) => intset.iter().map(|value| value.to_string().into_bytes()).collect(),
+            SetEncoding::HashTable { members, .. } => members.iter().cloned().collect(),
+        }
+    }
+    /// The integers the set holds, while it's still kept as an `IntSet`.
+    pub fn as_intset(&self) -> Option<&IntSet> {
+        match &self.encoding {
+            SetEncoding::IntSet(intset) => Some(intset),
+            SetEncoding::HashTable { .. } => None,
+        }
+    }
+    /// Members from `cursor` on, see `ScanOrder::scan`. An `IntSet` is small enough to go all at once.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Vec<u8>>) {
+        match &self.encoding {
+            SetEncoding::IntSet(_) => (0, self.members()),
+            SetEncoding::HashTable { order, .. } => {
+                let (next_cursor, members) = order.scan(cursor, count);
+                (next_cursor, members.into_iter().cloned().collect())
+            },
+        }
+    }
+    pub fn random_member(&self, rng: &mut impl Rng) -> Option<Vec<u8>> {
+        match &self.encoding {
+            SetEncoding::IntSet(intset) if intset.len() == 0 => None,
+            SetEncoding::IntSet(intset) => Some(intset.get(rng.random_range(0..intset.len())).to_string().into_bytes()),
+            SetEncoding::HashTable { order, .. } => order.random(rng).cloned(),
+        }
+    }
+}
+
+/// Which end of a sorted set to pop members from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ZSetEnd {
+    Min,
+    Max,
+}
+
+/// Members popped from a sorted set, with their scores and the key they came from.
+pub type ZSetPopped = (Vec<u8>, Vec<(Vec<u8>, f64)>);
+
+/// A client blocked until one of some sorted sets has members, by BZPOPMIN and the commands
+/// like it. The client waits on each of those keys, but only the first to have members
+/// serves it: that one takes the sender, so the others find nothing to send to.
+#[derive(Clone)]
+pub struct ZSetWaiter {
+    end: ZSetEnd,
+    count: usize,
+    sender: Arc<Mutex<Option<UnboundedSender<ZSetPopped>>>>,
+}
+
+impl ZSetWaiter {
+    pub fn new(end: ZSetEnd, count: usize, sender: UnboundedSender<ZSetPopped>) -> Self {
+        Self { end, count, sender: Arc::new(Mutex::new(Some(sender))) }
+    }
+    /// Whether the client is still blocked: not served through another key, nor gone.
+    fn is_waiting(&self) -> bool {
+        self.sender.lock().unwrap().as_ref().is_some_and(|sender| !sender.is_closed())
+    }
+}
+
+/// A sorted set: the score of each member, and the members in order in a `SkipList`.
+#[derive(Clone)]
+pub struct ZSetRecord {
+    scores: HashMap<Vec<u8>, f64>,
+    list: SkipList,
+}
+
+impl ZSetRecord {
+    pub fn new() -> Self {
+        Self { scores: HashMap::new(), list: SkipList::new() }
+    }
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+    /// Adds `member` with `score`, or moves it there. Returns whether the member is new.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.list.remove(previous, &member);
+        }
+        self.list.insert(score, member);
+        previous.is_none()
+    }
+    /// Returns whether the member was there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.list.remove(score, member),
+            None => false,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+    /// The 0 based position of `member`, lowest score first.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.list.count_while(|other_score, other| other_score < score || (other_score == score && other < member)))
+    }
+    /// How many members come before the first one `precedes` doesn't hold for, see `SkipList::count_while`.
+    pub fn count_while(&self, precedes: impl Fn(f64, &[u8]) -> bool) -> usize {
+        self.list.count_while(precedes)
+    }
+    /// Members and scores from the one at `rank` on, towards the highest or, with `reverse`, the lowest score.
+    pub fn iter_from(&self, rank: usize, reverse: bool) -> skiplist::Iter<'_> {
+        self.list.iter_from(rank, reverse)
+    }
+    /// Removes up to `count` members from `end`, and returns them with their scores, the one
+    /// at that end first.
+    pub fn pop(&mut self, end: ZSetEnd, count: usize) -> Vec<(Vec<u8>, f64)> {
+        let count = count.min(self.len());
+        let first = if end == ZSetEnd::Min { 0 } else { self.len().saturating_sub(1) };
+        let popped: Vec<_> = self.iter_from(first, end == ZSetEnd::Max).take(count).map(|(member, score)| (member.clone(), score)).collect();
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+}
+
 pub struct ListRecord {
-    list: VecDeque<String>,
-    waiters: VecDeque<UnboundedSender<String>>
+    list: VecDeque<Vec<u8>>,
+    waiters: VecDeque<UnboundedSender<Vec<u8>>>
 }
 
 impl ListRecord {
     pub fn new() -> Self {
         Self { list: VecDeque::new(), waiters: VecDeque::new() }
     }
-    pub fn from_list(list: VecDeque<String>) -> Self {
+    pub fn from_list(list: VecDeque<Vec<u8>>) -> Self {
         Self { list, waiters: VecDeque::new() }
     }
-    pub fn get_list(&self) -> VecDeque<String> {
+    pub fn get_list(&self) -> VecDeque<Vec<u8>> {
         self.list.clone()
     }
     pub fn len(&self) -> usize {
         self.list.len()
     }
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, Vec<u8>> {
+        self.list.iter()
+    }
     pub fn is_empty(&self) -> bool {
         self.list.is_empty()
     }
-    pub fn push_front(&mut self, value: String) {
-        if !self.waiters.is_empty() {
-            let waiter = self.waiters.pop_front().unwrap();
-            let mut result = waiter.send(value.clone());
-            while result.is_err() {
-                if self.waiters.is_empty() {
-                    self.list.push_front(value);
-                    return;
-                }
-                let waiter = self.waiters.pop_front().unwrap();
-                result = waiter.send(value.clone());
-            }
-        } else {
-            self.list.push_front(value);
-        }
+    pub fn push_front(&mut self, value: Vec<u8>) {
+        self.list.push_front(value);
     }
-    pub fn push_back(&mut self, value: String) {
-        if !self.waiters.is_empty() {
-            let waiter = self.waiters.pop_front().unwrap();
-            let mut result = waiter.send(value.clone());
-            while result.is_err() {
-                if self.waiters.is_empty() {
-                    self.list.push_back(value);
-                    return;
-                }
-                let waiter = self.waiters.pop_front().unwrap();
-                result = waiter.send(value.clone());
+    pub fn push_back(&mut self, value: Vec<u8>) {
+        self.list.push_back(value);
+    }
+    /// Hands elements from the front of the list to the clients blocked on it, in the
+    /// order they blocked. Returns how many were served.
+    pub fn serve_waiters(&mut self) -> usize {
+        let mut served = 0;
+        while !self.list.is_empty() && let Some(waiter) = self.waiters.pop_front() {
+            let value = self.list.pop_front().unwrap();
+            // a waiter that went away (e.g. timed out) doesn't take anything
+            if let Err(returned) = waiter.send(value) {
+                self.list.push_front(returned.0);
+            } else {
+                served += 1;
             }
-        } else {
-            self.list.push_back(value);
         }
+        served
     }
-    pub fn pop_front(&mut self) -> Option<String> {
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
         self.list.pop_front()
     }
-    pub fn subscribe_waiter(&mut self, waiter: UnboundedSender<String>) {
+    pub fn subscribe_waiter(&mut self, waiter: UnboundedSender<Vec<u8>>) {
         self.waiters.push_back(waiter);
     }
 }
 
 pub struct Registry {
-    pub channels: HashMap<String, HashSet<u32>>,
-    pub subscriptions: HashMap<u32, HashSet<String>>,
-    pub senders: HashMap<u32, UnboundedSender<Vec<u8>>>,
+    pub channels: HashMap<Vec<u8>, HashSet<u32>>,
+    pub subscriptions: HashMap<u32, HashSet<Vec<u8>>>,
+    /// Where to send the messages for each subscribed client.
+    pub senders: HashMap<u32, UnboundedSender<RedisValue>>,
 }
 
 impl Registry {
@@ -0,0 +1,50 @@
+use std::{num::{ParseFloatError, ParseIntError}, string::FromUtf8Error};
+use thiserror::Error;
+
+/// Why a command failed. Mistakes in the command itself are sent to the client as an
+/// error reply and the connection goes on; anything else ends the connection.
+#[derive(Debug, Error)]
+pub enum RedisError {
+    #[error("ERR {0}")]
+    Client(String),
+    #[error("Err wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    /// The connection or a file can't be used anymore.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl RedisError {
+    /// Whether the client is told about the error, as opposed to losing its connection.
+    pub fn is_client_error(&self) -> bool {
+        !matches!(self, Self::Io(_) | Self::Internal(_))
+    }
+}
+
+impl From<ParseIntError> for RedisError {
+    fn from(_: ParseIntError) -> Self {
+        Self::NotInteger
+    }
+}
+
+impl From<ParseFloatError> for RedisError {
+    fn from(_: ParseFloatError) -> Self {
+        Self::NotFloat
+    }
+}
+
+impl From<FromUtf8Error> for RedisError {
+    fn from(_: FromUtf8Error) -> Self {
+        Self::Client("invalid argument, expected UTF-8 text".to_string())
+    }
+}
@@ -0,0 +1,46 @@
+use std::{sync::Arc, time::Instant};
+use tokio::{sync::RwLock, time::{self, Duration}};
+
+use crate::modules::{config::Config, db::DB};
+
+/// Keys with a time to live looked at in each round of the cycle.
+const KEYS_PER_ROUND: usize = 20;
+/// Another round follows while more than this share of the sampled keys was expired, in percent.
+const ACCEPTABLE_STALE_PERC: usize = 10;
+/// Share of each tick the cycle may spend removing keys, in percent.
+const TIME_BUDGET_PERC: u32 = 25;
+
+/// Removes expired keys nobody reads, `hz` times per second. Each tick samples random keys
+/// with a time to live and removes the expired ones, repeating while many of them were
+/// expired and the time budget allows. Then it does the same for hashes with expiring fields.
+///
+/// Expire times are absolute, so replicas and the append only file reach the same result
+/// on their own and the removals aren't propagated.
+pub async fn run_active_expire(db: Arc<RwLock<DB>>, config: Arc<RwLock<Config>>) {
+    loop {
+        let hz = config.read().await.get_hz();
+        let tick = Duration::from_secs(1) / hz;
+        time::sleep(tick).await;
+        let budget = tick * TIME_BUDGET_PERC / 100;
+        let mut db = db.write().await;
+        let start = Instant::now();
+        let (mut total_sampled, mut total_expired) = (0, 0);
+        loop {
+            let (sampled, expired) = db.expire_sample(KEYS_PER_ROUND);
+            total_sampled += sampled;
+            total_expired += expired;
+            if sampled == 0 || expired * 100 <= sampled * ACCEPTABLE_STALE_PERC || start.elapsed() >= budget {
+                break;
+            }
+        }
+        if total_sampled > 0 {
+            db.update_expired_stale_perc(total_expired as f64 / total_sampled as f64 * 100.0);
+        }
+        while start.elapsed() < budget {
+            let (sampled, expired) = db.expire_fields_sample(KEYS_PER_ROUND);
+            if sampled == 0 || expired * 100 <= sampled * ACCEPTABLE_STALE_PERC {
+                break;
+            }
+        }
+    }
+}
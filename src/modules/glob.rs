@@ -0,0 +1,136 @@
+/// Matches `string` against a glob-style pattern the way Redis does for KEYS, SCAN and
+/// pub/sub patterns: `*` matches any run of bytes, `?` any single byte, `[abc]`, `[a-z]` and
+/// `[^a]` match one byte from a set, and `\` takes the next byte literally.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where the last `*` was, and where in the string it would resume matching
+    let mut backtrack = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true
+                    }
+                    backtrack = Some((p, s));
+                    continue;
+                },
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                },
+                b'[' => {
+                    let (matched, next) = match_class(pattern, p + 1, string[s]);
+                    if matched {
+                        p = next;
+                        s += 1;
+                        continue;
+                    }
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                },
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                },
+            }
+        }
+        // let the last `*` take one more byte and try again from there
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            },
+            None => return false,
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// Matches `c` against the class that starts at `start`, right after its `[`. Returns whether
+/// it matched and where the pattern goes on after the class. A class without its closing `]`
+/// runs to the end of the pattern.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut i = start;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            matched |= pattern[i] == c;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+            let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= (low..=high).contains(&c);
+            i += 2;
+        } else {
+            matched |= pattern[i] == c;
+        }
+        i += 1;
+    }
+    // past the `]`
+    (matched != negated, (i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, matching: &[&str], not_matching: &[&str]) {
+        for string in matching {
+            assert!(matches(pattern.as_bytes(), string.as_bytes()), "{} should match {}", pattern, string);
+        }
+        for string in not_matching {
+            assert!(!matches(pattern.as_bytes(), string.as_bytes()), "{} should not match {}", pattern, string);
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        check("*", &["", "anything"], &[]);
+        check("h?llo", &["hello", "hallo"], &["hllo", "heello"]);
+        check("h*llo", &["hllo", "heeeello"], &["helo", "hello!"]);
+        check("a*b*c", &["abc", "aXbYc", "abbcbc"], &["acb", "ab"]);
+        // the star has to give back what the rest of the pattern needs
+        check("*.txt", &["a.txt", "a.txt.txt"], &["a.txt.gz"]);
+        check("", &[""], &["a"]);
+    }
+
+    #[test]
+    fn classes() {
+        check("h[ae]llo", &["hello", "hallo"], &["hillo", "hllo"]);
+        check("h[^e]llo", &["hallo", "hbllo"], &["hello"]);
+        check("h[a-b]llo", &["hallo", "hbllo"], &["hcllo"]);
+        // a reversed range is the same range
+        check("h[b-a]llo", &["hallo", "hbllo"], &["hcllo"]);
+        check("[\\]]", &["]"], &["\\"]);
+        // a class without its `]` runs to the end of the pattern
+        check("[abc", &["a", "c"], &["d", "ab"]);
+    }
+
+    #[test]
+    fn escapes_and_bytes() {
+        check("h\\*llo", &["h*llo"], &["hello"]);
+        check("h\\?", &["h?"], &["ha"]);
+        assert!(matches(b"\xff*", b"\xff\x00"));
+        assert!(!matches(b"\xff?", b"\xfe\x00"));
+    }
+}
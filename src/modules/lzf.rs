@@ -0,0 +1,77 @@
+use anyhow::{Result, anyhow};
+
+/// Decompresses an LZF block (the format used for compressed strings in RDB files)
+/// into a buffer of exactly `expected_len` bytes.
+pub fn decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>> {
+    // the length comes from the file, and each input byte expands to at most 264 output bytes
+    let mut output = Vec::with_capacity(expected_len.min(input.len().saturating_mul(264)));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run of ctrl + 1 bytes
+            let run = ctrl + 1;
+            if i + run > input.len() {
+                return Err(anyhow!("LZF literal run goes past the end of the input"))
+            }
+            if output.len() + run > expected_len {
+                return Err(anyhow!("LZF decompressed to more than {} bytes", expected_len))
+            }
+            output.extend_from_slice(&input[i..i + run]);
+            i += run;
+        } else {
+            // back reference: 3 bits of length (7 means an extra length byte) and 13 bits of offset
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or(anyhow!("LZF back reference is truncated"))? as usize;
+                i += 1;
+            }
+            len += 2;
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or(anyhow!("LZF back reference is truncated"))? as usize + 1;
+            i += 1;
+            if offset > output.len() {
+                return Err(anyhow!("LZF back reference points before the start of the output"))
+            }
+            let start = output.len() - offset;
+            // the referenced region may overlap the bytes being written, so copy one at a time
+            if output.len() + len > expected_len {
+                return Err(anyhow!("LZF decompressed to more than {} bytes", expected_len))
+            }
+            for k in 0..len {
+                output.push(output[start + k]);
+            }
+        }
+    }
+    if output.len() != expected_len {
+        return Err(anyhow!("LZF decompressed to {} bytes, expected {}", output.len(), expected_len))
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_runs_and_back_references() {
+        // "abc", then 4 bytes from 3 back
+        assert_eq!(decompress(&[2, b'a', b'b', b'c', 2 << 5, 2], 7).unwrap(), b"abcabca");
+        // "a", then 9 bytes from 1 back, which overlap the ones being written
+        assert_eq!(decompress(&[0, b'a', 7 << 5, 0, 0], 10).unwrap(), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        // literal run past the end of the input
+        assert!(decompress(&[3, b'a', b'b'], 4).is_err());
+        // back reference without its offset byte
+        assert!(decompress(&[0, b'a', 1 << 5], 4).is_err());
+        // back reference before the start of the output
+        assert!(decompress(&[0, b'a', 1 << 5, 1], 4).is_err());
+        // more or fewer bytes than expected
+        assert!(decompress(&[2, b'a', b'b', b'c'], 2).is_err());
+        assert!(decompress(&[2, b'a', b'b', b'c'], 4).is_err());
+        assert!(decompress(&[0, b'a', 7 << 5, 0xff, 0], 100).is_err());
+    }
+}
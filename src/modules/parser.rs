@@ -1,111 +1,475 @@
-use std::{cmp::max, pin::Pin};
-use tokio::{io::AsyncReadExt, net::{tcp::{OwnedReadHalf}}};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use anyhow::{Result, anyhow};
 use crate::modules::values::RedisValue;
 
-type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+/// How much the buffer grows by, at least, before each read.
+const READ_CHUNK: usize = 16 * 1024;
+/// Default for the size of a bulk string, same as `proto-max-bulk-len`.
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Default for the number of elements of an array.
+pub const DEFAULT_MAX_MULTIBULK_LEN: usize = i32::MAX as usize;
+/// Deepest nesting of arrays accepted. Values are dropped (and encoded) recursively, so
+/// this keeps them from running out of stack.
+const MAX_NESTING_DEPTH: usize = 128;
+/// Longest line (e.g. a length header) accepted before its CRLF shows up.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Longest inline command accepted.
+const MAX_INLINE_LEN: usize = 64 * 1024;
 
-fn is_digit(b: u8) -> bool {
-    b >= b'0' && b <= b'9'
+fn read_len(line: &[u8]) -> Option<i64> {
+    std::str::from_utf8(line).ok()?.parse::<i64>().ok()
 }
 
-fn read_uint(blob: &[u8]) -> Result<usize> {
-    let str_repr = String::from_utf8(blob.to_vec().into_iter().take_while(|b| is_digit(*b)).collect())?;
-    Ok(usize::from_str_radix(&str_repr, 10)?)
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
 }
 
-pub struct RedisParser {
-    stream: OwnedReadHalf,
-    buffer: [u8; 1024],
-    position: usize,
+/// Splits an inline command into its arguments the way `redis-cli` does: separated by
+/// whitespace, where double quoted arguments may have escapes (`\n`, `\xff`...) and single
+/// quoted ones may have `\'`. Returns `None` when the quotes aren't balanced.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = vec![];
+    let mut p = 0;
+    loop {
+        while p < line.len() && line[p].is_ascii_whitespace() {
+            p += 1;
+        }
+        if p == line.len() {
+            return Some(args)
+        }
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        let mut current = vec![];
+        loop {
+            let c = line.get(p).copied();
+            let next = line.get(p + 1).copied();
+            if in_double_quotes {
+                match (c?, next) {
+                    (b'\\', Some(b'x')) if let (Some(high), Some(low)) = (line.get(p + 2).and_then(|b| hex_digit(*b)), line.get(p + 3).and_then(|b| hex_digit(*b))) => {
+                        current.push(high * 16 + low);
+                        p += 3;
+                    },
+                    (b'\\', Some(escaped)) => {
+                        current.push(match escaped {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                        p += 1;
+                    },
+                    (b'"', next) => {
+                        // the closing quote must end the argument
+                        if next.is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return None
+                        }
+                        p += 1;
+                        break;
+                    },
+                    (c, _) => current.push(c),
+                }
+            } else if in_single_quotes {
+                match (c?, next) {
+                    (b'\\', Some(b'\'')) => {
+                        current.push(b'\'');
+                        p += 1;
+                    },
+                    (b'\'', next) => {
+                        if next.is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return None
+                        }
+                        p += 1;
+                        break;
+                    },
+                    (c, _) => current.push(c),
+                }
+            } else {
+                match c {
+                    None | Some(b' ' | b'\n' | b'\r' | b'\t' | b'\0') => break,
+                    Some(b'"') => in_double_quotes = true,
+                    Some(b'\'') => in_single_quotes = true,
+                    Some(c) => current.push(c),
+                }
+            }
+            p += 1;
+        }
+        args.push(current);
+    }
 }
 
-impl RedisParser {
-    pub fn new(stream: OwnedReadHalf) -> Self {
-        Self { stream, buffer: [0u8; 1024], position: 0 }
+/// Types made of other values.
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+    /// Its key value pairs, followed by the value they describe.
+    Attribute,
+}
+
+impl Aggregate {
+    /// Builds the value from its elements, in the order they came.
+    fn build(self, mut items: Vec<RedisValue>) -> RedisValue {
+        match self {
+            Self::Array => RedisValue::Array(items),
+            Self::Set => RedisValue::Set(items),
+            Self::Push => RedisValue::Push(items),
+            Self::Map => RedisValue::Map(into_pairs(items)),
+            Self::Attribute => {
+                let value = items.pop().unwrap();
+                RedisValue::Attribute(into_pairs(items), Box::new(value))
+            },
+        }
     }
-    pub fn read_value(&mut self) -> BoxFuture<'_, Result<RedisValue>> {
-        Box::pin(async move {
-            if self.position == 0 {
-                if let Err(_) = self.stream.read_exact(&mut self.buffer[..1]).await {
-                    return Err(anyhow!("Client '{}' disconnected. ", self.stream.peer_addr()?))
-                }
-                self.position = 1;
-            }
-            match self.buffer[0] {
-                b'+' => self.simple_string().await,
-                b'*' => self.array().await,
-                b'$' => self.bulk_string().await,
-                b':' => self.integer().await,
-                _ => Err(anyhow!("Unrecognized value start {}", self.buffer[0]))
-            }
-        })
+}
+
+fn into_pairs(items: Vec<RedisValue>) -> Vec<(RedisValue, RedisValue)> {
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+/// What a single step of the decoder got out of the buffer.
+enum Element {
+    Value(RedisValue),
+    /// The header of an aggregate with this many elements, which come next.
+    AggregateStart(Aggregate, usize),
+}
+
+/// Incremental RESP decoder. Data is read into a buffer that grows as needed, and values
+/// are decoded from it as soon as they're complete, so a value can span any number of
+/// reads. Everything is kept in the parser between reads, which makes `read_value` safe to
+/// cancel (e.g. from a `select!`) without losing input.
+pub struct RedisParser<R> {
+    stream: R,
+    buffer: Vec<u8>,
+    /// Start of the part of `buffer` that hasn't been decoded yet.
+    cursor: usize,
+    /// Aggregates being decoded, innermost last, with the number of elements still missing from each.
+    pending: Vec<(Aggregate, Vec<RedisValue>, usize)>,
+    /// Bytes of the input that have been fully parsed into values.
+    consumed: usize,
+    eof: bool,
+    max_bulk_len: usize,
+    max_multibulk_len: usize,
+    /// Whether to take input that isn't an array as an inline command, as typed in a telnet session.
+    inline: bool,
+}
+
+impl<R: AsyncRead + Unpin + Send> RedisParser<R> {
+    pub fn new(stream: R) -> Self {
+        Self { stream, buffer: vec![], cursor: 0, pending: vec![], consumed: 0, eof: false,
+            max_bulk_len: DEFAULT_MAX_BULK_LEN, max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN, inline: false }
+    }
+    /// Makes the parser read commands from clients, which may also be sent inline.
+    pub fn accept_inline(&mut self) {
+        self.inline = true;
+    }
+    /// Sets the largest bulk string and array accepted. Bigger ones are a protocol error.
+    pub fn set_limits(&mut self, max_bulk_len: usize, max_multibulk_len: usize) {
+        self.max_bulk_len = max_bulk_len;
+        self.max_multibulk_len = max_multibulk_len;
+    }
+    pub fn get_consumed(&self) -> usize {
+        self.consumed
+    }
+    /// Whether the input ended, as opposed to containing something that isn't valid RESP.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+    async fn fill(&mut self) -> Result<()> {
+        // what was decoded is dropped from the buffer before it grows
+        if self.cursor > 0 {
+            self.buffer.drain(..self.cursor);
+            self.cursor = 0;
+        }
+        self.buffer.reserve(READ_CHUNK);
+        let nread = self.stream.read_buf(&mut self.buffer).await?;
+        if nread == 0 {
+            self.eof = true;
+            return Err(anyhow!("Client disconnected."))
+        }
+        Ok(())
     }
-    async fn read_blob(&mut self) -> Result<Vec<u8>> {
+    pub async fn read_value(&mut self) -> Result<RedisValue> {
         loop {
-            // Look for \r in the part of the buffer we've filled so far
-            let maybe_cr = self.buffer[..self.position].iter().position(|c| *c == b'\r');
-            match maybe_cr {
-                None => {
-                    // no \r yet -> read more data
-                    let nread = self.stream.read(&mut self.buffer[self.position..]).await?;
-                    if nread == 0 {
-                        return Err(anyhow!("Client disconnected."))
+            match self.next_element()? {
+                Some(Element::Value(value)) => {
+                    if let Some(value) = self.complete(value) {
+                        return Ok(value)
                     }
-                    self.position += nread;
                 },
-                Some(p) => {
-                    // \r found, now looking for \n
-                    if p+1 >= self.position {
-                        // \r is at the end of read data so lets get more bytes
-                        let nread = self.stream.read(&mut self.buffer[self.position..]).await?;
-                        if nread == 0 {
-                            return Err(anyhow!("Client disconnected."))
-                        }
-                        self.position += nread;
-                        continue;
+                Some(Element::AggregateStart(aggregate, 0)) => {
+                    if let Some(value) = self.complete(aggregate.build(vec![])) {
+                        return Ok(value)
                     }
-
-                    // check next byte for \n
-                    if self.buffer[p+1] != b'\n' {
-                        continue;
-                    } else {
-                        let blob = self.buffer[..(p+2)].to_vec();
-                        self.buffer.copy_within(p+2..self.position, 0);
-                        self.position = max(self.position - (p + 2), 0);
-                        return Ok(blob)
+                },
+                Some(Element::AggregateStart(aggregate, n)) => {
+                    if self.pending.len() >= MAX_NESTING_DEPTH {
+                        return Err(anyhow!("Protocol error: arrays nested too deeply"))
                     }
-                }
+                    // the length comes from the input, so it doesn't decide how much is allocated
+                    self.pending.push((aggregate, Vec::with_capacity(n.min(1024)), n));
+                },
+                None => self.fill().await?,
+            }
+        }
+    }
+    /// Adds a decoded value to the aggregate it belongs to, and returns the outermost value
+    /// once it's complete.
+    fn complete(&mut self, mut value: RedisValue) -> Option<RedisValue> {
+        loop {
+            let Some((_, items, missing)) = self.pending.last_mut() else {
+                return Some(value)
             };
+            items.push(value);
+            *missing -= 1;
+            if *missing > 0 {
+                return None
+            }
+            let (aggregate, items, _) = self.pending.pop().unwrap();
+            value = aggregate.build(items);
         }
     }
-    async fn simple_string(&mut self) -> Result<RedisValue> {
-        let blob = self.read_blob().await?;
-        Ok(RedisValue::String(String::from_utf8(blob[1..blob.len()-2].to_vec())?))
-    }
-    async fn integer(&mut self) -> Result<RedisValue> {
-        let blob = self.read_blob().await?;
-        let number_string = String::from_utf8(blob[1..blob.len()-2].to_vec())?;
-        let number = i64::from_str_radix(&number_string, 10)?;
-        
-        Ok(RedisValue::Int(number))
-    }
-    async fn bulk_string(&mut self) -> Result<RedisValue> {
-        let blob = self.read_blob().await?;
-        let n = read_uint(&blob[1..])?;
-        let decoded_string = String::from_utf8(self.buffer[..n].to_vec())?;
-        self.buffer.copy_within(n+2.., 0);
-        self.position = max(0, self.position - (n+2));
-        Ok(RedisValue::String(decoded_string))
-    }
-    async fn array(&mut self) -> Result<RedisValue> {
-        let blob = self.read_blob().await?;
-        let n = read_uint(&blob[1..])?;
+    /// The next line in the buffer, without its CRLF, if it has been read in full.
+    fn line(&self) -> Option<&[u8]> {
+        let unread = &self.buffer[self.cursor..];
+        let end = unread.windows(2).position(|window| window == b"\r\n")?;
+        Some(&unread[..end])
+    }
+    fn advance(&mut self, n: usize) {
+        self.cursor += n;
+        self.consumed += n;
+    }
+    /// Decodes an inline command from the buffer into an array of its arguments, or returns
+    /// `None` if more input is needed.
+    fn inline_command(&mut self) -> Result<Option<Element>> {
+        let unread = &self.buffer[self.cursor..];
+        let Some(end) = unread.iter().position(|b| *b == b'\n') else {
+            if unread.len() > MAX_INLINE_LEN {
+                return Err(anyhow!("Protocol error: too big inline request"))
+            }
+            return Ok(None)
+        };
+        if end > MAX_INLINE_LEN {
+            return Err(anyhow!("Protocol error: too big inline request"))
+        }
+        // telnet sends CRLF, but a bare LF is fine too
+        let line = unread[..end].strip_suffix(b"\r").unwrap_or(&unread[..end]);
+        let args = split_inline_args(line).ok_or(anyhow!("Protocol error: unbalanced quotes in request"))?;
+        self.advance(end + 1);
+        Ok(Some(Element::Value(RedisValue::Array(args.into_iter().map(RedisValue::String).collect()))))
+    }
+    /// Decodes the next element from the buffer, or returns `None` if more input is needed.
+    fn next_element(&mut self) -> Result<Option<Element>> {
+        let starts_inline = self.buffer.get(self.cursor).is_some_and(|b| *b != b'*');
+        if self.inline && self.pending.is_empty() && starts_inline {
+            return self.inline_command()
+        }
+        let Some(line) = self.line() else {
+            if self.buffer.len() - self.cursor > MAX_LINE_LEN {
+                return Err(anyhow!("Protocol error: too big request header"))
+            }
+            return Ok(None)
+        };
+        let line_len = line.len() + 2;
+        let Some(&kind) = line.first() else {
+            return Err(anyhow!("Protocol error: empty line"))
+        };
+        let element = match kind {
+            b'+' => Element::Value(RedisValue::String(line[1..].to_vec())),
+            b'-' => Element::Value(RedisValue::Error(String::from_utf8_lossy(&line[1..]).into_owned())),
+            b':' => {
+                let number = read_len(&line[1..]).ok_or(anyhow!("Protocol error: invalid integer"))?;
+                Element::Value(RedisValue::Int(number))
+            },
+            b'_' => Element::Value(RedisValue::Null),
+            b'#' => match &line[1..] {
+                b"t" => Element::Value(RedisValue::Boolean(true)),
+                b"f" => Element::Value(RedisValue::Boolean(false)),
+                _ => return Err(anyhow!("Protocol error: invalid boolean")),
+            },
+            b',' => {
+                let double = std::str::from_utf8(&line[1..]).ok().and_then(|d| d.parse::<f64>().ok())
+                    .ok_or(anyhow!("Protocol error: invalid double"))?;
+                Element::Value(RedisValue::Double(double))
+            },
+            b'(' => {
+                let digits = line[1..].strip_prefix(b"-").unwrap_or(&line[1..]);
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err(anyhow!("Protocol error: invalid big number"))
+                }
+                Element::Value(RedisValue::BigNumber(String::from_utf8_lossy(&line[1..]).into_owned()))
+            },
+            b'$' | b'=' => {
+                let n = read_len(&line[1..]).filter(|n| *n >= 0 && *n as usize <= self.max_bulk_len)
+                    .ok_or(anyhow!("Protocol error: invalid bulk length"))? as usize;
+                let available = self.buffer.len() - self.cursor;
+                if available < line_len + n + 2 {
+                    // the whole payload is read before anything is taken from the buffer
+                    self.buffer.reserve(line_len + n + 2 - available);
+                    return Ok(None)
+                }
+                let start = self.cursor + line_len;
+                if &self.buffer[start + n..start + n + 2] != b"\r\n" {
+                    return Err(anyhow!("Protocol error: bulk string not terminated by CRLF"))
+                }
+                let content = self.buffer[start..start + n].to_vec();
+                self.advance(line_len + n + 2);
+                let value = if kind == b'=' {
+                    // a verbatim string starts with its three letter format
+                    if content.len() < 4 || content[3] != b':' {
+                        return Err(anyhow!("Protocol error: invalid verbatim string"))
+                    }
+                    RedisValue::Verbatim(String::from_utf8_lossy(&content[..3]).into_owned(), content[4..].to_vec())
+                } else {
+                    RedisValue::String(content)
+                };
+                return Ok(Some(Element::Value(value)))
+            },
+            b'*' | b'%' | b'~' | b'>' | b'|' => {
+                let n = read_len(&line[1..]).filter(|n| *n >= 0 && *n as usize <= self.max_multibulk_len)
+                    .ok_or(anyhow!("Protocol error: invalid multibulk length"))? as usize;
+                match kind {
+                    b'%' => Element::AggregateStart(Aggregate::Map, n * 2),
+                    b'~' => Element::AggregateStart(Aggregate::Set, n),
+                    b'>' => Element::AggregateStart(Aggregate::Push, n),
+                    b'|' => Element::AggregateStart(Aggregate::Attribute, n * 2 + 1),
+                    _ => Element::AggregateStart(Aggregate::Array, n),
+                }
+            },
+            _ => return Err(anyhow!("Protocol error: unrecognized value start '{}'", kind as char)),
+        };
+        self.advance(line_len);
+        Ok(Some(element))
+    }
+    /// Reads the RDB file a master sends on a full resync: a bulk string header
+    /// followed by the payload, with no CRLF after it.
+    pub async fn read_rdb(&mut self) -> Result<Vec<u8>> {
+        let n = loop {
+            match self.line() {
+                Some(line) if line.first() == Some(&b'$') => {
+                    break read_len(&line[1..]).filter(|n| *n >= 0).ok_or(anyhow!("Invalid length for the RDB payload"))? as usize
+                },
+                Some(line) => return Err(anyhow!("Expected the RDB payload, found '{}'", String::from_utf8_lossy(line))),
+                None => self.fill().await?,
+            }
+        };
+        let header_len = self.line().unwrap().len() + 2;
+        self.advance(header_len);
+        while self.buffer.len() - self.cursor < n {
+            self.fill().await?;
+        }
+        let payload = self.buffer[self.cursor..self.cursor + n].to_vec();
+        self.advance(n);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io, pin::Pin, task::{Context, Poll}};
+    use tokio::io::ReadBuf;
+
+    use super::*;
+    use crate::modules::values::Protocol;
+
+    /// Input that arrives in the given pieces, one per read.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Chunks {
+        fn bytewise(data: &[u8]) -> Self {
+            Self(data.iter().map(|b| vec![*b]).collect())
+        }
+    }
+
+    impl AsyncRead for Chunks {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if let Some(chunk) = self.0.front_mut() {
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                chunk.drain(..n);
+                if chunk.is_empty() {
+                    self.0.pop_front();
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn read_all(parser: &mut RedisParser<impl AsyncRead + Unpin + Send>) -> Vec<Vec<u8>> {
         let mut values = vec![];
-        for _ in 0..n {
-            values.push(self.read_value().await?);
+        while let Ok(value) = parser.read_value().await {
+            values.push(value.encode_with(Protocol::Resp3));
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn values_split_across_reads() {
+        let input = b"*2\r\n$3\r\nfoo\r\n$5\r\nhello\r\n:-42\r\n%1\r\n$3\r\nkey\r\n~2\r\n#t\r\n_\r\n";
+        let mut parser = RedisParser::new(Chunks::bytewise(input));
+        let values = read_all(&mut parser).await;
+        assert_eq!(values, vec![b"*2\r\n$3\r\nfoo\r\n$5\r\nhello\r\n".to_vec(), b":-42\r\n".to_vec(), b"%1\r\n$3\r\nkey\r\n~2\r\n#t\r\n_\r\n".to_vec()]);
+        assert!(parser.is_eof());
+        assert_eq!(parser.get_consumed(), input.len());
+    }
+
+    #[tokio::test]
+    async fn bulk_strings_longer_than_a_read() {
+        let payload = vec![b'x'; READ_CHUNK * 3 + 7];
+        let mut input = format!("*1\r\n${}\r\n", payload.len()).into_bytes();
+        input.extend(&payload);
+        input.extend(b"\r\n");
+        let chunks = input.chunks(1000).map(<[u8]>::to_vec).collect();
+        let mut parser = RedisParser::new(Chunks(chunks));
+        assert_eq!(read_all(&mut parser).await, vec![input]);
+    }
+
+    #[tokio::test]
+    async fn limits_are_protocol_errors() {
+        let mut parser = RedisParser::new(&b"$3\r\nabc\r\n*2\r\n:1\r\n:2\r\n"[..]);
+        parser.set_limits(3, 2);
+        assert_eq!(read_all(&mut parser).await.len(), 2);
+
+        for input in [&b"$4\r\nabcd\r\n"[..], b"*3\r\n:1\r\n:2\r\n:3\r\n", b"$-2\r\n", b"*2147483647\r\n"] {
+            let mut parser = RedisParser::new(input);
+            parser.set_limits(3, 2);
+            assert!(parser.read_value().await.is_err());
+            assert!(!parser.is_eof());
+        }
+
+        let mut parser = RedisParser::new(Chunks(VecDeque::from(["*1\r\n".repeat(MAX_NESTING_DEPTH + 1).into_bytes()])));
+        assert!(parser.read_value().await.is_err());
+        assert!(!parser.is_eof());
+
+        let mut parser = RedisParser::new(Chunks(VecDeque::from([vec![b'+'; MAX_LINE_LEN + 1]])));
+        assert!(parser.read_value().await.is_err());
+        assert!(!parser.is_eof());
+    }
+
+    #[tokio::test]
+    async fn inline_commands() {
+        let mut parser = RedisParser::new(Chunks::bytewise(b"SET \"a b\\x41\\n\" 'c\\'d'\r\nPING\n*1\r\n$4\r\nPING\r\n"));
+        parser.accept_inline();
+        assert_eq!(read_all(&mut parser).await, vec![
+            b"*3\r\n$3\r\nSET\r\n$5\r\na bA\n\r\n$3\r\nc'd\r\n".to_vec(),
+            b"*1\r\n$4\r\nPING\r\n".to_vec(),
+            b"*1\r\n$4\r\nPING\r\n".to_vec(),
+        ]);
+
+        for input in [&b"SET \"a\r\n"[..], b"SET 'a'b\r\n"] {
+            let mut parser = RedisParser::new(input);
+            parser.accept_inline();
+            assert!(parser.read_value().await.is_err());
+            assert!(!parser.is_eof());
         }
-        Ok(RedisValue::Array(values))
     }
 }
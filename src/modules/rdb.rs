@@ -0,0 +1,1243 @@
+use std::{collections::{HashMap, VecDeque}, fs, io::ErrorKind, path::{Path, PathBuf}, process, sync::Arc};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use tokio::{sync::RwLock, time::{self, Duration}};
+
+use crate::{REDIS_VERSION, modules::{config::Config, crc64::crc64, db::{DB, DbRecord, HashRecord, ListRecord, SetRecord, StreamEntry, StreamRecord, StringRecord, ZSetRecord}, lzf}};
+
+const RDB_VERSION: u32 = 12;
+
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const RDB_TYPE_HASH_METADATA: u8 = 24;
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: u64 = 2;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+type FieldValues = Vec<(Vec<u8>, Vec<u8>)>;
+/// Hash fields with a time to live, and when they expire.
+type FieldExpires = Vec<(Vec<u8>, DateTime<Utc>)>;
+
+/// A value as stored in the RDB file, before it is turned into a `DbRecord`.
+enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    ZSet(Vec<(Vec<u8>, f64)>),
+    Hash(FieldValues, FieldExpires),
+    Stream(Vec<(String, FieldValues)>),
+}
+
+impl RdbValue {
+    fn into_record(self) -> DbRecord {
+        match self {
+            Self::String(value) => {
+                DbRecord::String(StringRecord::new(value))
+            },
+            Self::List(items) => {
+                DbRecord::List(ListRecord::from_list(VecDeque::from(items)))
+            },
+            Self::Stream(entries) => {
+                let mut stream_record = StreamRecord::new();
+                for (id, fields) in entries {
+                    let values = fields.into_iter().collect::<HashMap<_, _>>();
+                    stream_record.push(StreamEntry::new(&id, Some(values)));
+                }
+                DbRecord::Stream(stream_record)
+            },
+            Self::Hash(fields, expires) => {
+                let mut hash_record = HashRecord::new();
+                for (field, value) in fields {
+                    hash_record.insert(field, value);
+                }
+                for (field, limit) in expires {
+                    hash_record.set_expire(&field, limit);
+                }
+                DbRecord::Hash(hash_record)
+            },
+            Self::Set(members) => {
+                let mut set_record = SetRecord::new();
+                for member in members {
+                    set_record.insert(member);
+                }
+                DbRecord::Set(set_record)
+            },
+            Self::ZSet(members) => {
+                let mut zset_record = ZSetRecord::new();
+                for (member, score) in members {
+                    zset_record.insert(member, score);
+                }
+                DbRecord::ZSet(zset_record)
+            },
+        }
+    }
+
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+/// An element of a listpack or ziplist, which may be stored as a string or as an integer.
+enum PackedEntry {
+    Bytes(Vec<u8>),
+    Int(i64),
+}
+
+impl PackedEntry {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Bytes(bytes) => bytes,
+            Self::Int(i) => i.to_string().into_bytes(),
+        }
+    }
+
+    fn as_int(&self) -> Result<i64> {
+        match self {
+            Self::Int(i) => Ok(*i),
+            Self::Bytes(bytes) => Ok(std::str::from_utf8(bytes)?.parse::<i64>()?),
+        }
+    }
+
+    fn as_float(&self) -> Result<f64> {
+        match self {
+            Self::Int(i) => Ok(*i as f64),
+            Self::Bytes(bytes) => parse_float(bytes),
+        }
+    }
+}
+
+fn parse_float(bytes: &[u8]) -> Result<f64> {
+    let s = std::str::from_utf8(bytes)?;
+    match s {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Ok(s.parse::<f64>()?),
+    }
+}
+
+/// Loads the snapshot at `path`. A missing file yields an empty database.
+pub fn load(path: &Path) -> Result<DB> {
+    match fs::read(path) {
+        Ok(data) => parse(&data),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(DB::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<DB> {
+    let mut reader = RdbReader::new(data);
+    reader.read_db()
+}
+
+/// Saves the database in the foreground, blocking writers until the file is on disk.
+pub async fn save(db: &Arc<RwLock<DB>>, path: &Path) -> Result<()> {
+    let mut db = db.write().await;
+    if db.is_bgsave_in_progress() {
+        return Err(anyhow!("Background save already in progress"))
+    }
+    let snapshot = dump(&db)?;
+    write_file(path, &snapshot).await?;
+    let dirty = db.get_dirty();
+    db.mark_saved(dirty);
+    Ok(())
+}
+
+/// Takes a snapshot of the database and writes it to `path` on a background task,
+/// so clients are only blocked while the snapshot is serialized in memory.
+pub async fn bgsave(db: &Arc<RwLock<DB>>, path: PathBuf) -> Result<()> {
+    let (snapshot, dirty_at_snapshot) = {
+        let mut db = db.write().await;
+        if db.is_bgsave_in_progress() {
+            return Err(anyhow!("Background save already in progress"))
+        }
+        db.start_bgsave();
+        match dump(&db) {
+            Ok(snapshot) => (snapshot, db.get_dirty()),
+            Err(e) => {
+                db.finish_bgsave(false);
+                return Err(e)
+            }
+        }
+    };
+    let db = Arc::clone(db);
+    tokio::spawn(async move {
+        let result = write_file(&path, &snapshot).await;
+        let mut db = db.write().await;
+        match result {
+            Ok(()) => {
+                println!("Background saving terminated with success");
+                db.mark_saved(dirty_at_snapshot);
+                db.finish_bgsave(true);
+            },
+            Err(e) => {
+                eprintln!("Background saving error: {}", e);
+                db.finish_bgsave(false);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Seconds to wait before retrying a background save that failed.
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+/// Checks the configured `save` points once per second and starts a background save
+/// whenever one of them is reached.
+pub async fn run_save_points(db: Arc<RwLock<DB>>, config: Arc<RwLock<Config>>) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let (save_points, path) = {
+            let config = config.read().await;
+            (config.get_save_points(), config.get_rdb_path())
+        };
+        let reached = {
+            let db = db.read().await;
+            let now = Utc::now();
+            let elapsed = (now - db.get_lastsave()).num_seconds().max(0) as u64;
+            let can_retry = db.is_last_bgsave_ok() || db.get_last_bgsave_try().is_none_or(|last_try| (now - last_try).num_seconds() >= BGSAVE_RETRY_DELAY);
+            if db.is_bgsave_in_progress() || !can_retry {
+                None
+            } else {
+                save_points.into_iter().find(|(seconds, changes)| db.get_dirty() >= *changes && elapsed >= *seconds)
+            }
+        };
+        if let Some((seconds, changes)) = reached {
+            println!("{} changes in {} seconds. Saving...", changes, seconds);
+            if let Err(e) = bgsave(&db, path).await {
+                eprintln!("Can't start background save: {}", e);
+            }
+        }
+    }
+}
+
+/// Writes through a temporary file so a crash mid-write never leaves a truncated snapshot behind.
+async fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = path.with_file_name(format!("temp-{}.rdb", process::id()));
+    tokio::fs::write(&temp_path, contents).await?;
+    if let Err(e) = tokio::fs::rename(&temp_path, path).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e.into())
+    }
+    Ok(())
+}
+
+/// Serializes the whole database in RDB format.
+pub fn dump(db: &DB) -> Result<Vec<u8>> {
+    let mut writer = RdbWriter::new();
+    writer.write_db(db)?;
+    Ok(writer.buffer)
+}
+
+struct RdbReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read_db(&mut self) -> Result<DB> {
+        let magic = self.read_bytes(5)?;
+        if magic != b"REDIS" {
+            return Err(anyhow!("Wrong signature trying to load DB from file"))
+        }
+        let version = std::str::from_utf8(self.read_bytes(4)?)?.parse::<u32>()?;
+        if version == 0 || version > RDB_VERSION {
+            return Err(anyhow!("Can't handle RDB format version {}", version))
+        }
+
+        let mut db = DB::new();
+        let mut current_db = 0;
+        let mut expire = None;
+        let mut skipped = 0usize;
+        let now = Utc::now();
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => break,
+                RDB_OPCODE_SELECTDB => {
+                    current_db = self.read_len()?;
+                },
+                RDB_OPCODE_RESIZEDB => {
+                    let db_size = self.read_len()?;
+                    self.read_len()?;
+                    if current_db == 0 {
+                        db.reserve(db_size as usize);
+                    }
+                },
+                RDB_OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                },
+                RDB_OPCODE_EXPIRETIME => {
+                    let seconds = self.read_u32_le()? as i64;
+                    expire = DateTime::from_timestamp(seconds, 0);
+                },
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    let milliseconds = self.read_u64_le()? as i64;
+                    expire = DateTime::from_timestamp_millis(milliseconds);
+                },
+                RDB_OPCODE_FREQ => {
+                    self.read_u8()?;
+                },
+                RDB_OPCODE_IDLE => {
+                    self.read_len()?;
+                },
+                RDB_OPCODE_FUNCTION2 => {
+                    self.read_string()?;
+                },
+                RDB_OPCODE_SLOT_INFO => {
+                    self.read_len()?;
+                    self.read_len()?;
+                    self.read_len()?;
+                },
+                RDB_OPCODE_MODULE_AUX => {
+                    return Err(anyhow!("RDB files with module data are not supported"))
+                },
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = self.read_object(value_type)?;
+                    let key_expire = expire.take();
+                    if current_db != 0 {
+                        skipped += 1;
+                        continue;
+                    }
+                    if let Some(limit) = key_expire && limit <= now {
+                        continue;
+                    }
+                    db.insert(key.clone(), value.into_record());
+                    if let Some(limit) = key_expire {
+                        db.set_expire(&key, limit);
+                    }
+                }
+            }
+        }
+        if skipped > 0 {
+            println!("Skipped {} keys stored in databases other than 0", skipped);
+        }
+
+        if version >= 5 {
+            let checksum_start = self.position;
+            let expected = self.read_u64_le()?;
+            if expected != 0 {
+                let actual = crc64(0, &self.data[..checksum_start]);
+                if actual != expected {
+                    return Err(anyhow!("Wrong RDB checksum expected: {:x} got: {:x}", expected, actual))
+                }
+            }
+        }
+        // freshly loaded data is already on disk
+        db.mark_saved(db.get_dirty());
+        Ok(db)
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        // lengths come from the file, so they may be anything
+        let end = self.position.checked_add(n).filter(|end| *end <= self.data.len())
+            .ok_or(anyhow!("Unexpected EOF reading RDB file"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32_le(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into()?))
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into()?))
+    }
+
+    fn read_length(&mut self) -> Result<Length> {
+        let first = self.read_u8()?;
+        let length = match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64),
+            2 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.read_bytes(4)?.try_into()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.read_bytes(8)?.try_into()?)),
+                _ => return Err(anyhow!("Unknown length encoding {:#x} in RDB file", first)),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        };
+        Ok(length)
+    }
+
+    fn read_len(&mut self) -> Result<u64> {
+        match self.read_length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(anyhow!("Expected a length but found an encoded string in RDB file")),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        match self.read_length()? {
+            Length::Len(len) => Ok(self.read_bytes(len as usize)?.to_vec()),
+            Length::Encoded(RDB_ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT16) => Ok(i16::from_le_bytes(self.read_bytes(2)?.try_into()?).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT32) => Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into()?).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_len = self.read_len()? as usize;
+                let len = self.read_len()? as usize;
+                lzf::decompress(self.read_bytes(compressed_len)?, len)
+            },
+            Length::Encoded(encoding) => Err(anyhow!("Unknown RDB string encoding type {}", encoding)),
+        }
+    }
+
+    fn read_double(&mut self) -> Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_float(self.read_bytes(len as usize)?),
+        }
+    }
+
+    fn read_binary_double(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.read_bytes(8)?.try_into()?))
+    }
+
+    fn read_object(&mut self, value_type: u8) -> Result<RdbValue> {
+        let value = match value_type {
+            RDB_TYPE_STRING => RdbValue::String(self.read_string()?),
+            RDB_TYPE_LIST => {
+                let len = self.read_len()?;
+                let mut items = vec![];
+                for _ in 0..len {
+                    items.push(self.read_string()?);
+                }
+                RdbValue::List(items)
+            },
+            RDB_TYPE_LIST_ZIPLIST => {
+                RdbValue::List(decode_ziplist(&self.read_string()?)?.into_iter().map(PackedEntry::into_bytes).collect())
+            },
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_len()?;
+                let mut items = vec![];
+                for _ in 0..nodes {
+                    if value_type == RDB_TYPE_LIST_QUICKLIST {
+                        items.extend(decode_ziplist(&self.read_string()?)?.into_iter().map(PackedEntry::into_bytes));
+                        continue;
+                    }
+                    let container = self.read_len()?;
+                    let node = self.read_string()?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        items.push(node);
+                    } else {
+                        items.extend(decode_listpack(&node)?.into_iter().map(PackedEntry::into_bytes));
+                    }
+                }
+                RdbValue::List(items)
+            },
+            RDB_TYPE_SET => {
+                let len = self.read_len()?;
+                let mut members = vec![];
+                for _ in 0..len {
+                    members.push(self.read_string()?);
+                }
+                RdbValue::Set(members)
+            },
+            RDB_TYPE_SET_INTSET => RdbValue::Set(decode_intset(&self.read_string()?)?),
+            RDB_TYPE_SET_LISTPACK => {
+                RdbValue::Set(decode_listpack(&self.read_string()?)?.into_iter().map(PackedEntry::into_bytes).collect())
+            },
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_len()?;
+                let mut members = vec![];
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET { self.read_double()? } else { self.read_binary_double()? };
+                    members.push((member, score));
+                }
+                RdbValue::ZSet(members)
+            },
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let packed = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_ZSET_ZIPLIST { decode_ziplist(&packed)? } else { decode_listpack(&packed)? };
+                let mut members = vec![];
+                let mut entries = entries.into_iter();
+                while let Some(member) = entries.next() {
+                    let score = entries.next().ok_or(anyhow!("Sorted set member without a score in RDB file"))?;
+                    members.push((member.into_bytes(), score.as_float()?));
+                }
+                RdbValue::ZSet(members)
+            },
+            RDB_TYPE_HASH => {
+                let len = self.read_len()?;
+                let mut fields = vec![];
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    fields.push((field, value));
+                }
+                RdbValue::Hash(fields, vec![])
+            },
+            RDB_TYPE_HASH_METADATA => {
+                // each time to live is stored relative to the soonest one, plus one, or as 0 if there is none
+                let min_expire = self.read_u64_le()? as i64;
+                let len = self.read_len()?;
+                let (mut fields, mut expires) = (vec![], vec![]);
+                for _ in 0..len {
+                    let ttl = self.read_len()? as i64;
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    if ttl != 0 {
+                        let limit = DateTime::from_timestamp_millis(min_expire + ttl - 1).ok_or(anyhow!("Invalid hash field expire time in RDB file"))?;
+                        expires.push((field.clone(), limit));
+                    }
+                    fields.push((field, value));
+                }
+                RdbValue::Hash(fields, expires)
+            },
+            RDB_TYPE_HASH_ZIPMAP => RdbValue::Hash(decode_zipmap(&self.read_string()?)?, vec![]),
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let packed = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_HASH_ZIPLIST { decode_ziplist(&packed)? } else { decode_listpack(&packed)? };
+                RdbValue::Hash(into_pairs(entries)?, vec![])
+            },
+            RDB_TYPE_HASH_LISTPACK_EX => {
+                // the soonest time to live, then field, value and absolute expire time, or 0 for none
+                self.read_u64_le()?;
+                let (mut fields, mut expires) = (vec![], vec![]);
+                let mut entries = decode_listpack(&self.read_string()?)?.into_iter();
+                while let Some(field) = entries.next() {
+                    let field = field.into_bytes();
+                    let value = entries.next().ok_or(anyhow!("Hash field without a value in RDB file"))?.into_bytes();
+                    let ttl = entries.next().ok_or(anyhow!("Hash field without a time to live in RDB file"))?.as_int()?;
+                    if ttl != 0 {
+                        let limit = DateTime::from_timestamp_millis(ttl).ok_or(anyhow!("Invalid hash field expire time in RDB file"))?;
+                        expires.push((field.clone(), limit));
+                    }
+                    fields.push((field, value));
+                }
+                RdbValue::Hash(fields, expires)
+            },
+            RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => self.read_stream(value_type)?,
+            _ => return Err(anyhow!("Unknown RDB value type {}", value_type)),
+        };
+        Ok(value)
+    }
+
+    fn read_stream(&mut self, value_type: u8) -> Result<RdbValue> {
+        let mut entries = vec![];
+        let nodes = self.read_len()?;
+        for _ in 0..nodes {
+            let master_key = self.read_string()?;
+            if master_key.len() != 16 {
+                return Err(anyhow!("Stream node key entry is not the size of a stream ID"))
+            }
+            let master_ms = u64::from_be_bytes(master_key[..8].try_into()?);
+            let master_seq = u64::from_be_bytes(master_key[8..].try_into()?);
+            let packed = decode_listpack(&self.read_string()?)?;
+            let mut packed = packed.into_iter();
+            // master entry: count, deleted, master fields and the terminating zero
+            let count = next_int(&mut packed)?;
+            let deleted = next_int(&mut packed)?;
+            let master_fields_count = next_int(&mut packed)?;
+            let mut master_fields = vec![];
+            for _ in 0..master_fields_count {
+                master_fields.push(next_bytes(&mut packed)?);
+            }
+            next_int(&mut packed)?;
+            for _ in 0..(count + deleted) {
+                let flags = next_int(&mut packed)?;
+                let ms = master_ms.wrapping_add(next_int(&mut packed)? as u64);
+                let seq = master_seq.wrapping_add(next_int(&mut packed)? as u64);
+                let mut fields = vec![];
+                if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                    for field in &master_fields {
+                        fields.push((field.clone(), next_bytes(&mut packed)?));
+                    }
+                } else {
+                    let fields_count = next_int(&mut packed)?;
+                    for _ in 0..fields_count {
+                        let field = next_bytes(&mut packed)?;
+                        let value = next_bytes(&mut packed)?;
+                        fields.push((field, value));
+                    }
+                }
+                // lp-count of the entry, only used for reverse iteration
+                next_bytes(&mut packed)?;
+                if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                    entries.push((format!("{}-{}", ms, seq), fields));
+                }
+            }
+        }
+        // length and last id
+        self.read_len()?;
+        self.read_len()?;
+        self.read_len()?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            // first id, max deleted id and entries added
+            for _ in 0..5 {
+                self.read_len()?;
+            }
+        }
+        // consumer groups are not supported by the server, so they are read and dropped
+        let groups = self.read_len()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            self.read_len()?;
+            self.read_len()?;
+            if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                self.read_len()?;
+            }
+            let pending = self.read_len()?;
+            for _ in 0..pending {
+                self.read_bytes(16)?;
+                self.read_u64_le()?;
+                self.read_len()?;
+            }
+            let consumers = self.read_len()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                self.read_u64_le()?;
+                if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_u64_le()?;
+                }
+                let consumer_pending = self.read_len()?;
+                for _ in 0..consumer_pending {
+                    self.read_bytes(16)?;
+                }
+            }
+        }
+        Ok(RdbValue::Stream(entries))
+    }
+}
+
+fn next_int(entries: &mut impl Iterator<Item = PackedEntry>) -> Result<i64> {
+    entries.next().ok_or(anyhow!("Truncated stream listpack in RDB file"))?.as_int()
+}
+
+fn next_bytes(entries: &mut impl Iterator<Item = PackedEntry>) -> Result<Vec<u8>> {
+    Ok(entries.next().ok_or(anyhow!("Truncated stream listpack in RDB file"))?.into_bytes())
+}
+
+fn into_pairs(entries: Vec<PackedEntry>) -> Result<FieldValues> {
+    let mut pairs = vec![];
+    let mut entries = entries.into_iter();
+    while let Some(field) = entries.next() {
+        let value = entries.next().ok_or(anyhow!("Hash field without a value in RDB file"))?;
+        pairs.push((field.into_bytes(), value.into_bytes()));
+    }
+    Ok(pairs)
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    start.checked_add(len).and_then(|end| data.get(start..end)).ok_or(anyhow!("Encoded value goes past the end of its buffer"))
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn decode_listpack(lp: &[u8]) -> Result<Vec<PackedEntry>> {
+    let mut entries = vec![];
+    // header: total bytes (u32) and number of elements (u16)
+    let mut pos = 6;
+    loop {
+        let encoding = *lp.get(pos).ok_or(anyhow!("Listpack is missing its terminator"))?;
+        if encoding == 0xFF {
+            break;
+        }
+        let (entry, len) = if encoding & 0x80 == 0 {
+            (PackedEntry::Int((encoding & 0x7f) as i64), 1)
+        } else if encoding & 0xC0 == 0x80 {
+            let str_len = (encoding & 0x3f) as usize;
+            (PackedEntry::Bytes(slice(lp, pos + 1, str_len)?.to_vec()), 1 + str_len)
+        } else if encoding & 0xE0 == 0xC0 {
+            let value = (((encoding & 0x1f) as u64) << 8) | slice(lp, pos + 1, 1)?[0] as u64;
+            (PackedEntry::Int(sign_extend(value, 13)), 2)
+        } else if encoding & 0xF0 == 0xE0 {
+            let str_len = (((encoding & 0x0f) as usize) << 8) | slice(lp, pos + 1, 1)?[0] as usize;
+            (PackedEntry::Bytes(slice(lp, pos + 2, str_len)?.to_vec()), 2 + str_len)
+        } else {
+            match encoding {
+                0xF0 => {
+                    let str_len = u32::from_le_bytes(slice(lp, pos + 1, 4)?.try_into()?) as usize;
+                    (PackedEntry::Bytes(slice(lp, pos + 5, str_len)?.to_vec()), 5 + str_len)
+                },
+                0xF1 => (PackedEntry::Int(i16::from_le_bytes(slice(lp, pos + 1, 2)?.try_into()?) as i64), 3),
+                0xF2 => {
+                    let b = slice(lp, pos + 1, 3)?;
+                    let value = b[0] as u64 | (b[1] as u64) << 8 | (b[2] as u64) << 16;
+                    (PackedEntry::Int(sign_extend(value, 24)), 4)
+                },
+                0xF3 => (PackedEntry::Int(i32::from_le_bytes(slice(lp, pos + 1, 4)?.try_into()?) as i64), 5),
+                0xF4 => (PackedEntry::Int(i64::from_le_bytes(slice(lp, pos + 1, 8)?.try_into()?)), 9),
+                _ => return Err(anyhow!("Unknown listpack encoding {:#x}", encoding)),
+            }
+        };
+        let backlen = slice(lp, pos + len, backlen_size(len))?;
+        if decode_backlen(backlen) != Some(len) {
+            return Err(anyhow!("Listpack entry has a wrong back length"));
+        }
+        entries.push(entry);
+        pos += len + backlen.len();
+    }
+    Ok(entries)
+}
+
+fn decode_ziplist(zl: &[u8]) -> Result<Vec<PackedEntry>> {
+    let mut entries = vec![];
+    // header: total bytes (u32), tail offset (u32) and number of elements (u16)
+    let mut pos = 10;
+    loop {
+        let first = *zl.get(pos).ok_or(anyhow!("Ziplist is missing its terminator"))?;
+        if first == 0xFF {
+            break;
+        }
+        pos += if first < 254 { 1 } else { 5 };
+        let encoding = *zl.get(pos).ok_or(anyhow!("Ziplist entry is truncated"))?;
+        let (entry, len) = match encoding >> 6 {
+            0 => {
+                let str_len = (encoding & 0x3f) as usize;
+                (PackedEntry::Bytes(slice(zl, pos + 1, str_len)?.to_vec()), 1 + str_len)
+            },
+            1 => {
+                let str_len = (((encoding & 0x3f) as usize) << 8) | slice(zl, pos + 1, 1)?[0] as usize;
+                (PackedEntry::Bytes(slice(zl, pos + 2, str_len)?.to_vec()), 2 + str_len)
+            },
+            2 => {
+                let str_len = u32::from_be_bytes(slice(zl, pos + 1, 4)?.try_into()?) as usize;
+                (PackedEntry::Bytes(slice(zl, pos + 5, str_len)?.to_vec()), 5 + str_len)
+            },
+            _ => match encoding {
+                0xC0 => (PackedEntry::Int(i16::from_le_bytes(slice(zl, pos + 1, 2)?.try_into()?) as i64), 3),
+                0xD0 => (PackedEntry::Int(i32::from_le_bytes(slice(zl, pos + 1, 4)?.try_into()?) as i64), 5),
+                0xE0 => (PackedEntry::Int(i64::from_le_bytes(slice(zl, pos + 1, 8)?.try_into()?)), 9),
+                0xF0 => {
+                    let b = slice(zl, pos + 1, 3)?;
+                    let value = b[0] as u64 | (b[1] as u64) << 8 | (b[2] as u64) << 16;
+                    (PackedEntry::Int(sign_extend(value, 24)), 4)
+                },
+                0xFE => (PackedEntry::Int(slice(zl, pos + 1, 1)?[0] as i8 as i64), 2),
+                0xF1..=0xFD => (PackedEntry::Int((encoding & 0x0f) as i64 - 1), 1),
+                _ => return Err(anyhow!("Unknown ziplist encoding {:#x}", encoding)),
+            },
+        };
+        entries.push(entry);
+        pos += len;
+    }
+    Ok(entries)
+}
+
+fn decode_intset(intset: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let encoding = u32::from_le_bytes(slice(intset, 0, 4)?.try_into()?) as usize;
+    let len = u32::from_le_bytes(slice(intset, 4, 4)?.try_into()?) as usize;
+    let mut members = vec![];
+    for i in 0..len {
+        let raw = slice(intset, 8 + i * encoding, encoding)?;
+        let value = match encoding {
+            2 => i16::from_le_bytes(raw.try_into()?) as i64,
+            4 => i32::from_le_bytes(raw.try_into()?) as i64,
+            8 => i64::from_le_bytes(raw.try_into()?),
+            _ => return Err(anyhow!("Unknown intset encoding {}", encoding)),
+        };
+        members.push(value.to_string().into_bytes());
+    }
+    Ok(members)
+}
+
+fn decode_zipmap(zipmap: &[u8]) -> Result<FieldValues> {
+    let read_len = |pos: usize| -> Result<(usize, usize)> {
+        let first = *zipmap.get(pos).ok_or(anyhow!("Zipmap entry is truncated"))?;
+        if first < 254 {
+            Ok((first as usize, 1))
+        } else if first == 254 {
+            Ok((u32::from_le_bytes(slice(zipmap, pos + 1, 4)?.try_into()?) as usize, 5))
+        } else {
+            Err(anyhow!("Unexpected zipmap terminator"))
+        }
+    };
+    let mut pairs = vec![];
+    // the first byte holds the number of entries (when smaller than 254)
+    let mut pos = 1;
+    while *zipmap.get(pos).ok_or(anyhow!("Zipmap is missing its terminator"))? != 0xFF {
+        let (key_len, header) = read_len(pos)?;
+        let key = slice(zipmap, pos + header, key_len)?.to_vec();
+        pos += header + key_len;
+        let (value_len, header) = read_len(pos)?;
+        let free = *zipmap.get(pos + header).ok_or(anyhow!("Zipmap entry is truncated"))? as usize;
+        let value = slice(zipmap, pos + header + 1, value_len)?.to_vec();
+        pos += header + 1 + value_len + free;
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+/// Number of elements stored in each listpack node of a list or stream.
+const LISTPACK_NODE_ENTRIES: usize = 128;
+
+struct RdbWriter {
+    buffer: Vec<u8>,
+}
+
+impl RdbWriter {
+    fn new() -> Self {
+        Self { buffer: vec![] }
+    }
+
+    fn write_db(&mut self, db: &DB) -> Result<()> {
+        let now = Utc::now();
+        self.buffer.extend(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+        self.write_aux("redis-ver", REDIS_VERSION);
+        self.write_aux("redis-bits", "64");
+        self.write_aux("ctime", &now.timestamp().to_string());
+
+        let live = db.iter().collect::<Vec<_>>();
+        let expires = live.iter().filter(|(key, _)| db.get_expire(key).is_some()).count();
+        self.buffer.push(RDB_OPCODE_SELECTDB);
+        self.write_len(0);
+        self.buffer.push(RDB_OPCODE_RESIZEDB);
+        self.write_len(live.len() as u64);
+        self.write_len(expires as u64);
+
+        for (key, record) in live {
+            if let Some(limit) = db.get_expire(key) {
+                self.buffer.push(RDB_OPCODE_EXPIRETIME_MS);
+                self.buffer.extend((limit.timestamp_millis() as u64).to_le_bytes());
+            }
+            match record {
+                DbRecord::String(string_record) => {
+                    self.buffer.push(RDB_TYPE_STRING);
+                    self.write_string(key);
+                    self.write_string(string_record.get_value());
+                },
+                DbRecord::List(list_record) => {
+                    self.buffer.push(RDB_TYPE_LIST_QUICKLIST_2);
+                    self.write_string(key);
+                    self.write_list(list_record);
+                },
+                DbRecord::Stream(stream_record) => {
+                    self.buffer.push(RDB_TYPE_STREAM_LISTPACKS_3);
+                    self.write_string(key);
+                    self.write_stream(stream_record)?;
+                },
+                DbRecord::Set(set_record) => {
+                    match set_record.as_intset() {
+                        Some(intset) => {
+                            self.buffer.push(RDB_TYPE_SET_INTSET);
+                            self.write_string(key);
+                            self.write_string(&intset.to_bytes());
+                        },
+                        None => {
+                            self.buffer.push(RDB_TYPE_SET);
+                            self.write_string(key);
+                            self.write_len(set_record.len() as u64);
+                            for member in set_record.members() {
+                                self.write_string(&member);
+                            }
+                        },
+                    }
+                },
+                DbRecord::ZSet(zset_record) => {
+                    self.buffer.push(RDB_TYPE_ZSET_2);
+                    self.write_string(key);
+                    self.write_len(zset_record.len() as u64);
+                    for (member, score) in zset_record.iter_from(0, false) {
+                        self.write_string(member);
+                        self.buffer.extend(score.to_le_bytes());
+                    }
+                },
+                DbRecord::Hash(hash_record) => {
+                    let min_expire = hash_record.next_expire().map(|limit| limit.timestamp_millis());
+                    self.buffer.push(if min_expire.is_some() { RDB_TYPE_HASH_METADATA } else { RDB_TYPE_HASH });
+                    self.write_string(key);
+                    if let Some(min_expire) = min_expire {
+                        self.buffer.extend((min_expire as u64).to_le_bytes());
+                    }
+                    let fields = hash_record.iter().collect::<Vec<_>>();
+                    self.write_len(fields.len() as u64);
+                    for (field, value) in fields {
+                        if let Some(min_expire) = min_expire {
+                            let ttl = hash_record.get_expire(field).map_or(0, |limit| limit.timestamp_millis() - min_expire + 1);
+                            self.write_len(ttl as u64);
+                        }
+                        self.write_string(field);
+                        self.write_string(value);
+                    }
+                },
+            }
+        }
+
+        self.buffer.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &self.buffer);
+        self.buffer.extend(checksum.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) {
+        self.buffer.push(RDB_OPCODE_AUX);
+        self.write_string(key.as_bytes());
+        self.write_string(value.as_bytes());
+    }
+
+    fn write_len(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.buffer.push(len as u8);
+        } else if len < 1 << 14 {
+            self.buffer.push(0x40 | (len >> 8) as u8);
+            self.buffer.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buffer.push(0x80);
+            self.buffer.extend((len as u32).to_be_bytes());
+        } else {
+            self.buffer.push(0x81);
+            self.buffer.extend(len.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) {
+        self.write_len(s.len() as u64);
+        self.buffer.extend(s);
+    }
+
+    fn write_list(&mut self, list_record: &ListRecord) {
+        let items = list_record.iter().collect::<Vec<_>>();
+        let chunks = items.chunks(LISTPACK_NODE_ENTRIES);
+        self.write_len(chunks.len() as u64);
+        for chunk in chunks {
+            let entries = chunk.iter().map(|item| PackedEntry::Bytes(item.to_vec())).collect::<Vec<_>>();
+            self.write_len(QUICKLIST_NODE_CONTAINER_PACKED);
+            self.write_string(&encode_listpack(&entries));
+        }
+    }
+
+    fn write_stream(&mut self, stream_record: &StreamRecord) -> Result<()> {
+        let entries = stream_record.into_iter().map(|entry| Ok((parse_stream_id(entry.get_id())?, entry))).collect::<Result<Vec<_>>>()?;
+        let chunks = entries.chunks(LISTPACK_NODE_ENTRIES);
+        self.write_len(chunks.len() as u64);
+        for chunk in chunks {
+            let (master_ms, master_seq) = chunk[0].0;
+            let mut master_key = master_ms.to_be_bytes().to_vec();
+            master_key.extend(master_seq.to_be_bytes());
+            // master entry without master fields: count, deleted, master fields count and terminator
+            let mut packed = vec![PackedEntry::Int(chunk.len() as i64), PackedEntry::Int(0), PackedEntry::Int(0), PackedEntry::Int(0)];
+            for ((ms, seq), entry) in chunk {
+                let fields = entry.into_iter().collect::<Vec<_>>();
+                packed.push(PackedEntry::Int(0));
+                packed.push(PackedEntry::Int((ms - master_ms) as i64));
+                packed.push(PackedEntry::Int((seq.wrapping_sub(master_seq)) as i64));
+                packed.push(PackedEntry::Int(fields.len() as i64));
+                for (field, value) in &fields {
+                    packed.push(PackedEntry::Bytes(field.to_vec()));
+                    packed.push(PackedEntry::Bytes(value.to_vec()));
+                }
+                packed.push(PackedEntry::Int(fields.len() as i64 * 2 + 4));
+            }
+            self.write_string(&master_key);
+            self.write_string(&encode_listpack(&packed));
+        }
+        let (last_ms, last_seq) = entries.last().map(|(id, _)| *id).unwrap_or((0, 0));
+        let (first_ms, first_seq) = entries.first().map(|(id, _)| *id).unwrap_or((0, 0));
+        self.write_len(entries.len() as u64);
+        self.write_len(last_ms);
+        self.write_len(last_seq);
+        self.write_len(first_ms);
+        self.write_len(first_seq);
+        // max deleted entry id and entries added
+        self.write_len(0);
+        self.write_len(0);
+        self.write_len(entries.len() as u64);
+        // consumer groups
+        self.write_len(0);
+        Ok(())
+    }
+}
+
+fn parse_stream_id(id: &str) -> Result<(u64, u64)> {
+    let (ms, seq) = id.split_once('-').ok_or(anyhow!("Invalid stream id '{}'", id))?;
+    Ok((ms.parse::<u64>()?, seq.parse::<u64>()?))
+}
+
+fn encode_listpack(entries: &[PackedEntry]) -> Vec<u8> {
+    let mut body = vec![];
+    for entry in entries {
+        let mut encoded = vec![];
+        match entry {
+            PackedEntry::Int(i) => {
+                let i = *i;
+                if (0..=127).contains(&i) {
+                    encoded.push(i as u8);
+                } else if (-4096..=4095).contains(&i) {
+                    let value = (i as u64) & 0x1fff;
+                    encoded.push(0xC0 | (value >> 8) as u8);
+                    encoded.push(value as u8);
+                } else if (i16::MIN as i64..=i16::MAX as i64).contains(&i) {
+                    encoded.push(0xF1);
+                    encoded.extend((i as i16).to_le_bytes());
+                } else if (-(1 << 23)..(1 << 23)).contains(&i) {
+                    encoded.push(0xF2);
+                    encoded.extend(&(i as i32).to_le_bytes()[..3]);
+                } else if (i32::MIN as i64..=i32::MAX as i64).contains(&i) {
+                    encoded.push(0xF3);
+                    encoded.extend((i as i32).to_le_bytes());
+                } else {
+                    encoded.push(0xF4);
+                    encoded.extend(i.to_le_bytes());
+                }
+            },
+            PackedEntry::Bytes(bytes) => {
+                let len = bytes.len();
+                if len < 64 {
+                    encoded.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    encoded.push(0xE0 | (len >> 8) as u8);
+                    encoded.push(len as u8);
+                } else {
+                    encoded.push(0xF0);
+                    encoded.extend((len as u32).to_le_bytes());
+                }
+                encoded.extend(bytes);
+            },
+        }
+        let backlen = encode_backlen(encoded.len());
+        body.extend(encoded);
+        body.extend(backlen);
+    }
+    let total = 6 + body.len() + 1;
+    let mut listpack = (total as u32).to_le_bytes().to_vec();
+    listpack.extend((entries.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    listpack.extend(body);
+    listpack.push(0xFF);
+    listpack
+}
+
+/// How many bytes the back length of an entry `len` bytes long takes, as in Redis' `lpEncodeBacklen`.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// The length of an entry stored after it, so listpacks can be walked backwards: 7 bits per
+/// byte, the highest first. Every byte but the first has its top bit set, which tells a
+/// reader coming from the end that more bytes follow.
+fn encode_backlen(len: usize) -> Vec<u8> {
+    let size = backlen_size(len);
+    (0..size).rev().map(|i| {
+        let group = (len >> (7 * i)) as u8 & 127;
+        if i == size - 1 { group } else { group | 128 }
+    }).collect()
+}
+
+/// Reads a back length from its last byte towards its first, as Redis' `lpDecodeBacklen` does.
+/// `None` if the bytes don't make up exactly one.
+fn decode_backlen(backlen: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    for (i, byte) in backlen.iter().rev().enumerate() {
+        len |= ((byte & 127) as usize) << (7 * i);
+        if byte & 128 == 0 {
+            return (i == backlen.len() - 1).then_some(len)
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entry lengths where the back length grows by a byte, and the ones right before.
+    const BACKLEN_BOUNDARIES: [usize; 8] = [127, 128, 16382, 16383, 16384, 2097150, 2097151, 268435455];
+
+    #[test]
+    fn backlen_matches_redis_layout() {
+        assert_eq!(encode_backlen(127), vec![127]);
+        assert_eq!(encode_backlen(128), vec![1, 128]);
+        assert_eq!(encode_backlen(16383), vec![0, 127 | 128, 127 | 128]);
+        assert_eq!(encode_backlen(2097151), vec![0, 127 | 128, 127 | 128, 127 | 128]);
+    }
+
+    #[test]
+    fn backlen_roundtrip() {
+        for len in BACKLEN_BOUNDARIES {
+            let backlen = encode_backlen(len);
+            assert_eq!(backlen.len(), backlen_size(len), "size of {}", len);
+            assert_eq!(decode_backlen(&backlen), Some(len), "value of {}", len);
+        }
+        assert_eq!(decode_backlen(&[1 | 128]), None);
+    }
+
+    #[test]
+    fn huge_lengths_are_errors() {
+        // a string key whose length is the largest 64 bit one
+        let mut data = b"REDIS0011\xfe\x00\x00\x81".to_vec();
+        data.extend(u64::MAX.to_be_bytes());
+        assert!(parse(&data).is_err());
+        assert!(slice(b"abc", 1, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn reads_encoded_strings_and_expires() {
+        let mut data = b"REDIS0011\xfe\x00".to_vec();
+        data.extend(b"\x00\x01a\xc0\xf6");
+        data.extend(b"\x00\x01b\xc1\xe8\x03");
+        data.extend(b"\x00\x01c\xc2");
+        data.extend((-70000i32).to_le_bytes());
+        // "aaaaaaaaaa" compressed, expiring in 2100
+        data.push(RDB_OPCODE_EXPIRETIME);
+        data.extend(4102444800u32.to_le_bytes());
+        data.extend(b"\x00\x01d\xc3\x05\x0a\x00a\xe0\x00\x00");
+        // expired long ago, so it isn't loaded
+        data.push(RDB_OPCODE_EXPIRETIME_MS);
+        data.extend(1u64.to_le_bytes());
+        data.extend(b"\x00\x01e\x01e");
+        data.push(RDB_OPCODE_EOF);
+        data.extend(crc64(0, &data).to_le_bytes());
+
+        let db = parse(&data).unwrap();
+        let value = |key: &[u8]| db.get_as::<StringRecord>(key).unwrap().map(|string_record| string_record.get_value().to_vec());
+        assert_eq!(value(b"a"), Some(b"-10".to_vec()));
+        assert_eq!(value(b"b"), Some(b"1000".to_vec()));
+        assert_eq!(value(b"c"), Some(b"-70000".to_vec()));
+        assert_eq!(value(b"d"), Some(b"aaaaaaaaaa".to_vec()));
+        assert_eq!(db.get_expire(b"d"), DateTime::from_timestamp(4102444800, 0));
+        assert_eq!(value(b"e"), None);
+        assert_eq!(db.len(), 4);
+
+        // a checksum of 0 means it wasn't computed
+        let len = data.len();
+        data[len - 8..].fill(0);
+        assert!(parse(&data).is_ok());
+        data[len - 1] = 1;
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn dump_and_parse_roundtrip_every_type() {
+        let limit = DateTime::from_timestamp_millis(4102444800123).unwrap();
+        let mut db = DB::new();
+        db.insert(b"string".to_vec(), DbRecord::String(StringRecord::new(b"value".to_vec())));
+        db.set_expire(b"string", limit);
+        // past the entries of one quicklist node, with elements whose encoding (5 byte header
+        // included) is right where the back length grows
+        let list: VecDeque<Vec<u8>> = (0..LISTPACK_NODE_ENTRIES + 1).map(|i| i.to_string().into_bytes())
+            .chain([vec![b'x'; 16378], vec![b'y'; 2097146]])
+            .collect();
+        db.insert(b"list".to_vec(), DbRecord::List(ListRecord::from_list(list.clone())));
+        let mut stream_record = StreamRecord::new();
+        stream_record.push(StreamEntry::new("1-1", Some(HashMap::from([(b"a".to_vec(), b"1".to_vec())]))));
+        stream_record.push(StreamEntry::new("5-0", Some(HashMap::from([(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]))));
+        db.insert(b"stream".to_vec(), DbRecord::Stream(stream_record));
+        let mut hash_record = HashRecord::new();
+        hash_record.insert(b"kept".to_vec(), b"1".to_vec());
+        hash_record.insert(b"expiring".to_vec(), vec![b'z'; 16383]);
+        hash_record.set_expire(b"expiring", limit);
+        db.insert(b"hash".to_vec(), DbRecord::Hash(hash_record));
+        let mut intset = SetRecord::new();
+        for member in ["1", "-20", "300000"] {
+            intset.insert(member.as_bytes().to_vec());
+        }
+        db.insert(b"intset".to_vec(), DbRecord::Set(intset));
+        let mut set_record = SetRecord::new();
+        for member in ["a", "1", "b"] {
+            set_record.insert(member.as_bytes().to_vec());
+        }
+        db.insert(b"set".to_vec(), DbRecord::Set(set_record));
+        let mut zset_record = ZSetRecord::new();
+        for (member, score) in [("a", 1.5), ("b", -2.0), ("c", f64::INFINITY)] {
+            zset_record.insert(member.as_bytes().to_vec(), score);
+        }
+        db.insert(b"zset".to_vec(), DbRecord::ZSet(zset_record));
+
+        let loaded = parse(&dump(&db).unwrap()).unwrap();
+        assert_eq!(loaded.len(), db.len());
+        assert_eq!(loaded.get_as::<StringRecord>(b"string").unwrap().unwrap().get_value(), b"value");
+        assert_eq!(loaded.get_expire(b"string"), Some(limit));
+        assert_eq!(loaded.get_as::<ListRecord>(b"list").unwrap().unwrap().get_list(), list);
+        let entries = loaded.get_as::<StreamRecord>(b"stream").unwrap().unwrap().into_iter()
+            .map(|entry| (entry.get_id().to_string(), entry.into_iter().map(|(field, value)| (field.clone(), value.clone())).collect::<HashMap<_, _>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![
+            ("1-1".to_string(), HashMap::from([(b"a".to_vec(), b"1".to_vec())])),
+            ("5-0".to_string(), HashMap::from([(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())])),
+        ]);
+        let hash_record = loaded.get_as::<HashRecord>(b"hash").unwrap().unwrap();
+        assert_eq!(hash_record.len(), 2);
+        assert_eq!(hash_record.get(b"kept"), Some(&b"1".to_vec()));
+        assert_eq!(hash_record.get_expire(b"kept"), None);
+        assert_eq!(hash_record.get(b"expiring"), Some(&vec![b'z'; 16383]));
+        assert_eq!(hash_record.get_expire(b"expiring"), Some(limit));
+        let intset = loaded.get_as::<SetRecord>(b"intset").unwrap().unwrap();
+        assert!(intset.as_intset().is_some());
+        assert_eq!(intset.members(), vec![b"-20".to_vec(), b"1".to_vec(), b"300000".to_vec()]);
+        let set_record = loaded.get_as::<SetRecord>(b"set").unwrap().unwrap();
+        assert!(set_record.as_intset().is_none());
+        let mut members = set_record.members();
+        members.sort();
+        assert_eq!(members, vec![b"1".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        let zset_record = loaded.get_as::<ZSetRecord>(b"zset").unwrap().unwrap();
+        let members = zset_record.iter_from(0, false).map(|(member, score)| (member.clone(), score)).collect::<Vec<_>>();
+        assert_eq!(members, vec![(b"b".to_vec(), -2.0), (b"a".to_vec(), 1.5), (b"c".to_vec(), f64::INFINITY)]);
+    }
+
+    #[test]
+    fn listpack_roundtrip_across_backlen_sizes() {
+        // the encoded entry is the string plus its 2 or 5 byte header
+        let entries: Vec<PackedEntry> = [125, 126, 16378, 16379, 2097146].into_iter()
+            .map(|len| PackedEntry::Bytes(vec![b'x'; len]))
+            .chain([PackedEntry::Int(-1), PackedEntry::Int(1 << 40)])
+            .collect();
+        let expected: Vec<Vec<u8>> = entries.iter().map(|entry| match entry {
+            PackedEntry::Bytes(bytes) => bytes.clone(),
+            PackedEntry::Int(i) => i.to_string().into_bytes(),
+        }).collect();
+        let decoded = decode_listpack(&encode_listpack(&entries)).unwrap();
+        assert_eq!(decoded.into_iter().map(PackedEntry::into_bytes).collect::<Vec<_>>(), expected);
+    }
+}
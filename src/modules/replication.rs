@@ -0,0 +1,232 @@
+use std::{collections::VecDeque, sync::Arc};
+use anyhow::{Result, anyhow};
+use tokio::{io::AsyncWriteExt, net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}, sync::{Notify, RwLock}, time::{self, Duration}};
+
+use crate::{ReplicaDb, ReplicaInfo, modules::{client_handler::ClientHandler, db::DB, parser::RedisParser, rdb, values::RedisValue}};
+
+/// How long a replica waits before trying to reconnect to its master.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The last bytes of the replication stream, kept so a replica that briefly loses the
+/// connection can get what it missed instead of a full copy of the database.
+pub struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+    /// Replication offset of the first byte in `data`.
+    start_offset: u64,
+}
+
+impl Backlog {
+    pub fn new(size: usize, offset: u64) -> Self {
+        Self { data: VecDeque::new(), size, start_offset: offset }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        self.trim();
+    }
+
+    pub fn resize(&mut self, size: usize) {
+        self.size = size;
+        self.trim();
+    }
+
+    /// Drops the contents, with the stream starting over at `offset`.
+    pub fn reset(&mut self, offset: u64) {
+        self.data.clear();
+        self.start_offset = offset;
+    }
+
+    fn trim(&mut self) {
+        if self.data.len() > self.size {
+            let excess = self.data.len() - self.size;
+            self.data.drain(..excess);
+            self.start_offset += excess as u64;
+        }
+    }
+
+    /// The stream from `offset` onwards, if the backlog still has all of it.
+    pub fn get_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start_offset || offset > self.start_offset + self.data.len() as u64 {
+            return None
+        }
+        Some(self.data.range((offset - self.start_offset) as usize..).copied().collect())
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get_start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// The link from a replica to its master, once the handshake is done.
+pub struct MasterLink {
+    parser: RedisParser<OwnedReadHalf>,
+    write_stream: OwnedWriteHalf,
+    /// Replication offset when the link was established.
+    base_offset: u64,
+    /// Whether the master sent a full copy of its database, instead of continuing the stream.
+    full_resync: bool,
+}
+
+async fn read_master_reply(parser: &mut RedisParser<OwnedReadHalf>) -> Result<String> {
+    match parser.read_value().await {
+        Ok(RedisValue::String(reply)) => Ok(String::from_utf8(reply)?),
+        Ok(_) => Err(anyhow!("Unexpected reply from the master during the handshake.")),
+        Err(_) if parser.is_eof() => Err(anyhow!("Master closed the connection during the handshake.")),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs the replication handshake with the master. With `resume` set, it first asks to
+/// continue the stream where it was left; otherwise, or if the master can't do that, the
+/// snapshot it sends replaces the contents of `db`.
+async fn handshake(repl_info: &Arc<RwLock<ReplicaInfo>>, replicadb: &Arc<RwLock<ReplicaDb>>, db: &Arc<RwLock<DB>>, resume: bool) -> Result<MasterLink> {
+    let (address, port) = {
+        let repl_info = repl_info.read().await;
+        (repl_info.get_address(), repl_info.get_port())
+    };
+    let stream = TcpStream::connect(address).await?;
+    let (read_stream, mut write_stream) = stream.into_split();
+    let mut parser = RedisParser::new(read_stream);
+    // PING
+    write_stream.write_all(&RedisValue::Array(vec![RedisValue::String("PING".into())]).encode()).await?;
+    read_master_reply(&mut parser).await?;
+    // REPLCONF listening-port <port>
+    write_stream.write_all(&RedisValue::array_from_string_vec(vec!["REPLCONF", "listening-port", &port]).encode()).await?;
+    read_master_reply(&mut parser).await?;
+    // REPLCONF capa psync2
+    write_stream.write_all(&RedisValue::array_from_string_vec(vec!["REPLCONF", "capa", "psync2"]).encode()).await?;
+    read_master_reply(&mut parser).await?;
+    // PSYNC <replid> <offset of the next byte needed>, or PSYNC ? -1 to ask for a full copy
+    let (replid, offset) = {
+        let repl_info = repl_info.read().await;
+        (repl_info.get_replid(), repl_info.get_offset())
+    };
+    let next_offset = (offset + 1).to_string();
+    let psync = if resume { vec!["PSYNC", &replid, &next_offset] } else { vec!["PSYNC", "?", "-1"] };
+    write_stream.write_all(&RedisValue::array_from_string_vec(psync).encode()).await?;
+    let reply = read_master_reply(&mut parser).await?;
+    let mut full_resync = false;
+    let base_offset = match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["FULLRESYNC", master_replid, master_offset] => {
+            let master_offset = master_offset.parse::<u64>()?;
+            let snapshot = rdb::parse(&parser.read_rdb().await?)?;
+            // everything is locked before changing anything, so a link stopped halfway leaves no trace
+            let mut db = db.write().await;
+            let mut replicadb = replicadb.write().await;
+            let mut repl_info = repl_info.write().await;
+            *db = snapshot;
+            // our own replicas were following a stream that no longer exists
+            replicadb.reset(master_offset);
+            repl_info.set_replid(master_replid);
+            repl_info.set_offset(master_offset);
+            println!("DB loaded from master: {} keys", db.len());
+            full_resync = true;
+            master_offset
+        },
+        ["CONTINUE"] => offset,
+        ["CONTINUE", master_replid] => {
+            // the master changed its id (e.g. it was promoted), the stream goes on under the new one
+            if master_replid != replid {
+                repl_info.write().await.shift_replid(master_replid);
+            }
+            offset
+        },
+        _ => return Err(anyhow!("Unexpected reply to PSYNC: {}", reply)),
+    };
+    Ok(MasterLink { parser, write_stream, base_offset, full_resync })
+}
+
+/// Applies the commands the master propagates through `handler`, without replying to them,
+/// and keeps the replication offset up to date with the bytes processed. The only command
+/// answered is `REPLCONF GETACK`, with the offset processed before it. Returns when `shutdown`
+/// is notified, in between commands.
+async fn apply_master_stream(link: &mut MasterLink, handler: &mut ClientHandler, repl_info: &Arc<RwLock<ReplicaInfo>>, shutdown: &Notify) -> Result<()> {
+    let start = link.parser.get_consumed();
+    loop {
+        let value = tokio::select! {
+            _ = shutdown.notified() => return Ok(()),
+            value = link.parser.read_value() => value,
+        };
+        let args = match value {
+            Ok(RedisValue::Array(args)) if !args.is_empty() => args,
+            Ok(_) => return Err(anyhow!("Unexpected value in the replication stream.")),
+            Err(_) if link.parser.is_eof() => return Err(anyhow!("Master closed the connection.")),
+            Err(e) => return Err(e),
+        };
+        let is_getack = args.len() == 3 && args[0].get_string()?.to_uppercase() == "REPLCONF" && args[1].get_string()?.to_uppercase() == "GETACK";
+        if is_getack {
+            let offset = repl_info.read().await.get_offset().to_string();
+            link.write_stream.write_all(&RedisValue::array_from_string_vec(vec!["REPLCONF", "ACK", &offset]).encode()).await?;
+        } else {
+            handler.handle_internal_command(args.clone()).await?;
+        }
+        // our own replicas get the master's stream as is, so offsets match along the chain
+        handler.forward_to_replicas(args).await;
+        let processed = (link.parser.get_consumed() - start) as u64;
+        repl_info.write().await.set_offset(link.base_offset + processed);
+    }
+}
+
+/// Keeps the replica in sync with its master, reconnecting after the link drops and
+/// resuming the stream where it was left when the master still has it.
+async fn run_replica_link(mut handler: ClientHandler, repl_info: Arc<RwLock<ReplicaInfo>>, replicadb: Arc<RwLock<ReplicaDb>>,
+                          db: Arc<RwLock<DB>>, mut resume: bool, shutdown: Arc<Notify>) {
+    loop {
+        let link = tokio::select! {
+            _ = shutdown.notified() => return,
+            link = handshake(&repl_info, &replicadb, &db, resume) => link,
+        };
+        match link {
+            Ok(mut link) => {
+                resume = true;
+                // the append only file has to describe the new contents instead of the old ones
+                if link.full_resync && let Err(e) = handler.rewrite_aof().await {
+                    eprintln!("Error rewriting the append only file: {}", e);
+                }
+                match apply_master_stream(&mut link, &mut handler, &repl_info, &shutdown).await {
+                    Ok(()) => return,
+                    Err(e) => eprintln!("Lost the connection to the master: {}", e),
+                }
+            },
+            Err(e) => eprintln!("Error connecting to the master: {}", e),
+        }
+        tokio::select! {
+            _ = shutdown.notified() => return,
+            _ = time::sleep(RECONNECT_DELAY) => (),
+        }
+    }
+}
+
+/// Starts following the master set in `repl_info` on a background task, through `handler`,
+/// after stopping the link to the previous master if there was one. With `resume` set, the
+/// first connection already tries to continue the current stream.
+pub async fn start_replica_link(handler: ClientHandler, repl_info: Arc<RwLock<ReplicaInfo>>, replicadb: Arc<RwLock<ReplicaDb>>,
+                                db: Arc<RwLock<DB>>, resume: bool) {
+    let shutdown = Arc::new(Notify::new());
+    if let Some(previous) = repl_info.write().await.replace_link_shutdown(Some(Arc::clone(&shutdown))) {
+        previous.notify_one();
+    }
+    spawn_replica_link(handler, repl_info, replicadb, db, resume, shutdown);
+}
+
+// Not async: the link runs commands, and REPLICAOF starts a link, so the future of this
+// function would otherwise contain itself.
+fn spawn_replica_link(handler: ClientHandler, repl_info: Arc<RwLock<ReplicaInfo>>, replicadb: Arc<RwLock<ReplicaDb>>,
+                      db: Arc<RwLock<DB>>, resume: bool, shutdown: Arc<Notify>) {
+    tokio::spawn(run_replica_link(handler, repl_info, replicadb, db, resume, shutdown));
+}
+
+pub async fn stop_replica_link(repl_info: &Arc<RwLock<ReplicaInfo>>) {
+    if let Some(shutdown) = repl_info.write().await.replace_link_shutdown(None) {
+        shutdown.notify_one();
+    }
+}
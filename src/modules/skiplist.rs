@@ -0,0 +1,268 @@
+use rand::Rng;
+
+const MAX_LEVEL: usize = 32;
+/// Chance of a node having one more level than the one below, as in Redis.
+const LEVEL_PROBABILITY: f64 = 0.25;
+/// Index of the node that starts every level and holds no member.
+const HEADER: usize = 0;
+
+#[derive(Clone)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    levels: Vec<Level>,
+    backward: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Level {
+    forward: Option<usize>,
+    /// How many members the link skips over, so ranks can be counted along the way.
+    span: usize,
+}
+
+/// Members sorted by score, then by member, the way Redis keeps sorted sets. Each link knows
+/// how many members it skips, which makes finding a member's rank and the member at a rank
+/// take O(log n) time.
+///
+/// Nodes live in one vector and point at each other by index. Removing a node leaves a hole
+/// the next insert fills.
+#[derive(Clone)]
+pub struct SkipList {
+    nodes: Vec<Option<Node>>,
+    holes: Vec<usize>,
+    /// Levels in use, at least 1.
+    level: usize,
+    len: usize,
+}
+
+fn precedes(node: &Node, score: f64, member: &[u8]) -> bool {
+    node.score < score || (node.score == score && node.member.as_slice() < member)
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let header = Node { member: vec![], score: 0.0, levels: vec![Level { forward: None, span: 0 }; MAX_LEVEL], backward: None };
+        Self { nodes: vec![Some(header)], holes: vec![], level: 1, len: 0 }
+    }
+    fn node(&self, index: usize) -> &Node {
+        self.nodes[index].as_ref().unwrap()
+    }
+    fn node_mut(&mut self, index: usize) -> &mut Node {
+        self.nodes[index].as_mut().unwrap()
+    }
+    fn random_level() -> usize {
+        let mut rng = rand::rng();
+        let mut level = 1;
+        while level < MAX_LEVEL && rng.random::<f64>() < LEVEL_PROBABILITY {
+            level += 1;
+        }
+        level
+    }
+    /// For each level, the last node before where `score` and `member` go, and its rank.
+    fn find_predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.node(x).levels[i].forward && precedes(self.node(next), score, member) {
+                rank[i] += self.node(x).levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+    /// Adds `member`, which must not be in the list yet.
+    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let (mut update, mut rank) = self.find_predecessors(score, &member);
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.node_mut(HEADER).levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+        let node = Node { member, score, levels: vec![Level { forward: None, span: 0 }; level], backward: None };
+        let x = match self.holes.pop() {
+            Some(hole) => {
+                self.nodes[hole] = Some(node);
+                hole
+            },
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            },
+        };
+        for i in 0..level {
+            let previous = self.node(update[i]).levels[i];
+            self.node_mut(x).levels[i] = Level { forward: previous.forward, span: previous.span - (rank[0] - rank[i]) };
+            self.node_mut(update[i]).levels[i] = Level { forward: Some(x), span: rank[0] - rank[i] + 1 };
+        }
+        // the links above the new node now skip one more member
+        for (i, &previous) in update.iter().enumerate().take(self.level).skip(level) {
+            self.node_mut(previous).levels[i].span += 1;
+        }
+        self.node_mut(x).backward = if update[0] == HEADER { None } else { Some(update[0]) };
+        if let Some(next) = self.node(x).levels[0].forward {
+            self.node_mut(next).backward = Some(x);
+        }
+        self.len += 1;
+    }
+    /// Returns whether `member` was there with that `score`.
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.find_predecessors(score, member);
+        let Some(x) = self.node(update[0]).levels[0].forward else { return false };
+        if self.node(x).score != score || self.node(x).member != member {
+            return false
+        }
+        for (i, &previous) in update.iter().enumerate().take(self.level) {
+            if self.node(previous).levels[i].forward == Some(x) {
+                let removed = self.node(x).levels[i];
+                let level = &mut self.node_mut(previous).levels[i];
+                level.span = level.span + removed.span - 1;
+                level.forward = removed.forward;
+            } else {
+                self.node_mut(previous).levels[i].span -= 1;
+            }
+        }
+        let backward = self.node(x).backward;
+        if let Some(next) = self.node(x).levels[0].forward {
+            self.node_mut(next).backward = backward;
+        }
+        while self.level > 1 && self.node(HEADER).levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+        self.nodes[x] = None;
+        self.holes.push(x);
+        self.len -= 1;
+        true
+    }
+    /// How many members from the start satisfy `precedes`, which has to hold for the first
+    /// members and then never again, like "the score is below 5".
+    pub fn count_while(&self, precedes: impl Fn(f64, &[u8]) -> bool) -> usize {
+        let mut count = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.node(x).levels[i].forward && precedes(self.node(next).score, &self.node(next).member) {
+                count += self.node(x).levels[i].span;
+                x = next;
+            }
+        }
+        count
+    }
+    /// The node at the 0 based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None
+        }
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.node(x).levels[i].forward && traversed + self.node(x).levels[i].span <= rank + 1 {
+                traversed += self.node(x).levels[i].span;
+                x = next;
+            }
+            if traversed == rank + 1 {
+                return Some(x)
+            }
+        }
+        None
+    }
+    /// Members and scores from the one at `rank` on, towards the end or, with `reverse`, the start.
+    pub fn iter_from(&self, rank: usize, reverse: bool) -> Iter<'_> {
+        Iter { list: self, next: self.node_at(rank), reverse }
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+    reverse: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.list.node(self.next?);
+        self.next = if self.reverse { node.backward } else { node.levels[0].forward };
+        Some((&node.member, node.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    /// Checks every rank in both directions, and the ranks `count_while` finds, against `expected`.
+    fn check(list: &SkipList, expected: &[(f64, Vec<u8>)]) {
+        assert_eq!(list.len, expected.len());
+        for rank in 0..expected.len() {
+            let forward = list.iter_from(rank, false).map(|(member, score)| (score, member.clone())).collect::<Vec<_>>();
+            assert_eq!(forward, expected[rank..]);
+            let mut backward = list.iter_from(rank, true).map(|(member, score)| (score, member.clone())).collect::<Vec<_>>();
+            backward.reverse();
+            assert_eq!(backward, expected[..=rank]);
+            let (score, member) = &expected[rank];
+            assert_eq!(list.count_while(|s, m| s < *score || (s == *score && m < member.as_slice())), rank);
+        }
+        assert!(list.iter_from(expected.len(), false).next().is_none());
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut list = SkipList::new();
+        for (score, member) in [(2.0, "b"), (1.0, "z"), (2.0, "a"), (f64::NEG_INFINITY, "m"), (-0.5, "c")] {
+            list.insert(score, member.as_bytes().to_vec());
+        }
+        let expected = [(f64::NEG_INFINITY, "m"), (-0.5, "c"), (1.0, "z"), (2.0, "a"), (2.0, "b")]
+            .map(|(score, member)| (score, member.as_bytes().to_vec()));
+        check(&list, &expected);
+        assert_eq!(list.count_while(|score, _| score < 2.0), 3);
+    }
+
+    #[test]
+    fn remove_needs_the_member_and_its_score() {
+        let mut list = SkipList::new();
+        list.insert(1.0, b"a".to_vec());
+        assert!(!list.remove(2.0, b"a"));
+        assert!(!list.remove(1.0, b"b"));
+        assert!(list.remove(1.0, b"a"));
+        assert!(!list.remove(1.0, b"a"));
+        check(&list, &[]);
+    }
+
+    #[test]
+    fn ranks_stay_right_through_inserts_and_removes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut list = SkipList::new();
+        let mut expected: Vec<(f64, Vec<u8>)> = vec![];
+        for round in 0..2000 {
+            let member = rng.random_range(0..300u32).to_string().into_bytes();
+            match expected.iter().position(|(_, m)| *m == member) {
+                Some(i) => {
+                    let (score, member) = expected.remove(i);
+                    assert!(list.remove(score, &member));
+                },
+                None => {
+                    let score = rng.random_range(0..50) as f64;
+                    list.insert(score, member.clone());
+                    let i = expected.partition_point(|(s, m)| *s < score || (*s == score && *m < member));
+                    expected.insert(i, (score, member));
+                },
+            }
+            if round % 200 == 0 {
+                check(&list, &expected);
+            }
+        }
+        check(&list, &expected);
+        // removed nodes leave holes that inserts fill
+        assert_eq!(list.nodes.len(), expected.len() + list.holes.len() + 1);
+    }
+}
@@ -1,28 +1,78 @@
 use anyhow::{Result, anyhow};
 
+use crate::modules::error::RedisError;
+
+/// Version of the protocol spoken on a connection, chosen by the client with `HELLO`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
 #[derive(Debug, Clone)]
 pub enum RedisValue {
-    String(String),
+    /// Bulk data, which may be any sequence of bytes.
+    String(Vec<u8>),
     Int(i64),
     Array(Vec<RedisValue>),
     Error(String),
     NullString,
     NullArray,
+    // RESP3 types, sent as their closest RESP2 type to clients that didn't ask for RESP3
+    Null,
+    Map(Vec<(RedisValue, RedisValue)>),
+    Set(Vec<RedisValue>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// Text along with its format, e.g. `txt` or `mkd`.
+    Verbatim(String, Vec<u8>),
+    /// Out of band information about the value that follows.
+    Attribute(Vec<(RedisValue, RedisValue)>, Box<RedisValue>),
+    /// Data the server sends on its own, e.g. pub/sub messages.
+    Push(Vec<RedisValue>),
+}
+
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf".to_string() } else { "-inf".to_string() }
+    } else {
+        format_redis_double(d)
+    }
+}
+
+/// Formats a finite double the way Redis does: the shortest digits that read back
+/// the same, laid out like `%.17g`.
+pub fn format_redis_double(d: f64) -> String {
+    let scientific = format!("{:e}", d);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    if d != 0.0 && !(-4..17).contains(&exponent) {
+        format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+    } else {
+        d.to_string()
+    }
 }
 
 impl RedisValue {
     pub fn array_from_string_vec(array: Vec<&str>) -> RedisValue {
         let mut redis_value_vec = vec![];
         for s in array {
-            redis_value_vec.push(RedisValue::String(s.to_string()));
+            redis_value_vec.push(RedisValue::String(s.into()));
         }
         RedisValue::Array(redis_value_vec)
     }
-    pub fn get_string(&self) -> Result<String> {
+    /// The value as text, e.g. for command names and numbers. Fails if it isn't valid UTF-8.
+    pub fn get_string(&self) -> Result<String, RedisError> {
+        Ok(String::from_utf8(self.get_bytes()?)?)
+    }
+    pub fn get_bytes(&self) -> Result<Vec<u8>, RedisError> {
         if let Self::String(s) = self {
             Ok(s.to_owned())
         } else {
-            Err(anyhow!("Value is not a string."))
+            Err(RedisError::Client("invalid argument, expected a string".to_string()))
         }
     }
     pub fn _get_int(&self) -> Result<i64> {
@@ -32,11 +82,16 @@ impl RedisValue {
             Err(anyhow!("Value is not an integer."))
         }
     }
+    /// Encodes the value in RESP2, as used for the append only file and the replication stream.
     pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(Protocol::Resp2)
+    }
+    pub fn encode_with(&self, protocol: Protocol) -> Vec<u8> {
         let mut encoded = vec![];
+        let resp3 = protocol == Protocol::Resp3;
         match self {
             Self::String(s) => {
-                let content = s.as_bytes().to_vec();
+                let content = s.clone();
                 let s_size = content.len();
                 encoded.extend(format!("${}\r\n", s_size).as_bytes());
                 encoded.extend(content);
@@ -48,7 +103,10 @@ impl RedisValue {
                 encoded.extend(content);
                 encoded.extend("\r\n".as_bytes());
             },
-            Self::NullString => {
+            Self::NullString | Self::NullArray | Self::Null if resp3 => {
+                encoded.extend(b"_\r\n");
+            },
+            Self::NullString | Self::Null => {
                 encoded.extend(b"$-1\r\n");
             },
             Self::NullArray => {
@@ -60,8 +118,68 @@ impl RedisValue {
             Self::Array(values) => {
                 encoded.extend(format!("*{}\r\n", values.len()).as_bytes());
                 for value in values {
-                    encoded.extend(value.encode());
+                    encoded.extend(value.encode_with(protocol));
+                }
+            },
+            Self::Set(values) | Self::Push(values) => {
+                let kind = match self {
+                    Self::Set(_) if resp3 => '~',
+                    Self::Push(_) if resp3 => '>',
+                    _ => '*',
+                };
+                encoded.extend(format!("{}{}\r\n", kind, values.len()).as_bytes());
+                for value in values {
+                    encoded.extend(value.encode_with(protocol));
+                }
+            },
+            Self::Map(pairs) => {
+                // a flat list of keys and values in RESP2
+                if resp3 {
+                    encoded.extend(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    encoded.extend(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (key, value) in pairs {
+                    encoded.extend(key.encode_with(protocol));
+                    encoded.extend(value.encode_with(protocol));
+                }
+            },
+            Self::Double(d) if resp3 => {
+                encoded.extend(format!(",{}\r\n", format_double(*d)).as_bytes());
+            },
+            Self::Double(d) => {
+                encoded.extend(Self::String(format_double(*d).into()).encode_with(protocol));
+            },
+            Self::Boolean(b) if resp3 => {
+                encoded.extend(if *b { b"#t\r\n" } else { b"#f\r\n" });
+            },
+            Self::Boolean(b) => {
+                encoded.extend(format!(":{}\r\n", *b as u8).as_bytes());
+            },
+            Self::BigNumber(n) if resp3 => {
+                encoded.extend(format!("({}\r\n", n).as_bytes());
+            },
+            Self::BigNumber(n) => {
+                encoded.extend(Self::String(n.clone().into()).encode_with(protocol));
+            },
+            Self::Verbatim(format, text) if resp3 => {
+                encoded.extend(format!("={}\r\n{}:", text.len() + format.len() + 1, format).as_bytes());
+                encoded.extend(text);
+                encoded.extend(b"\r\n");
+            },
+            Self::Verbatim(_, text) => {
+                encoded.extend(Self::String(text.clone()).encode_with(protocol));
+            },
+            Self::Attribute(pairs, value) => {
+                // RESP2 clients just get the value
+                if resp3 {
+                    encoded.extend(format!("|{}\r\n", pairs.len()).as_bytes());
+                    for (key, value) in pairs {
+                        encoded.extend(key.encode_with(protocol));
+                        encoded.extend(value.encode_with(protocol));
+                    }
                 }
+                encoded.extend(value.encode_with(protocol));
             },
         }
         encoded
@@ -69,7 +187,7 @@ impl RedisValue {
     pub fn as_simple_string(&self) -> Result<Vec<u8>> {
         if let Self::String(s) = self {
             let mut encoded = vec![];
-            let content = s.as_bytes().to_vec();
+            let content = s.clone();
             encoded.push(b'+');
             encoded.extend(content);
             encoded.extend("\r\n".as_bytes());